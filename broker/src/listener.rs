@@ -1,4 +1,6 @@
+use carrier::certificate::{Authenticator, CertificateChain};
 use carrier::channel;
+use carrier::cookie::{Admission, CookieChecker};
 ///! receive incomming channels on route0
 use carrier::endpoint;
 use failure::Error;
@@ -8,33 +10,45 @@ use futures::Sink;
 use futures::{Async, Future, Stream};
 use carrier::identity;
 use carrier::noise;
-use packet::EncryptedPacket;
+use packet::{Ecn, EncryptedPacket};
 use carrier::proto;
 use shadow;
 use std::env;
 use std::net::SocketAddr;
 use std::net::UdpSocket as StdSocket;
+use std::time::Instant;
 use tokio;
 use tokio::net::UdpSocket;
 use carrier::transport;
 use xlog;
 use stats;
 
+/// route 0 handshakes per second before the listener answers with cookie replies
+const HANDSHAKE_LIMIT: u32 = 100;
+
 pub struct Listener {
     ep:      endpoint::Endpoint,
     xsecret: identity::Secret,
-    route0:  mpsc::Receiver<(EncryptedPacket, SocketAddr)>,
+    psk:     Option<noise::Psk>,
+    route0:  mpsc::Receiver<(EncryptedPacket, SocketAddr, Ecn)>,
     work:    mpsc::Sender<endpoint::EndpointWorkerCmd>,
     sock:    StdSocket,
+    cookies: CookieChecker,
+    started: Instant,
 }
 
 impl Listener {
     pub fn handle(&self) -> endpoint::Endpoint {
         self.ep.clone()
     }
+
+    fn now(&self) -> u64 {
+        let elapsed = self.started.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
 }
 
-pub fn listen(secret: identity::Secret) -> Result<(Listener, shadow::broker::Handle), Error> {
+pub fn listen(secret: identity::Secret, psk: Option<noise::Psk>) -> Result<(Listener, shadow::broker::Handle), Error> {
     let port = env::var("PORT").unwrap_or("8443".to_string());
     let stdsock = StdSocket::bind(&format!("0.0.0.0:{}", port))?;
     let miosock = UdpSocket::from_std(stdsock.try_clone()?, &tokio::reactor::Handle::current())?;
@@ -53,9 +67,12 @@ pub fn listen(secret: identity::Secret) -> Result<(Listener, shadow::broker::Han
         Listener {
             route0: rx,
             xsecret: secret,
+            psk,
             ep,
             work: work,
             sock: stdsock,
+            cookies: CookieChecker::new(HANDSHAKE_LIMIT, 0),
+            started: Instant::now(),
         },
         shadow::spawn(),
     ))
@@ -64,6 +81,7 @@ pub fn listen(secret: identity::Secret) -> Result<(Listener, shadow::broker::Han
 pub struct ChannelHandshake {
     addr:     SocketAddr,
     identity: identity::Identity,
+    chain:    CertificateChain,
     noise:    noise::HandshakeResponder,
     work:     mpsc::Sender<endpoint::EndpointWorkerCmd>,
     sock:     StdSocket,
@@ -75,13 +93,31 @@ impl Stream for Listener {
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
         loop {
-            let (pkt, addr) = match self.route0.poll() {
+            let (pkt, addr, _) = match self.route0.poll() {
                 Ok(Async::Ready(Some(v))) => v,
                 Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
                 Err(_) => unreachable!(),
             };
-            let (r, identity, timestamp) = match noise::respond(Some(&self.xsecret), pkt) {
+
+            let now = self.now();
+            let pkt = match self.cookies.admit(pkt, &addr, now) {
+                Admission::Accept(pkt) => pkt,
+                Admission::Retry(reply) => {
+                    debug!("under load, sending cookie reply to {}", addr);
+                    let reply = reply.encode();
+                    if let Err(e) = self.sock.send_to(&reply, &addr) {
+                        warn!("cannot send cookie reply: {}", e);
+                    }
+                    continue;
+                }
+                Admission::Drop => {
+                    debug!("dropping handshake from {}, too many with a valid cookie", addr);
+                    continue;
+                }
+            };
+
+            let (r, identity, timestamp, chain) = match noise::respond(Some(&self.xsecret), pkt, self.psk.as_ref()) {
                 Ok(v) => v,
                 Err(e) => {
                     warn!("cannot accept handshake: {}", e);
@@ -97,6 +133,7 @@ impl Stream for Listener {
             return Ok(Async::Ready(Some(ChannelHandshake {
                 addr:     addr,
                 identity: identity,
+                chain:    chain,
                 noise:    r,
                 work:     self.work.clone(),
                 sock:     self.sock.try_clone()?,
@@ -114,6 +151,12 @@ impl ChannelHandshake {
         &self.identity
     }
 
+    /// check that auth grants the peer resource, directly or through the certificates it presented in the handshake.
+    /// to be called before accept, so an unauthorized peer never gets a channel
+    pub fn authorize(&self, auth: &Authenticator, resource: &str) -> Result<(), Error> {
+        auth.authorize(&self.identity, resource.to_string(), &self.chain)
+    }
+
     pub fn accept(self, secret: identity::Secret) -> impl Future<Item = channel::Channel, Error = Error> {
         let (tx, rx) = mpsc::channel(100);
         let tc = stats::PacketCounter{
@@ -136,7 +179,7 @@ impl ChannelHandshake {
                 route_rx
                     .map_err(Error::from)
                     .and_then(move |route| {
-                        let (r, pkt2) = noise.send_response(route, &secret)?;
+                        let (r, pkt2) = noise.send_response(route, &secret, &Vec::new())?;
                         let pkt2 = pkt2.encode();
                         assert_eq!(sock.send_to(&pkt2, &addr)?, pkt2.len());
 
@@ -43,12 +43,16 @@ pub fn main() {
 
     let secrets = keystore::Secrets::load().unwrap();
     tokio::run(futures::lazy(move || {
-        broker(secrets.identity, coordinators).map_err(|e| error!("{}", e))
+        broker(secrets.identity, secrets.psk, coordinators).map_err(|e| error!("{}", e))
     }));
 }
 
-pub fn broker(secret: identity::Secret, coordinators: HashSet<identity::Identity>) -> impl Future<Item = (), Error = Error> {
-    let (lst, sb) = listener::listen(secret.clone()).unwrap();
+pub fn broker(
+    secret: identity::Secret,
+    psk: Option<noise::Psk>,
+    coordinators: HashSet<identity::Identity>,
+) -> impl Future<Item = (), Error = Error> {
+    let (lst, sb) = listener::listen(secret.clone(), psk).unwrap();
     let ep = lst.handle();
     lst.for_each(move |ch| {
         let coordinators = coordinators.clone();
@@ -1,6 +1,6 @@
 use carrier::channel;
 use carrier::endpoint;
-use failure::Error;
+use failure::{err_msg, Error};
 use futures::sync::mpsc;
 use futures::sync::oneshot;
 use futures::{self, Future, Sink, Stream};
@@ -379,16 +379,15 @@ impl peer::Worker for Peer {
     ) -> Box<Future<Item = (Option<Self>, proto::PeerConnectResponse), Error = (Option<Self>, Error)> + Send + Sync>
     {
         let selfipaddr = self.ipaddr.clone();
-        let ft = self
-            .channel
-            .message("/carrier.broker.v1/peer/connect")
-            .unwrap()
+        // the channel may have timed out while the publisher is not yet removed from its shadow
+        let stream = wrk_try!(self, self.channel.message("/carrier.broker.v1/peer/connect"));
+        let ft = stream
             .send(req)
             .flatten_stream()
             .into_future()
             .map_err(|(e, _)| e)
             .and_then(move |(resp, _)| {
-                let mut resp: proto::PeerConnectResponse = resp.expect("eof before header");
+                let mut resp: proto::PeerConnectResponse = resp.ok_or_else(|| err_msg("peer channel closed"))?;
                 resp.paths.push(proto::Path {
                     category: (proto::path::Category::Internet as i32),
                     ipaddr:   format!("{}", selfipaddr),
@@ -57,12 +57,9 @@ impl prost_build::ServiceGenerator for ServiceGen {
                 {
                     use prost::Message;
 
-                    let headers = headers.into_iter()
-                        .chain(vec![(":path".as_bytes(), #wire_name.as_bytes())].into_iter());
-
-                    use hpack::Encoder;
-                    let mut encoder = Encoder::new();
-                    let headers = encoder.encode(headers);
+                    let mut headers = headers.into_iter()
+                        .fold(Headers::new(), |h, (k, v)| h.and(k.to_vec(), v.to_vec()));
+                    headers.add(b":path".to_vec(), #wire_name.as_bytes().to_vec());
 
                     let mut v = Vec::new();
                     i.encode(&mut v)?;
@@ -107,25 +104,11 @@ impl prost_build::ServiceGenerator for ServiceGen {
 
                     #(#service_trait_fns)*
 
-                    fn dispatch(&mut self, header: Vec<u8>) -> Box<CallHandler<C>> {
-                        use hpack::Decoder;
-                        let mut decoder = Decoder::new();
-                        let headers = match decoder.decode(&header) {
-                            Err(e) => {
-                                warn!("error decoding headers: {:?}", e);
-                                return Box::new(CallError::new(
-                                    format!("invalid hpack headers: {:?}",e).into_bytes(),
-                                    b"400".to_vec(),
-                                ));
-                            },
-                            Ok(v) => v,
-                        };
-                        let mut path : Option<Vec<u8>> = None;
-                        for header in &headers {
-                            if header.0 == b":path" {
-                                path = Some(header.1.clone());
-                            }
-                        }
+                    fn dispatch(&mut self, header: Headers) -> Box<CallHandler<C>> {
+                        let path = header.path().map(|v|v.to_vec());
+                        let headers : Vec<(Vec<u8>, Vec<u8>)> = header.iter()
+                            .map(|(k, v)|(k.to_vec(), v.to_vec()))
+                            .collect();
                         trace!("dispatch {:?}", path.as_ref().map(|v|String::from_utf8_lossy(v)));
 
                         match path.as_ref().map(|v|v.as_slice()) {
@@ -2,7 +2,6 @@ use std::env;
 use failure::Error;
 use carrier::*;
 use futures::{Future, Sink, Stream};
-use std::collections::HashSet;
 use framed;
 use std::fs::File;
 use std::io::Read;
@@ -23,6 +22,7 @@ pub struct Config {
 
 pub fn axon(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     config_file: String,
 ) -> impl Future<Item = (), Error = Error> {
 
@@ -32,33 +32,40 @@ pub fn axon(
     let config : Config = toml::de::from_str(&contents).expect(&format!("reading {}", config_file));
 
 
-    let allowable : HashSet<identity::Identity>  = config.allowed.iter()
-        .map(|(k,v)| k.parse().expect("parsing allowed identity from config"))
-        .collect();
+    let shadow : identity::Address = config.publish.shadow.parse().expect("parsing shadow from config");
 
-    let shadow = config.publish.shadow.parse().expect("parsing shadow from config");
+    // allowed identities get in directly, anyone else needs a certificate for this shadow and axon
+    let mut auth = certificate::Authenticator::new(
+        certificate::AuthenticatorSide::Subscribe,
+        shadow.clone(),
+        secret.identity(),
+    );
+    for (k, v) in config.allowed.iter() {
+        auth.allow(k.parse().expect("parsing allowed identity from config"), vec![v.clone()]);
+    }
 
 
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
 
 
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
 
         if let Some(keepalive) = config.keepalive {
             brk.config(transport::Config{
-                sleeping:   false,
-                timeout:    Some(keepalive),
+                timeout: Some(keepalive),
+                ..Default::default()
             }).ok();
         }
 
         info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-        publisher::dispatch(shadow, ep, brk, sock, addr, secret, move |id| allowable.contains(&id)).for_each(
+        let acceptor = publisher::authenticated(auth, "*".to_string());
+        publisher::dispatch(shadow, ep, brk, sock, addr, secret, psk, acceptor).for_each(
             move |mut channel| {
                 info!("peer has subscribed {}", channel.identity());
                 if let Some(keepalive) = config.keepalive {
                     channel.config(transport::Config{
-                        sleeping:   false,
-                        timeout:    Some(keepalive),
+                        timeout: Some(keepalive),
+                        ..Default::default()
                     }).ok();
                 }
                 let server = channel
@@ -24,11 +24,17 @@ pub fn x(stream: channel::ChannelStream) -> impl Future<Item = (), Error = Error
 }
 
 
-pub fn forward(secret: identity::Secret, target: identity::Identity, local: u16, remote: u16) -> impl Future<Item = (), Error = Error> {
+pub fn forward(
+    secret: identity::Secret,
+    psk: Option<noise::Psk>,
+    target: identity::Identity,
+    local: u16,
+    remote: u16,
+) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
         info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-        subscriber::connect(target, ep, &mut brk, sock, addr, secret).and_then(move |mut channel| {
+        subscriber::connect(target, ep, &mut brk, sock, addr, secret, psk).and_then(move |mut channel| {
 
             let mut headers = headers::Headers::with_path("/v0/connect");
             headers.add("PORT".into(), format!("{}", remote).into());
@@ -88,6 +88,9 @@ pub fn main_() -> Result<(), Error> {
         $ carrier gen
     The secrets file can also be set in an environment variable
         $ export CARRIER_SECRET_FILE=~/.devguard/secret
+    Closed deployments can add a pre-shared key of 32 base58 encoded bytes to the secrets file
+        psk = \"...\"
+    All peers and brokers of the deployment must use the same key.
     ",
         ).subcommand(SubCommand::with_name("gen").about("generate new identity"))
 
@@ -268,7 +271,7 @@ pub fn main_() -> Result<(), Error> {
             let target = config.resolve_identity(submatches.value_of("target").unwrap().to_string()).expect("resolving identity from cli");
 
             tokio::run(futures::lazy(move || {
-                update(secrets.identity, target).map_err(|e| error!("{}", e))
+                update(secrets.identity, secrets.psk, target).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -324,7 +327,7 @@ pub fn main_() -> Result<(), Error> {
                 .unwrap_or("/opt/devguard/axon.toml".into());
 
             tokio::run(futures::lazy(move || {
-                axons::axon(secrets.identity, config_file).map_err(|e| error!("{}", e))
+                axons::axon(secrets.identity, secrets.psk, config_file).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -333,7 +336,7 @@ pub fn main_() -> Result<(), Error> {
             let shadow  = submatches.value_of("address").unwrap().to_string().parse().expect("parsing shadow");
 
             tokio::run(futures::lazy(move || {
-                subscribe(secrets.identity, shadow).map_err(|e| error!("{}", e))
+                subscribe(secrets.identity, secrets.psk, shadow).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -348,7 +351,7 @@ pub fn main_() -> Result<(), Error> {
             let remote_file = submatches.value_of("remote-file").unwrap().to_string();
 
             tokio::run(futures::lazy(move || {
-                push(secrets.identity, target, local_file, remote_file).map_err(|e| error!("{}", e))
+                push(secrets.identity, secrets.psk, target, local_file, remote_file).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -376,7 +379,7 @@ pub fn main_() -> Result<(), Error> {
             }
 
             tokio::run(futures::lazy(move || {
-                get(secrets.identity, target, resource, headers).map_err(|e| error!("{}", e))
+                get(secrets.identity, secrets.psk, target, resource, headers).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -396,7 +399,7 @@ pub fn main_() -> Result<(), Error> {
             let remote :u16 = submatches.value_of("remote").unwrap().to_string().parse().unwrap();
 
             tokio::run(futures::lazy(move || {
-                forward::forward(secrets.identity, target, local, remote).map_err(|e| error!("{}", e))
+                forward::forward(secrets.identity, secrets.psk, target, local, remote).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -411,7 +414,7 @@ pub fn main_() -> Result<(), Error> {
                 .resolve_identity(submatches.value_of("target").unwrap().to_string())
                 .expect("resolving identity from cli");
             tokio::run(futures::lazy(move || {
-                shell(secrets.identity, target).map_err(|e| error!("{}", e))
+                shell(secrets.identity, secrets.psk, target).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -460,7 +463,7 @@ pub fn main_() -> Result<(), Error> {
             let epoch: u64 = submatches.value_of("epoch").unwrap().to_string().parse().expect("epoch");
 
             tokio::run(futures::lazy(move || {
-                sync(secrets.identity, broker, epoch).map_err(|e| error!("{}", e))
+                sync(secrets.identity, secrets.psk, broker, epoch).map_err(|e| error!("{}", e))
             }));
             Ok(())
         }
@@ -482,13 +485,17 @@ pub fn main() {
     target_os = "linux",
     target_os = "macos",
 ))]
-pub fn shell(secret: identity::Secret, target: identity::Identity) -> impl Future<Item = (), Error = Error> {
+pub fn shell(
+    secret: identity::Secret,
+    psk: Option<noise::Psk>,
+    target: identity::Identity,
+) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
         info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-        subscriber::connect(target, ep, &mut brk, sock, addr, secret).and_then(move |mut channel| {
+        subscriber::connect(target, ep, &mut brk, sock, addr, secret, psk).and_then(move |mut channel| {
             channel
-                .open(headers::Headers::with_path("/v0/shell"))
+                .open(headers::Headers::with_path("/v0/shell").and(":priority".into(), "0".into()))
                 .expect("open channel")
                 .into_future()
                 .map_err(|(e, _)| e)
@@ -508,10 +515,11 @@ pub fn shell(secret: identity::Secret, target: identity::Identity) -> impl Futur
 
 pub fn subscribe(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     shadow: identity::Address,
 ) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
         brk.message("/carrier.broker.v1/broker/subscribe")
             .unwrap()
             .send(proto::SubscribeRequest {
@@ -543,6 +551,7 @@ pub fn subscribe(
 
 pub fn push(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     target: identity::Identity,
     local_file: String,
     remote_file: String,
@@ -571,11 +580,11 @@ pub fn push(
 
     tokio_fs::file::File::open(local_file)
         .map_err(Error::from)
-        .and_then(|local_file|{
+        .and_then(move |local_file|{
         let local_file = framed::Framed(local_file);
-        connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+        connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
             info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-            subscriber::connect(target, ep, &mut brk, sock, addr, secret).and_then(move |mut channel| {
+            subscriber::connect(target, ep, &mut brk, sock, addr, secret, psk).and_then(move |mut channel| {
                 let headers = headers::Headers::with_path("/v0/sft".as_bytes())
                     .and(":method".into(), "PUT".into())
                     .and("sha256".into(), sha)
@@ -617,14 +626,15 @@ pub fn push(
 
 pub fn get(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     target: identity::Identity,
     resource: String,
     headers: headers::Headers,
 ) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
         info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-        subscriber::connect(target, ep, &mut brk, sock, addr, secret).and_then(move |mut channel| {
+        subscriber::connect(target, ep, &mut brk, sock, addr, secret, psk).and_then(move |mut channel| {
             channel
                 .open(headers)
                 .expect("open channel")
@@ -651,12 +661,13 @@ pub fn get(
 
 pub fn update(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     target: identity::Identity,
 ) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect(domain, secret.clone()).and_then(move |(ep, mut brk, sock, addr)| {
+    connect::connect(domain, secret.clone(), psk).and_then(move |(ep, mut brk, sock, addr)| {
         info!("established broker route {:#x} with {}", brk.route(), brk.identity());
-        subscriber::connect(target, ep, &mut brk, sock, addr, secret).and_then(move |mut channel| {
+        subscriber::connect(target, ep, &mut brk, sock, addr, secret, psk).and_then(move |mut channel| {
             channel
                 .open(headers::Headers::with_path("/v0/self-update").and(":method".into(), "POST".into()))
                 .expect("open channel")
@@ -684,11 +695,12 @@ pub fn update(
 
 pub fn sync(
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     broker: std::net::IpAddr,
     epoch:  u64,
 ) -> impl Future<Item = (), Error = Error> {
     let domain = env::var("CARRIER_BROKER_DOMAIN").unwrap_or("2.carrier.devguard.io".to_string());
-    connect::connect_to_ip(domain, broker, secret.clone()).and_then(move |(_ep, mut brk, _sock, _addr)| {
+    connect::connect_to_ip(domain, broker, secret.clone(), psk).and_then(move |(_ep, mut brk, _sock, _addr)| {
         brk.message("/carrier.broker.v1/broker/epochsync")
             .unwrap()
             .send(proto::EpochSyncRequest{
@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate carrier_core;
+
+use carrier_core::certificate::Certificate;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Certificate::from_signed(data);
+});
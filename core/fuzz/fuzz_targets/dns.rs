@@ -0,0 +1,11 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate carrier_core;
+
+use carrier_core::dns::DnsRecord;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = ::std::str::from_utf8(data) {
+        let _ = DnsRecord::from_signed_txt(s);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate carrier_core;
+
+use carrier_core::noise;
+use carrier_core::packet::{EncryptedPacket, RoutingDirection, HANDSHAKE_VERSION};
+
+// Noise_NN sends the initiator payload in plaintext, so this reaches the handshake payload parser
+fuzz_target!(|data: &[u8]| {
+    let pkt = EncryptedPacket {
+        version:   HANDSHAKE_VERSION,
+        route:     0,
+        direction: RoutingDirection::Initiator2Responder,
+        counter:   0,
+        payload:   data.to_vec().into(),
+    };
+    let _ = noise::respond(None, pkt, None);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate carrier_core;
+
+use carrier_core::headers::{HeaderDecoder, Headers, DEFAULT_TABLE_SIZE};
+
+// the first byte splits the input into two blocks, so the second one is decoded with the table of the first
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let at = (data[0] as usize).min(data.len() - 1);
+    let (first, second) = data[1..].split_at(at);
+
+    let _ = Headers::decode(&data[1..]);
+
+    let mut decoder = HeaderDecoder::new(DEFAULT_TABLE_SIZE);
+    let _ = decoder.decode(first);
+    let _ = decoder.decode(second);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate carrier_core;
+
+use carrier_core::packet::{EncryptedPacket, Frame, SUPPORTED_VERSIONS};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = EncryptedPacket::decode(data);
+    for &version in SUPPORTED_VERSIONS {
+        if let Ok(frames) = Frame::decode(version, data) {
+            for frame in frames {
+                let mut w = Vec::new();
+                let _ = frame.encode(&mut w);
+                let _ = frame.order();
+            }
+        }
+    }
+});
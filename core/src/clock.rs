@@ -0,0 +1,89 @@
+//! time source of a transport::Channel.
+//! everything in the transport counts milliseconds since an arbitrary start, as u64.
+
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    type Performance;
+    static performance: Performance;
+    #[wasm_bindgen(method)]
+    fn now(this: &Performance) -> f64;
+}
+
+pub trait Clock {
+    /// milliseconds since an arbitrary start. must never go backwards
+    fn now(&self) -> u64;
+}
+
+/// the monotonic system clock
+pub struct SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    basetime: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            #[cfg(not(target_arch = "wasm32"))]
+            basetime: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let elapsed = self.basetime.elapsed();
+            elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            performance.now() as u64
+        }
+    }
+}
+
+/// a clock that only moves when told to.
+/// clones share the same time, so a test can keep one and hand the other to a channel
+#[derive(Clone, Default)]
+pub struct ManualClock(Arc<Mutex<u64>>);
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock(Arc::new(Mutex::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        let mut time = self.0.lock().unwrap();
+        assert!(now >= *time, "clock must not go backwards");
+        *time = now;
+    }
+
+    pub fn advance(&self, ms: u64) {
+        *self.0.lock().unwrap() += ms;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn manual_clock() {
+    let clock = ManualClock::new(10);
+    let handle = clock.clone();
+    handle.advance(5);
+    assert_eq!(clock.now(), 15);
+    handle.set(100);
+    assert_eq!(clock.now(), 100);
+}
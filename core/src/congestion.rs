@@ -0,0 +1,501 @@
+//! congestion control, split out of recovery so it can be chosen per channel.
+//!
+//! recovery tracks what is in flight and detects loss. it tells the controller about
+//! sent, acked and lost packets, and the controller decides how many bytes may be in flight.
+
+use std::cmp::{max, min};
+
+// 4.8.1.  Congestion Control Settings
+
+/// The max packet size is used for calculating initial and minimum congestion windows.
+/// Replaced with the path mtu once it is known, see set_mss.
+pub const INITIAL_MSS: u64 = 1460;
+
+/// Limit on the initial amount of outstanding data in packets.
+const INITIAL_WINDOW_PACKETS: u64 = 10;
+
+/// Limit on the initial amount of outstanding data in bytes.
+pub const INITIAL_WINDOW: u64 = INITIAL_WINDOW_PACKETS * INITIAL_MSS;
+
+/// Minimum congestion window in packets.
+const MINIMUM_WINDOW_PACKETS: u64 = 2;
+
+/// Reduction in congestion window when a new loss event is detected.
+const LOSS_REDUCTION_FACTOR: f64 = 0.5;
+
+/// CUBIC scaling constant, RFC 8312 section 5
+const CUBIC_C: f64 = 0.4;
+
+/// CUBIC multiplicative decrease factor, RFC 8312 section 4.5
+const CUBIC_BETA: f64 = 0.7;
+
+/// gain of the Westwood+ bandwidth filter
+const WESTWOOD_GAIN: f64 = 0.125;
+
+/// rtt estimates of the path in milliseconds. 0 if not known yet
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rtt {
+    pub smoothed: u64,
+    pub min:      u64,
+}
+
+pub trait CongestionController {
+    /// 4.8.4. a packet with bytes of retransmittable frames was sent
+    fn on_packet_sent(&mut self, _seq: u64, _bytes: usize, _now: u64) {}
+
+    /// 4.8.5. a packet with bytes of retransmittable frames was acked
+    fn on_ack(&mut self, seq: u64, bytes: usize, now: u64, rtt: Rtt);
+
+    /// 4.8.6. packets up to largest_lost were declared lost.
+    /// largest_sent is the most recently sent packet, which ends the recovery period
+    fn on_loss(&mut self, largest_lost: u64, largest_sent: u64, now: u64);
+
+    /// 4.8.9. an ack confirmed that the retransmission timeout was not spurious
+    fn on_retransmission_timeout_verified(&mut self);
+
+    /// a packet declared lost by a verified retransmission timeout got acked after all.
+    /// restore the window from before on_retransmission_timeout_verified
+    fn on_spurious_retransmission_timeout(&mut self);
+
+    /// the path mtu changed
+    fn set_mss(&mut self, mss: u64);
+
+    /// maximum number of bytes in flight
+    fn window(&self) -> u64;
+
+    /// the window below which the controller is in slow start. u64::max_value() until the first loss
+    fn ssthresh(&self) -> u64;
+}
+
+/// selects a congestion controller for a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    NewReno,
+    Cubic,
+    /// Westwood+, tolerant to random loss on wireless links
+    Westwood,
+}
+
+impl Algorithm {
+    pub fn controller(&self, mss: u64) -> Box<CongestionController + Send> {
+        match self {
+            Algorithm::NewReno => Box::new(NewReno::new(mss)),
+            Algorithm::Cubic => Box::new(Cubic::new(mss)),
+            Algorithm::Westwood => Box::new(Westwood::new(mss)),
+        }
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::NewReno
+    }
+}
+
+/// the controller of draft-ietf-quic-recovery-13
+pub struct NewReno {
+    mss: u64,
+
+    /// Maximum number of bytes-in-flight that may be sent.
+    congestion_window: u64,
+
+    /// The largest packet number sent when QUIC detects a loss.
+    /// When a larger packet is acknowledged, QUIC exits recovery.
+    end_of_recovery: u64,
+
+    /// Slow start threshold in bytes.  When the congestion window
+    /// is below ssthresh, the mode is slow start and the window grows by
+    /// the number of bytes acknowledged.
+    ssthresh: u64,
+
+    /// congestion_window and ssthresh before the last verified retransmission timeout
+    undo: Option<(u64, u64)>,
+}
+
+impl NewReno {
+    pub fn new(mss: u64) -> Self {
+        Self {
+            mss,
+            congestion_window: INITIAL_WINDOW_PACKETS * mss,
+            end_of_recovery: 0,
+            ssthresh: <u64>::max_value(),
+            undo: None,
+        }
+    }
+
+    /// 4.8.5. Congestion Control
+    fn in_recovery(&self, packet_number: u64) -> bool {
+        packet_number <= self.end_of_recovery
+    }
+}
+
+impl CongestionController for NewReno {
+    fn on_ack(&mut self, seq: u64, bytes: usize, _now: u64, _rtt: Rtt) {
+        if self.in_recovery(seq) {
+            // Do not increase congestion window in recovery period.
+            return;
+        }
+
+        if self.congestion_window < self.ssthresh {
+            // Slow start.
+            self.congestion_window += bytes as u64;
+        } else {
+            // Congestion avoidance.
+            self.congestion_window += self.mss * bytes as u64 / self.congestion_window;
+        }
+    }
+
+    fn on_loss(&mut self, largest_lost: u64, largest_sent: u64, _now: u64) {
+        if !self.in_recovery(largest_lost) {
+            self.end_of_recovery = largest_sent;
+            self.congestion_window = (self.congestion_window as f64 * LOSS_REDUCTION_FACTOR) as u64;
+            self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW_PACKETS * self.mss);
+            self.ssthresh = self.congestion_window
+        }
+    }
+
+    // QUIC decreases the congestion window to the minimum value once the
+    // retransmission timeout has been verified.
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.undo = Some((self.congestion_window, self.ssthresh));
+        self.congestion_window = MINIMUM_WINDOW_PACKETS * self.mss;
+    }
+
+    fn on_spurious_retransmission_timeout(&mut self) {
+        if let Some((congestion_window, ssthresh)) = self.undo.take() {
+            self.congestion_window = max(self.congestion_window, congestion_window);
+            self.ssthresh = ssthresh;
+        }
+    }
+
+    // the window is left alone, unless it is now below the minimum
+    fn set_mss(&mut self, mss: u64) {
+        self.mss = mss;
+        self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW_PACKETS * mss);
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+
+    fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+}
+
+/// RFC 8312. grows the window as a cubic function of the time since the last loss,
+/// so it recovers quickly on paths with a large bandwidth delay product
+pub struct Cubic {
+    mss:               u64,
+    congestion_window: u64,
+    end_of_recovery:   u64,
+    ssthresh:          u64,
+
+    /// window in bytes before the last reduction
+    w_max:       f64,
+    /// window in bytes a reno flow would have, for the tcp friendly region
+    w_est:       f64,
+    /// start of the current congestion avoidance epoch
+    epoch_start: Option<u64>,
+    /// congestion_window and ssthresh before the last verified retransmission timeout
+    undo:        Option<(u64, u64)>,
+}
+
+impl Cubic {
+    pub fn new(mss: u64) -> Self {
+        Self {
+            mss,
+            congestion_window: INITIAL_WINDOW_PACKETS * mss,
+            end_of_recovery: 0,
+            ssthresh: <u64>::max_value(),
+            w_max: 0.0,
+            w_est: 0.0,
+            epoch_start: None,
+            undo: None,
+        }
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_ack(&mut self, seq: u64, bytes: usize, now: u64, rtt: Rtt) {
+        if seq <= self.end_of_recovery {
+            return;
+        }
+
+        if self.congestion_window < self.ssthresh {
+            self.congestion_window += bytes as u64;
+            return;
+        }
+
+        let epoch_start = *self.epoch_start.get_or_insert(now);
+        let mss = self.mss as f64;
+        let cwnd = self.congestion_window as f64;
+
+        // the window the cubic function wants one rtt from now
+        let t = (now - epoch_start + rtt.smoothed) as f64 / 1000.0;
+        let k = (self.w_max / mss * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+        let w_cubic = CUBIC_C * (t - k).powi(3) * mss + self.w_max;
+
+        // never grow slower than reno would
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * mss * bytes as f64 / cwnd;
+
+        let target = w_cubic.max(self.w_est);
+        if target > cwnd {
+            self.congestion_window += ((target - cwnd) * bytes as f64 / cwnd) as u64;
+        }
+    }
+
+    fn on_loss(&mut self, largest_lost: u64, largest_sent: u64, _now: u64) {
+        if largest_lost <= self.end_of_recovery {
+            return;
+        }
+        self.end_of_recovery = largest_sent;
+        self.epoch_start = None;
+
+        // fast convergence: release bandwidth to new flows if the window keeps shrinking
+        let cwnd = self.congestion_window as f64;
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+
+        self.congestion_window = max((cwnd * CUBIC_BETA) as u64, MINIMUM_WINDOW_PACKETS * self.mss);
+        self.ssthresh = self.congestion_window;
+        self.w_est = self.congestion_window as f64;
+    }
+
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.undo = Some((self.congestion_window, self.ssthresh));
+        self.congestion_window = MINIMUM_WINDOW_PACKETS * self.mss;
+        self.epoch_start = None;
+    }
+
+    fn on_spurious_retransmission_timeout(&mut self) {
+        if let Some((congestion_window, ssthresh)) = self.undo.take() {
+            self.congestion_window = max(self.congestion_window, congestion_window);
+            self.ssthresh = ssthresh;
+            self.epoch_start = None;
+        }
+    }
+
+    fn set_mss(&mut self, mss: u64) {
+        self.mss = mss;
+        self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW_PACKETS * mss);
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+
+    fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+}
+
+/// Westwood+. grows like NewReno, but on loss it falls back to the estimated
+/// bandwidth delay product instead of halving the window.
+/// random loss on wireless links therefore barely reduces throughput
+pub struct Westwood {
+    mss:               u64,
+    congestion_window: u64,
+    end_of_recovery:   u64,
+    ssthresh:          u64,
+
+    /// filtered bandwidth estimate in bytes per millisecond
+    bandwidth:    f64,
+    /// bytes acked since the start of the current sample
+    acked:        u64,
+    /// one bandwidth sample is taken per rtt
+    sample_start: Option<u64>,
+    min_rtt:      u64,
+    /// congestion_window and ssthresh before the last verified retransmission timeout
+    undo:         Option<(u64, u64)>,
+}
+
+impl Westwood {
+    pub fn new(mss: u64) -> Self {
+        Self {
+            mss,
+            congestion_window: INITIAL_WINDOW_PACKETS * mss,
+            end_of_recovery: 0,
+            ssthresh: <u64>::max_value(),
+            bandwidth: 0.0,
+            acked: 0,
+            sample_start: None,
+            min_rtt: 0,
+            undo: None,
+        }
+    }
+
+    /// the window that would just fill the path, or the halved window if we don't know the bandwidth yet
+    fn bdp(&self) -> u64 {
+        let bdp = if self.bandwidth > 0.0 && self.min_rtt > 0 {
+            (self.bandwidth * self.min_rtt as f64) as u64
+        } else {
+            (self.congestion_window as f64 * LOSS_REDUCTION_FACTOR) as u64
+        };
+        max(bdp, MINIMUM_WINDOW_PACKETS * self.mss)
+    }
+}
+
+impl CongestionController for Westwood {
+    fn on_ack(&mut self, seq: u64, bytes: usize, now: u64, rtt: Rtt) {
+        if rtt.min > 0 {
+            self.min_rtt = rtt.min;
+        }
+
+        self.acked += bytes as u64;
+        let sample_start = *self.sample_start.get_or_insert(now);
+        let interval = now - sample_start;
+        if rtt.smoothed > 0 && interval >= rtt.smoothed {
+            let sample = self.acked as f64 / interval as f64;
+            self.bandwidth = if self.bandwidth > 0.0 {
+                (1.0 - WESTWOOD_GAIN) * self.bandwidth + WESTWOOD_GAIN * sample
+            } else {
+                sample
+            };
+            self.acked = 0;
+            self.sample_start = Some(now);
+        }
+
+        if seq <= self.end_of_recovery {
+            return;
+        }
+
+        if self.congestion_window < self.ssthresh {
+            self.congestion_window += bytes as u64;
+        } else {
+            self.congestion_window += self.mss * bytes as u64 / self.congestion_window;
+        }
+    }
+
+    fn on_loss(&mut self, largest_lost: u64, largest_sent: u64, _now: u64) {
+        if largest_lost <= self.end_of_recovery {
+            return;
+        }
+        self.end_of_recovery = largest_sent;
+        self.ssthresh = self.bdp();
+        self.congestion_window = min(self.congestion_window, self.ssthresh);
+    }
+
+    fn on_retransmission_timeout_verified(&mut self) {
+        self.undo = Some((self.congestion_window, self.ssthresh));
+        self.ssthresh = self.bdp();
+        self.congestion_window = MINIMUM_WINDOW_PACKETS * self.mss;
+    }
+
+    fn on_spurious_retransmission_timeout(&mut self) {
+        if let Some((congestion_window, ssthresh)) = self.undo.take() {
+            self.congestion_window = max(self.congestion_window, congestion_window);
+            self.ssthresh = ssthresh;
+        }
+    }
+
+    fn set_mss(&mut self, mss: u64) {
+        self.mss = mss;
+        self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW_PACKETS * mss);
+    }
+
+    fn window(&self) -> u64 {
+        self.congestion_window
+    }
+
+    fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+}
+
+#[test]
+fn newreno_mss() {
+    let mut cc = NewReno::new(INITIAL_MSS);
+    assert_eq!(cc.window(), INITIAL_WINDOW);
+    cc.on_retransmission_timeout_verified();
+    assert_eq!(cc.window(), 2 * INITIAL_MSS);
+
+    cc.set_mss(1044);
+    assert_eq!(cc.window(), 2 * INITIAL_MSS, "the window does not shrink with the mss");
+    cc.on_retransmission_timeout_verified();
+    assert_eq!(cc.window(), 2 * 1044);
+
+    cc.set_mss(1300);
+    assert_eq!(cc.window(), 2 * 1300, "the window grows to the new minimum");
+}
+
+#[test]
+fn newreno_undo() {
+    let mut cc = NewReno::new(1000);
+    cc.on_loss(1, 10, 0);
+    assert_eq!(cc.window(), 5000);
+
+    cc.on_retransmission_timeout_verified();
+    assert_eq!(cc.window(), 2000);
+    cc.on_spurious_retransmission_timeout();
+    assert_eq!(cc.window(), 5000);
+    assert_eq!(cc.ssthresh, 5000);
+
+    cc.on_spurious_retransmission_timeout();
+    assert_eq!(cc.window(), 5000, "only undone once");
+}
+
+#[test]
+fn cubic_recovers() {
+    let rtt = Rtt { smoothed: 500, min: 500 };
+    let mut cc = Cubic::new(1000);
+    let mut reno = NewReno::new(1000);
+
+    // slow start up to 100 packets
+    for seq in 1..91 {
+        cc.on_ack(seq, 1000, 0, rtt);
+        reno.on_ack(seq, 1000, 0, rtt);
+    }
+    assert_eq!(cc.window(), 100000);
+    cc.on_loss(90, 90, 0);
+    reno.on_loss(90, 90, 0);
+    assert_eq!(cc.window(), 70000, "beta is 0.7");
+
+    // one window worth of acks per rtt
+    let mut seq = 90;
+    let mut now = 0;
+    for _ in 0..10 {
+        now += 500;
+        for _ in 0..cc.window() / 1000 {
+            seq += 1;
+            cc.on_ack(seq, 1000, now, rtt);
+        }
+        for _ in 0..reno.window() / 1000 {
+            seq += 1;
+            reno.on_ack(seq, 1000, now, rtt);
+        }
+    }
+    assert!(cc.window() > 95000, "cubic is back near the window before the loss");
+    assert!(reno.window() < 65000, "reno grows by one packet per rtt");
+}
+
+#[test]
+fn westwood_random_loss() {
+    let rtt = Rtt { smoothed: 100, min: 100 };
+    let mut cc = Westwood::new(1000);
+    let mut reno = NewReno::new(1000);
+    let mut seq = 0;
+    let mut now = 0;
+
+    // the path delivers up to 100 packets per rtt and randomly drops one every 5 rtts
+    for i in 1..51 {
+        now += 100;
+        for _ in 0..min(cc.window() / 1000, 100) {
+            seq += 1;
+            cc.on_ack(seq, 1000, now, rtt);
+        }
+        for _ in 0..min(reno.window() / 1000, 100) {
+            seq += 1;
+            reno.on_ack(seq, 1000, now, rtt);
+        }
+        if i % 5 == 0 {
+            cc.on_loss(seq, seq, now);
+            reno.on_loss(seq, seq, now);
+        }
+    }
+    assert!(reno.window() < 30000, "reno halves the window on every loss");
+    assert!(cc.window() > 2 * reno.window(), "westwood keeps the window at the bandwidth delay product");
+}
@@ -0,0 +1,445 @@
+//! stateless cookies for route 0 handshakes, after the wireguard cookie reply.
+//!
+//! a responder under load answers a handshake with a cookie instead of doing any public key crypto.
+//! the cookie is a mac over the source address, keyed with a secret that is replaced every two minutes,
+//! so the responder does not need to remember anything about the initiator.
+//! the initiator then repeats its handshake with a mac over the handshake payload appended, keyed with the cookie.
+//! only an initiator that can receive packets at its source address can compute that mac,
+//! which takes the responder a single hash to check.
+//! a mac can be replayed, so handshakes with one are rate limited per address.
+//! the cookie reply is encrypted with a key derived from the handshake it answers,
+//! so it cannot be forged by anyone who did not see that handshake.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use packet::{self, EncryptedPacket, RoutingDirection};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use snow::params::CipherChoice;
+use snow::resolvers::{CryptoResolver, HaclStarResolver};
+use snow::types::Cipher;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+pub const COOKIE_SIZE: usize = 16;
+pub const MAC_SIZE: usize = 16;
+
+const NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// payload of a cookie reply: a random nonce and the encrypted cookie with its tag
+pub const REPLY_SIZE: usize = NONCE_SIZE + COOKIE_SIZE + TAG_SIZE;
+
+/// milliseconds a secret is used to hand out cookies. cookies stay valid for another lifetime after that
+pub const SECRET_LIFETIME: u64 = 120000;
+
+/// handshakes are counted in windows of this many milliseconds
+const LOAD_WINDOW: u64 = 1000;
+
+/// handshakes with a valid mac a single address may make per second, and at once, like the wireguard ratelimiter.
+/// a mac can be replayed, so it proves the address but does not limit how often it is used
+const MAC_RATE: u64 = 20;
+const MAC_BURST: u64 = 5;
+
+/// addresses whose rate is tracked at once. handshakes from more addresses are dropped
+const MAX_RATE_ENTRIES: usize = 4096;
+
+const HMAC_BLOCK_SIZE: usize = 64;
+
+pub enum Admission {
+    /// go ahead with the handshake. a mac, if any, is stripped from the payload
+    Accept(EncryptedPacket),
+    /// send this cookie reply to the source address and drop the handshake
+    Retry(EncryptedPacket),
+    /// the address made too many handshakes, drop it without an answer
+    Drop,
+}
+
+/// a token bucket per source address, holding tokens and the time they were counted.
+/// tokens are milliseconds of refill time, so a handshake costs 1000 / MAC_RATE
+struct RateLimiter {
+    buckets: HashMap<IpAddr, (u64, u64)>,
+}
+
+const MAC_COST: u64 = 1000 / MAC_RATE;
+const MAC_CAPACITY: u64 = MAC_BURST * MAC_COST;
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, ip: IpAddr, now: u64) -> bool {
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= MAX_RATE_ENTRIES {
+            // forget addresses whose bucket refilled, they are no different from new ones
+            self.buckets.retain(|_, &mut (tokens, last)| tokens + now.saturating_sub(last) < MAC_CAPACITY);
+            if self.buckets.len() >= MAX_RATE_ENTRIES {
+                return false;
+            }
+        }
+
+        let &mut (ref mut tokens, ref mut last) = self.buckets.entry(ip).or_insert((MAC_CAPACITY, now));
+        *tokens = (*tokens + now.saturating_sub(*last)).min(MAC_CAPACITY);
+        *last = now;
+        if *tokens < MAC_COST {
+            return false;
+        }
+        *tokens -= MAC_COST;
+        true
+    }
+}
+
+/// decides which route 0 handshakes are answered with a cookie reply
+pub struct CookieChecker {
+    secret:     [u8; 32],
+    previous:   [u8; 32],
+    rotated:    u64,
+    window:     u64,
+    handshakes: u32,
+    limit:      u32,
+    rate:       RateLimiter,
+}
+
+impl CookieChecker {
+    /// starts asking for cookies after more than limit handshakes in a second
+    pub fn new(limit: u32, now: u64) -> Self {
+        CookieChecker {
+            secret:     random_secret(),
+            previous:   random_secret(),
+            rotated:    now,
+            window:     now,
+            handshakes: 0,
+            limit,
+            rate:       RateLimiter::new(),
+        }
+    }
+
+    pub fn admit(&mut self, pkt: EncryptedPacket, addr: &SocketAddr, now: u64) -> Admission {
+        self.rotate(now);
+
+        if now >= self.window + LOAD_WINDOW {
+            self.window = now;
+            self.handshakes = 0;
+        }
+        self.handshakes = self.handshakes.saturating_add(1);
+
+        // accepted regardless of load, but only at a limited rate per address
+        if let Some((handshake, their)) = split_mac(&pkt) {
+            let valid = [&self.secret, &self.previous].iter().any(|secret| {
+                let cookie = make_cookie(&secret[..], addr);
+                constant_time_eq(&mac(&cookie, &handshake.payload), &their)
+            });
+            if valid {
+                if !self.rate.allow(addr.ip(), now) {
+                    return Admission::Drop;
+                }
+                return Admission::Accept(handshake);
+            }
+        }
+
+        let handshake = match split_mac(&pkt) {
+            Some((handshake, _)) => handshake,
+            None => pkt,
+        };
+        if self.handshakes <= self.limit {
+            return Admission::Accept(handshake);
+        }
+
+        let cookie = make_cookie(&self.secret[..], addr);
+        Admission::Retry(EncryptedPacket {
+            version:   packet::HANDSHAKE_VERSION,
+            route:     0,
+            direction: RoutingDirection::Responder2Initiator,
+            counter:   0,
+            payload:   Bytes::from(seal(&handshake, &cookie)),
+        })
+    }
+
+    fn rotate(&mut self, now: u64) {
+        if now < self.rotated + SECRET_LIFETIME {
+            return;
+        }
+        self.previous = if now < self.rotated + 2 * SECRET_LIFETIME {
+            self.secret
+        } else {
+            random_secret()
+        };
+        self.secret = random_secret();
+        self.rotated = now;
+    }
+}
+
+/// the cookie carried by a cookie reply to handshake, if pkt is one.
+/// handshake responses never arrive on route 0, so there is nothing to confuse it with.
+/// a reply that was not made for this handshake is ignored
+pub fn cookie_reply(handshake: &EncryptedPacket, pkt: &EncryptedPacket) -> Option<[u8; COOKIE_SIZE]> {
+    if pkt.route != 0 || pkt.direction != RoutingDirection::Responder2Initiator || pkt.payload.len() != REPLY_SIZE {
+        return None;
+    }
+    let nonce = BigEndian::read_u64(&pkt.payload[..NONCE_SIZE]);
+    let mut cookie = [0; COOKIE_SIZE];
+    reply_cipher(handshake).decrypt(nonce, &[], &pkt.payload[NONCE_SIZE..], &mut cookie).ok()?;
+    Some(cookie)
+}
+
+/// the handshake to repeat after receiving a cookie reply
+pub fn with_mac(handshake: &EncryptedPacket, cookie: &[u8; COOKIE_SIZE]) -> EncryptedPacket {
+    let mut payload = BytesMut::with_capacity(handshake.payload.len() + MAC_SIZE);
+    payload.extend_from_slice(&handshake.payload);
+    payload.extend_from_slice(&mac(cookie, &handshake.payload));
+    EncryptedPacket {
+        payload: payload.freeze(),
+        ..handshake.clone()
+    }
+}
+
+/// handshake payloads are padded to 256 bytes, so a mac is the only thing that can stick out
+fn split_mac(pkt: &EncryptedPacket) -> Option<(EncryptedPacket, Bytes)> {
+    if pkt.payload.len() % 256 != MAC_SIZE {
+        return None;
+    }
+    let at = pkt.payload.len() - MAC_SIZE;
+    let handshake = EncryptedPacket {
+        payload: pkt.payload.slice_to(at),
+        ..pkt.clone()
+    };
+    Some((handshake, pkt.payload.slice_from(at)))
+}
+
+/// like the cookie reply of wireguard, which uses the initiator's mac1 as associated data,
+/// but keyed with a hash of the whole handshake payload, since carrier handshakes carry no mac1
+fn seal(handshake: &EncryptedPacket, cookie: &[u8; COOKIE_SIZE]) -> Vec<u8> {
+    let nonce = OsRng::new().unwrap().next_u64();
+    let mut reply = vec![0; REPLY_SIZE];
+    BigEndian::write_u64(&mut reply[..NONCE_SIZE], nonce);
+    reply_cipher(handshake).encrypt(nonce, &[], cookie, &mut reply[NONCE_SIZE..]);
+    reply
+}
+
+fn reply_cipher(handshake: &EncryptedPacket) -> Box<Cipher> {
+    let mut cipher = HaclStarResolver::default()
+        .resolve_cipher(&CipherChoice::ChaChaPoly)
+        .expect("hacl-star implements ChaChaPoly");
+    let mut h = Sha256::default();
+    h.input(b"carrier cookie reply");
+    h.input(&handshake.payload);
+    cipher.set(&h.result());
+    cipher
+}
+
+fn make_cookie(secret: &[u8], addr: &SocketAddr) -> [u8; COOKIE_SIZE] {
+    let mut b = Vec::with_capacity(18);
+    match addr.ip() {
+        IpAddr::V4(ip) => b.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => b.extend_from_slice(&ip.octets()),
+    }
+    b.push((addr.port() >> 8) as u8);
+    b.push(addr.port() as u8);
+    truncate(hmac(secret, &b))
+}
+
+fn mac(cookie: &[u8; COOKIE_SIZE], payload: &[u8]) -> [u8; MAC_SIZE] {
+    truncate(hmac(cookie, payload))
+}
+
+/// RFC 2104 with sha256. keys are never longer than a block here
+fn hmac(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    assert!(key.len() <= HMAC_BLOCK_SIZE);
+    let mut ipad = [0x36; HMAC_BLOCK_SIZE];
+    let mut opad = [0x5c; HMAC_BLOCK_SIZE];
+    for (i, k) in key.iter().enumerate() {
+        ipad[i] ^= k;
+        opad[i] ^= k;
+    }
+
+    let mut inner = Sha256::default();
+    inner.input(&ipad);
+    inner.input(msg);
+
+    let mut outer = Sha256::default();
+    outer.input(&opad);
+    outer.input(&inner.result());
+
+    let mut r = [0; 32];
+    r.copy_from_slice(&outer.result());
+    r
+}
+
+fn truncate(h: [u8; 32]) -> [u8; 16] {
+    let mut r = [0; 16];
+    r.copy_from_slice(&h[..16]);
+    r
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    OsRng::new().unwrap().fill_bytes(&mut secret);
+    secret
+}
+
+#[cfg(test)]
+fn handshake() -> EncryptedPacket {
+    EncryptedPacket {
+        version:   packet::HANDSHAKE_VERSION,
+        route:     0,
+        direction: RoutingDirection::Initiator2Responder,
+        counter:   1,
+        payload:   Bytes::from(vec![7; 256]),
+    }
+}
+
+#[test]
+fn hmac_sha256() {
+    // RFC 4231 test case 2
+    let h = hmac(b"Jefe", b"what do ya want for nothing?");
+    assert_eq!(
+        &h[..],
+        &[
+            0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7, 0x5a, 0x00,
+            0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+        ][..]
+    );
+}
+
+#[test]
+fn retry_under_load() {
+    let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    let mut checker = CookieChecker::new(2, 0);
+
+    for _ in 0..2 {
+        match checker.admit(handshake(), &addr, 0) {
+            Admission::Accept(pkt) => assert_eq!(pkt.payload, handshake().payload),
+            _ => panic!("not under load yet"),
+        }
+    }
+
+    let cookie = match checker.admit(handshake(), &addr, 10) {
+        Admission::Retry(reply) => {
+            let reply = EncryptedPacket::decode(reply.encode()).unwrap();
+            assert_eq!(reply.payload.len(), REPLY_SIZE);
+
+            // the reply only opens with the handshake it answers
+            let other = EncryptedPacket {
+                payload: Bytes::from(vec![8; 256]),
+                ..handshake()
+            };
+            assert!(cookie_reply(&other, &reply).is_none());
+            let mut forged = reply.payload.to_vec();
+            forged[NONCE_SIZE] ^= 1;
+            let forged = EncryptedPacket {
+                payload: Bytes::from(forged),
+                ..reply.clone()
+            };
+            assert!(cookie_reply(&handshake(), &forged).is_none());
+
+            cookie_reply(&handshake(), &reply).expect("cookie reply")
+        }
+        _ => panic!("must ask for a cookie under load"),
+    };
+    assert!(cookie_reply(&handshake(), &handshake()).is_none());
+
+    // the repeated handshake is accepted and arrives without the mac
+    let retry = with_mac(&handshake(), &cookie);
+    assert_eq!(retry.payload.len(), 256 + MAC_SIZE);
+    match checker.admit(retry.clone(), &addr, 20) {
+        Admission::Accept(pkt) => assert_eq!(pkt.payload, handshake().payload),
+        _ => panic!("valid mac must be accepted"),
+    }
+
+    // the cookie is bound to the address and the mac to the handshake
+    let other: SocketAddr = "10.0.0.1:1235".parse().unwrap();
+    assert!(match checker.admit(retry, &other, 30) {
+        Admission::Retry(_) => true,
+        _ => false,
+    });
+    let mut forged = vec![8; 256];
+    forged.extend_from_slice(&with_mac(&handshake(), &cookie).payload[256..]);
+    let forged = EncryptedPacket {
+        payload: Bytes::from(forged),
+        ..handshake()
+    };
+    assert!(match checker.admit(forged, &addr, 40) {
+        Admission::Retry(_) => true,
+        _ => false,
+    });
+
+    // the load is measured per second
+    assert!(match checker.admit(handshake(), &addr, LOAD_WINDOW) {
+        Admission::Accept(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn mac_rate_limit() {
+    let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    let mut checker = CookieChecker::new(0, 0);
+    let cookie = match checker.admit(handshake(), &addr, 0) {
+        Admission::Retry(reply) => cookie_reply(&handshake(), &reply).unwrap(),
+        _ => panic!("limit of 0 always asks for a cookie"),
+    };
+    let retry = with_mac(&handshake(), &cookie);
+    let mut admit = |addr: &SocketAddr, now| match checker.admit(retry.clone(), addr, now) {
+        Admission::Accept(_) => true,
+        Admission::Drop => false,
+        Admission::Retry(_) => panic!("valid mac must not be asked for another cookie"),
+    };
+
+    // replaying the same handshake is only accepted in a burst, then at the configured rate
+    for _ in 0..MAC_BURST {
+        assert!(admit(&addr, 10));
+    }
+    assert!(!admit(&addr, 10));
+    assert!(!admit(&addr, 10 + MAC_COST - 1));
+    assert!(admit(&addr, 10 + MAC_COST));
+
+    // the limit is per ip address, and the mac is bound to the port the cookie was made for
+    let neighbour: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+    assert!(match checker.admit(retry.clone(), &neighbour, 20) {
+        Admission::Retry(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn mac_counts_towards_load() {
+    let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    let mut checker = CookieChecker::new(2, 0);
+    let cookie = make_cookie(&checker.secret[..], &addr);
+    for _ in 0..2 {
+        assert!(match checker.admit(with_mac(&handshake(), &cookie), &addr, 0) {
+            Admission::Accept(_) => true,
+            _ => false,
+        });
+    }
+    assert!(match checker.admit(handshake(), &addr, 0) {
+        Admission::Retry(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn secret_rotation() {
+    let addr: SocketAddr = "[::1]:1234".parse().unwrap();
+    let mut checker = CookieChecker::new(0, 0);
+    let cookie = match checker.admit(handshake(), &addr, 0) {
+        Admission::Retry(reply) => cookie_reply(&handshake(), &reply).unwrap(),
+        _ => panic!("limit of 0 always asks for a cookie"),
+    };
+    let retry = with_mac(&handshake(), &cookie);
+
+    let accepted = |checker: &mut CookieChecker, now| match checker.admit(retry.clone(), &addr, now) {
+        Admission::Accept(_) => true,
+        _ => false,
+    };
+    assert!(accepted(&mut checker, SECRET_LIFETIME - 1));
+    assert!(accepted(&mut checker, SECRET_LIFETIME + 1));
+    assert!(!accepted(&mut checker, 3 * SECRET_LIFETIME));
+}
@@ -50,3 +50,23 @@ impl DnsRecord {
         })
     }
 }
+
+#[test]
+fn signed_txt() {
+    let secret = Secret::gen();
+    let record = DnsRecord {
+        priority: 1,
+        addr:     "127.0.0.1:8443".parse().unwrap(),
+        x:        Secret::gen().address(),
+        epoch:    2,
+    };
+    let txt = record.to_signed_txt(&secret);
+    let parsed = DnsRecord::from_signed_txt(&txt).unwrap();
+    assert_eq!(parsed.addr, record.addr);
+    assert_eq!(parsed.x, record.x);
+
+    // must not panic on any truncation
+    for len in 0..txt.len() {
+        let _ = DnsRecord::from_signed_txt(&txt[..len]);
+    }
+}
@@ -0,0 +1,161 @@
+//! byte based flow control, similar to QUIC MAX_DATA and MAX_STREAM_DATA.
+//!
+//! each receiver gives the sender credit as a cumulative limit of bytes it may send.
+//! credit is counted in encoded Stream and Fragment frame bytes, so that empty messages
+//! still take up space in the receivers buffers.
+//! both sides start with the default windows and the receiver raises the limit
+//! as the application consumes data.
+
+use failure::Error;
+use packet::Frame;
+use std::cmp::max;
+
+/// initial credit for each stream
+pub const DEFAULT_STREAM_WINDOW: u64 = 1024 * 1024;
+
+/// initial credit for all streams of a channel combined
+pub const DEFAULT_CHANNEL_WINDOW: u64 = 4 * 1024 * 1024;
+
+/// credit for peers of wire version 0x08, which have no flow control
+pub const UNLIMITED_WINDOW: u64 = u64::max_value();
+
+#[derive(Debug, Fail)]
+enum FlowError {
+    #[fail(display = "flow control violation: peer sent {} bytes with a limit of {}", received, limit)]
+    Exceeded { received: u64, limit: u64 },
+}
+
+/// data frames count against flow control with their encoded size
+pub fn charge(frame: &Frame) -> u64 {
+    match frame {
+        Frame::Stream { .. } | Frame::Fragment { .. } => frame.len() as u64,
+        _ => 0,
+    }
+}
+
+/// credit we have to send to the peer
+pub struct SendWindow {
+    sent:  u64,
+    limit: u64,
+}
+
+impl SendWindow {
+    pub fn new(limit: u64) -> Self {
+        Self { sent: 0, limit }
+    }
+
+    /// bytes we may still send
+    pub fn credit(&self) -> u64 {
+        self.limit.saturating_sub(self.sent)
+    }
+
+    /// total bytes sent
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    pub fn on_sent(&mut self, bytes: u64) {
+        self.sent += bytes;
+    }
+
+    /// the peer raised the limit. limits may arrive out of order, so they never decrease.
+    pub fn on_limit(&mut self, limit: u64) {
+        self.limit = max(self.limit, limit);
+    }
+}
+
+/// credit we gave to the peer
+pub struct RecvWindow {
+    window:   u64,
+    received: u64,
+    consumed: u64,
+    limit:    u64,
+}
+
+impl RecvWindow {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window:   window,
+            received: 0,
+            consumed: 0,
+            limit:    window,
+        }
+    }
+
+    pub fn on_recv(&mut self, bytes: u64) -> Result<(), Error> {
+        match self.received.checked_add(bytes) {
+            Some(received) if received <= self.limit => {
+                self.received = received;
+                Ok(())
+            }
+            _ => Err(FlowError::Exceeded {
+                received: self.received.saturating_add(bytes),
+                limit:    self.limit,
+            }.into()),
+        }
+    }
+
+    /// total bytes received
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// total bytes consumed
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// the credit we gave
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn on_consumed(&mut self, bytes: u64) {
+        self.consumed = self.consumed.saturating_add(bytes);
+    }
+
+    /// returns a new limit to advertise, once the application consumed half the window
+    pub fn update(&mut self) -> Option<u64> {
+        if self.limit.saturating_sub(self.consumed) > self.window / 2 {
+            return None;
+        }
+        let limit = self.consumed.saturating_add(self.window);
+        if limit == self.limit {
+            return None;
+        }
+        self.limit = limit;
+        Some(self.limit)
+    }
+}
+
+#[test]
+fn windows() {
+    let mut rx = RecvWindow::new(100);
+    let mut tx = SendWindow::new(100);
+
+    assert_eq!(tx.credit(), 100);
+    tx.on_sent(60);
+    rx.on_recv(60).unwrap();
+    assert_eq!(tx.credit(), 40);
+    assert_eq!(rx.update(), None, "nothing consumed yet");
+
+    rx.on_consumed(40);
+    assert_eq!(rx.update(), None, "less than half the window consumed");
+    rx.on_consumed(20);
+    assert_eq!(rx.update(), Some(160));
+
+    tx.on_limit(160);
+    tx.on_limit(100);
+    assert_eq!(tx.credit(), 100, "older limits are ignored");
+
+    assert!(rx.on_recv(101).is_err());
+
+    // no overflow near the end of the counters
+    let mut rx = RecvWindow::new(UNLIMITED_WINDOW);
+    rx.on_recv(u64::max_value() - 1).unwrap();
+    assert!(rx.on_recv(u64::max_value()).is_err());
+    assert_eq!(rx.received(), u64::max_value() - 1);
+    rx.on_consumed(u64::max_value());
+    rx.on_consumed(1);
+    assert_eq!(rx.update(), None);
+}
@@ -1,7 +1,18 @@
+use std::collections::VecDeque;
 use std::io::{Error, ErrorKind};
 use std::iter::Iterator;
 use std::fmt;
 
+/// size of the hpack dynamic table until the peer says otherwise, from RFC 7541
+pub const DEFAULT_TABLE_SIZE: u32 = 4096;
+
+/// number of entries in the hpack static table. dynamic table indices start after it
+const STATIC_TABLE_LEN: usize = 61;
+
+/// largest number of fields in a header block. an indexed field expands to a whole table entry,
+/// so this bounds what decoding a single block can allocate
+pub const MAX_FIELDS: usize = 64;
+
 #[derive(Default, Clone)]
 pub struct Headers {
     f: Vec<(Vec<u8>,Vec<u8>)>,
@@ -62,6 +73,13 @@ impl Headers {
         path
     }
 
+    /// sending priority of the stream. lower values are more urgent
+    pub fn priority(&self) -> Option<u8> {
+        self.get(b":priority")
+            .and_then(|v| ::std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse().ok())
+    }
+
     pub fn add(&mut self, k: Vec<u8>, v: Vec<u8>) {
         self.f.push((k,v));
     }
@@ -79,6 +97,14 @@ impl Headers {
 
     pub fn decode(b: &[u8]) -> Result<Self, Error> {
         use hpack::Decoder;
+        for size in check_block(b)? {
+            if size > DEFAULT_TABLE_SIZE as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("hpack table size {} exceeds maximum of {}", size, DEFAULT_TABLE_SIZE),
+                ));
+            }
+        }
         let h = Decoder::new()
             .decode(&b)
             .map_err(|e|Error::new(ErrorKind::InvalidData, format!("{:?}", e)))
@@ -106,3 +132,431 @@ impl fmt::Debug for Headers {
         Ok(())
     }
 }
+
+
+/// hpack encoder that keeps its dynamic table across header blocks.
+/// the blocks must be decoded by a single HeaderDecoder in the order they were encoded.
+/// it only indexes into its own dynamic table and never uses huffman coding
+pub struct HeaderEncoder {
+    /// newest entry first
+    table:    VecDeque<(Vec<u8>, Vec<u8>)>,
+    size:     usize,
+    max_size: usize,
+    /// smallest table size since the last block, if it changed. the next block starts with a size update
+    resize:   Option<usize>,
+}
+
+impl HeaderEncoder {
+    pub fn new(max_size: u32) -> Self {
+        let mut encoder = Self {
+            table:    VecDeque::new(),
+            size:     0,
+            max_size: DEFAULT_TABLE_SIZE as usize,
+            resize:   None,
+        };
+        encoder.set_max_size(max_size);
+        encoder
+    }
+
+    /// limit the dynamic table to what the decoder of the peer accepts
+    pub fn set_max_size(&mut self, max_size: u32) {
+        let max_size = max_size as usize;
+        if max_size == self.max_size {
+            return;
+        }
+        self.max_size = max_size;
+        self.resize = Some(self.resize.map(|v| v.min(max_size)).unwrap_or(max_size));
+        self.evict();
+    }
+
+    /// upper bound for the length of the encoded headers, regardless of what is in the table
+    pub fn max_encoded_len(headers: &Headers) -> usize {
+        // two size updates, and every field as a literal with a literal name
+        let updates = 2 * 6;
+        headers.iter().fold(updates, |len, (k, v)| len + 1 + 5 + k.len() + 5 + v.len())
+    }
+
+    pub fn encode(&mut self, headers: &Headers) -> Vec<u8> {
+        let mut w = Vec::with_capacity(Self::max_encoded_len(headers));
+
+        if let Some(smallest) = self.resize.take() {
+            encode_integer(&mut w, 0x20, 5, smallest);
+            if smallest != self.max_size {
+                encode_integer(&mut w, 0x20, 5, self.max_size);
+            }
+        }
+
+        for (k, v) in headers.iter() {
+            if let Some(i) = self.table.iter().position(|e| e.0 == k && e.1 == v) {
+                encode_integer(&mut w, 0x80, 7, STATIC_TABLE_LEN + 1 + i);
+                continue;
+            }
+
+            match self.table.iter().position(|e| e.0 == k) {
+                Some(i) => encode_integer(&mut w, 0x40, 6, STATIC_TABLE_LEN + 1 + i),
+                None => {
+                    w.push(0x40);
+                    encode_string(&mut w, k);
+                }
+            }
+            encode_string(&mut w, v);
+
+            self.size += k.len() + v.len() + 32;
+            self.table.push_front((k.to_vec(), v.to_vec()));
+            self.evict();
+        }
+        w
+    }
+
+    /// an entry larger than the table evicts everything, including itself
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let (k, v) = self.table.pop_back().unwrap();
+            self.size -= k.len() + v.len() + 32;
+        }
+    }
+}
+
+/// RFC 7541 5.1
+fn encode_integer(w: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let limit = (1 << prefix) - 1;
+    if value < limit {
+        w.push(flags | value as u8);
+        return;
+    }
+    w.push(flags | limit as u8);
+    value -= limit;
+    while value >= 128 {
+        w.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    w.push(value as u8);
+}
+
+/// RFC 7541 5.2, without huffman coding
+fn encode_string(w: &mut Vec<u8>, s: &[u8]) {
+    encode_integer(w, 0x00, 7, s.len());
+    w.extend_from_slice(s);
+}
+
+/// hpack decoder that keeps its dynamic table across header blocks
+pub struct HeaderDecoder {
+    decoder:  ::hpack::Decoder<'static>,
+    /// largest table size the peer may use
+    max_size: usize,
+    /// a smaller limit the peer has not confirmed with a size update yet
+    lowered:  Option<usize>,
+}
+
+impl HeaderDecoder {
+    pub fn new(max_size: u32) -> Self {
+        let mut decoder = ::hpack::Decoder::new();
+        decoder.set_max_table_size(max_size as usize);
+        Self {
+            decoder:  decoder,
+            max_size: max_size as usize,
+            lowered:  None,
+        }
+    }
+
+    /// limit the dynamic table the peer may use.
+    /// a smaller limit is enforced once the peer encodes a size update within it,
+    /// since blocks it encoded before learning about the limit may still arrive
+    pub fn set_max_size(&mut self, max_size: u32) {
+        let max_size = max_size as usize;
+        if max_size >= self.max_size {
+            self.max_size = max_size;
+            self.lowered = None;
+        } else {
+            self.lowered = Some(max_size);
+        }
+    }
+
+    pub fn decode(&mut self, b: &[u8]) -> Result<Headers, Error> {
+        for size in check_block(b)? {
+            if size > self.max_size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("hpack table size {} exceeds maximum of {}", size, self.max_size),
+                ));
+            }
+            if let Some(lowered) = self.lowered {
+                if size <= lowered {
+                    self.max_size = lowered;
+                    self.lowered = None;
+                }
+            }
+        }
+
+        let h = self.decoder
+            .decode(b)
+            .map_err(|e|Error::new(ErrorKind::InvalidData, format!("{:?}", e)))
+            ?;
+
+        Ok(Headers {
+            f: h
+        })
+    }
+}
+
+/// walk a header block without decoding it, so that malformed integers and strings are rejected
+/// before they reach the hpack decoder, which panics on some of them.
+/// returns the table size updates, which are only allowed at the start of a block
+fn check_block(b: &[u8]) -> Result<Vec<usize>, Error> {
+    let mut r = b;
+    let mut updates = Vec::new();
+    let mut fields = 0;
+    while !r.is_empty() {
+        let consumed = if r[0] & 0x80 == 0x80 {
+            decode_integer(r, 7)?.1
+        } else if r[0] & 0xc0 == 0x40 {
+            check_literal(r, 6)?
+        } else if r[0] & 0xe0 == 0x20 {
+            if fields > 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "hpack table size update after a header field"));
+            }
+            let (size, consumed) = decode_integer(r, 5)?;
+            updates.push(size);
+            r = &r[consumed..];
+            continue;
+        } else {
+            check_literal(r, 4)?
+        };
+        fields += 1;
+        if fields > MAX_FIELDS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("header block has more than {} fields", MAX_FIELDS),
+            ));
+        }
+        r = &r[consumed..];
+    }
+    Ok(updates)
+}
+
+/// a literal field with a name that is either indexed or a literal itself
+fn check_literal(b: &[u8], prefix: u8) -> Result<usize, Error> {
+    let (index, mut consumed) = decode_integer(b, prefix)?;
+    if index == 0 {
+        consumed += check_string(&b[consumed..])?;
+    }
+    consumed += check_string(&b[consumed..])?;
+    Ok(consumed)
+}
+
+fn check_string(b: &[u8]) -> Result<usize, Error> {
+    let (len, consumed) = decode_integer(b, 7)?;
+    if len > b.len() - consumed {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated hpack string"));
+    }
+    Ok(consumed + len)
+}
+
+/// RFC 7541 5.1. returns the value and the number of bytes it took.
+/// at most 4 continuation bytes are accepted, the same as the hpack decoder does
+fn decode_integer(b: &[u8], prefix: u8) -> Result<(usize, usize), Error> {
+    if b.is_empty() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated hpack integer"));
+    }
+    let limit = (1 << prefix) - 1;
+    let mut value = (b[0] & limit) as usize;
+    if value < limit as usize {
+        return Ok((value, 1));
+    }
+    for i in 1..b.len().min(5) {
+        value += ((b[i] & 0x7f) as usize) << (7 * (i - 1));
+        if b[i] & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "invalid hpack integer"))
+}
+
+#[cfg(test)]
+fn block(path: &str) -> Headers {
+    Headers::with_path(path).and(b":method".to_vec(), b"POST".to_vec())
+}
+
+#[test]
+fn persistent_context() {
+    let mut encoder = HeaderEncoder::new(DEFAULT_TABLE_SIZE);
+    let mut decoder = HeaderDecoder::new(DEFAULT_TABLE_SIZE);
+
+    let first = encoder.encode(&block("/v0/shell"));
+    let second = encoder.encode(&block("/v0/shell"));
+    let third = encoder.encode(&block("/v0/sft"));
+    assert_eq!(second, &[0x80 | 63, 0x80 | 62], "repeated headers are indexed");
+    assert!(third.len() < first.len(), "header names are indexed");
+
+    for (b, path) in vec![(first, "/v0/shell"), (second, "/v0/shell"), (third, "/v0/sft")] {
+        let headers = decoder.decode(&b).unwrap();
+        assert_eq!(headers.path(), Some(path.as_bytes()));
+        assert_eq!(headers.get(b":method"), Some(&b"POST"[..]));
+    }
+}
+
+#[test]
+fn table_size() {
+    let mut encoder = HeaderEncoder::new(64);
+    let mut decoder = HeaderDecoder::new(64);
+
+    // every entry takes at least 32 bytes, so only one fits
+    for _ in 0..3 {
+        let b = encoder.encode(&block("/a"));
+        assert_eq!(decoder.decode(&b).unwrap().path(), Some(&b"/a"[..]));
+    }
+    assert_eq!(encoder.table.len(), 1);
+
+    encoder.set_max_size(0);
+    let b = encoder.encode(&block("/a"));
+    assert_eq!(b[0], 0x20, "starts with a size update");
+    assert_eq!(decoder.decode(&b).unwrap().path(), Some(&b"/a"[..]));
+    assert!(encoder.table.is_empty());
+
+    let mut encoder = HeaderEncoder::new(8192);
+    let b = encoder.encode(&block("/a"));
+    assert!(decoder.decode(&b).is_err(), "peer must not use a larger table than we allowed");
+}
+
+#[test]
+fn lower_table_size() {
+    let mut encoder = HeaderEncoder::new(DEFAULT_TABLE_SIZE);
+    let mut decoder = HeaderDecoder::new(DEFAULT_TABLE_SIZE);
+
+    // a block encoded before the peer learned about the smaller limit
+    let old = encoder.encode(&block("/a"));
+    decoder.set_max_size(64);
+    assert!(decoder.decode(&old).is_ok());
+
+    encoder.set_max_size(64);
+    assert!(decoder.decode(&encoder.encode(&block("/a"))).is_ok());
+
+    let mut b = Vec::new();
+    encode_integer(&mut b, 0x20, 5, 128);
+    assert!(decoder.decode(&b).is_err(), "the peer confirmed the smaller limit");
+}
+
+#[test]
+fn integers() {
+    for &(prefix, value) in &[(5, 0), (5, 30), (5, 31), (7, 126), (7, 127), (6, 1337), (5, 1 << 20)] {
+        let mut w = Vec::new();
+        encode_integer(&mut w, 0, prefix, value);
+        assert_eq!(decode_integer(&w, prefix).unwrap(), (value, w.len()));
+    }
+    assert!(decode_integer(&[0x1f, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80], 5).is_err());
+    assert!(decode_integer(&[0x1f, 0x80, 0x80, 0x80, 0x80, 0x01], 5).is_err());
+    assert!(decode_integer(&[0x1f, 0x80], 5).is_err());
+    assert!(decode_integer(&[], 5).is_err());
+}
+
+#[test]
+fn malformed_blocks() {
+    let mut decoder = HeaderDecoder::new(DEFAULT_TABLE_SIZE);
+
+    // size update with an integer the hpack decoder cannot parse, after a field
+    assert!(decoder.decode(&[0x82, 0x3f, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    assert!(Headers::decode(&[0x82, 0x3f, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    // size update after a field, even if it is valid
+    assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    // string longer than the block
+    assert!(decoder.decode(&[0x40, 0x05, b'a']).is_err());
+    // size update larger than the table of a fresh context
+    assert!(Headers::decode(&[0x3f, 0xe1, 0x7f]).is_err());
+
+    let fields = vec![0x82; MAX_FIELDS + 1];
+    assert!(decoder.decode(&fields).is_err());
+    assert_eq!(decoder.decode(&fields[..MAX_FIELDS]).unwrap().iter().count(), MAX_FIELDS);
+}
+
+#[test]
+fn channel_context() {
+    use netsim::{network, LinkConfig};
+    use std::collections::HashMap;
+    use transport::ChannelProgress;
+
+    let mut net = network(
+        4,
+        LinkConfig {
+            delay:   50,
+            loss:    0.3,
+            reorder: 0.2,
+            ..LinkConfig::default()
+        },
+    );
+    // both sides learn that the other keeps an hpack context
+    net.run_until(1000, |net| net.now() >= 1000);
+
+    let mut opened = HashMap::new();
+    for i in 0..100 {
+        let headers = Headers::with_path(format!("/rpc/{}", i % 7)).and(b":id".to_vec(), format!("{}", i).into());
+        let stream = net.initiator.channel.open(&headers, true).unwrap();
+        if i % 10 == 0 {
+            // the header block of a reset stream is still needed to decode the others
+            net.initiator.channel.reset(stream, 1);
+        } else {
+            opened.insert(stream, i);
+        }
+    }
+
+    let mut received = 0;
+    let done = net.run_until(600000, |net| {
+        for event in net.responder.events.drain(..) {
+            if let ChannelProgress::ReceiveHeader(stream, headers) = event {
+                if let Some(i) = opened.get(&stream) {
+                    assert_eq!(headers.path(), Some(format!("/rpc/{}", i % 7).as_bytes()));
+                    assert_eq!(headers.get(b":id"), Some(format!("{}", i).as_bytes()));
+                    received += 1;
+                }
+            }
+        }
+        received == opened.len()
+    });
+    assert!(done, "only {} of {} headers arrived", received, opened.len());
+}
+
+#[test]
+fn fragmented_headers() {
+    use netsim::{network, LinkConfig};
+    use std::collections::HashMap;
+    use transport::ChannelProgress;
+
+    let mut net = network(
+        7,
+        LinkConfig {
+            delay:   50,
+            loss:    0.3,
+            reorder: 0.2,
+            ..LinkConfig::default()
+        },
+    );
+    net.run_until(1000, |net| net.now() >= 1000);
+
+    // blocks larger than a packet, between ones that fit, and one on a reset stream
+    let mut opened = HashMap::new();
+    for i in 0..20 {
+        let value: Vec<u8> = (0..i * 300).map(|_| ::rand::random::<u8>()).collect();
+        let headers = Headers::with_path("/large").and(b":value".to_vec(), value.clone());
+        let stream = net.initiator.channel.open(&headers, true).unwrap();
+        if i == 10 {
+            net.initiator.channel.reset(stream, 1);
+        } else {
+            opened.insert(stream, value);
+        }
+    }
+    let huge = Headers::with_path("/huge").and(b":value".to_vec(), vec![0; 20 * 1024]);
+    assert!(net.initiator.channel.open(&huge, true).is_err());
+
+    let mut received = 0;
+    let done = net.run_until(600000, |net| {
+        for event in net.responder.events.drain(..) {
+            if let ChannelProgress::ReceiveHeader(stream, headers) = event {
+                if let Some(value) = opened.get(&stream) {
+                    assert_eq!(headers.get(b":value"), Some(&value[..]));
+                    received += 1;
+                }
+            }
+        }
+        received == opened.len()
+    });
+    assert!(done, "only {} of {} headers arrived", received, opened.len());
+}
@@ -101,7 +101,7 @@ impl FromStr for Secret {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = bs58::decode(s).with_alphabet(bs58::alphabet::BITCOIN).into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(IdentityError::InvalidLen.into());
         }
 
@@ -173,7 +173,7 @@ impl FromStr for Address {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = bs58::decode(s).with_alphabet(bs58::alphabet::BITCOIN).into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(IdentityError::InvalidLen.into());
         }
 
@@ -191,10 +191,6 @@ impl FromStr for Address {
             return Err(IdentityError::InvalidAddress.into());
         }
 
-        if s.len() - 3 < 32 {
-            return Err(IdentityError::InvalidAddress.into());
-        }
-
         let mut b = [0; 32];
         b.copy_from_slice(&s[2..s.len() - 1]);
 
@@ -253,7 +249,7 @@ impl FromStr for Signature {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = bs58::decode(s).with_alphabet(bs58::alphabet::BITCOIN).into_vec()?;
-        if s.len() < 67 {
+        if s.len() != 67 {
             return Err(IdentityError::InvalidLen.into());
         }
 
@@ -335,7 +331,7 @@ impl FromStr for Identity {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = bs58::decode(s).with_alphabet(bs58::alphabet::BITCOIN).into_vec()?;
 
-        if s.len() < 35 {
+        if s.len() != 35 {
             return Err(IdentityError::InvalidLen.into());
         }
 
@@ -422,6 +418,23 @@ fn parse() {
     assert_eq!(id.to_string(), s);
 }
 
+#[test]
+fn parse_invalid_len() {
+    // a valid checksum over one byte too many
+    for &(tag, len) in &[(9, 33), (6, 33), (3, 33), (2, 65)] {
+        let mut v = vec![8, tag];
+        v.extend_from_slice(&vec![0x42; len]);
+        let crc = crc8::Crc8::create_lsb(130).calc(&v, v.len() as i32, 0);
+        v.push(crc);
+        let s = bs58::encode(v).with_alphabet(bs58::alphabet::BITCOIN).into_string();
+        assert!(s.parse::<Identity>().is_err());
+        assert!(s.parse::<Address>().is_err());
+        assert!(s.parse::<Secret>().is_err());
+        assert!(s.parse::<Signature>().is_err());
+    }
+    assert!("".parse::<Identity>().is_err());
+}
+
 #[test]
 fn public_id() {
     let client_secret = Secret::from_array([
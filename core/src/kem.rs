@@ -0,0 +1,127 @@
+//! ML-KEM-512 for the hybrid handshake, which needs the mlkem feature.
+//!
+//! the initiator sends an encapsulation key in its handshake and the responder answers with a ciphertext.
+//! the shared secret is mixed into every key the handshake sets after both sides know it,
+//! so the transport keys stay secret as long as either X25519 or ML-KEM does.
+//! ML-KEM-512 rather than 768 keeps both handshake messages within 1044 byte packets.
+
+use failure::Error;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "mlkem")]
+use pqcrypto_mlkem::mlkem512;
+#[cfg(feature = "mlkem")]
+use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
+
+pub const PUBLIC_KEY_SIZE: usize = 800;
+pub const CIPHERTEXT_SIZE: usize = 768;
+
+pub type KemSecret = [u8; 32];
+
+#[derive(Debug, Fail)]
+enum KemError {
+    #[fail(display = "built without ML-KEM support")]
+    Unavailable,
+
+    #[cfg_attr(not(feature = "mlkem"), allow(dead_code))]
+    #[fail(display = "ML-KEM {} must be {} bytes, got {}", what, need, got)]
+    InvalidLength { what: &'static str, need: usize, got: usize },
+}
+
+/// the secret half of the initiator's key pair, used once to decapsulate the response
+#[cfg(feature = "mlkem")]
+pub struct DecapsulationKey(mlkem512::SecretKey);
+
+#[cfg(not(feature = "mlkem"))]
+pub enum DecapsulationKey {}
+
+pub fn available() -> bool {
+    cfg!(feature = "mlkem")
+}
+
+/// a fresh key pair for every handshake, and the encapsulation key to send
+#[cfg(feature = "mlkem")]
+pub fn keypair() -> Result<(DecapsulationKey, Vec<u8>), Error> {
+    let (public, secret) = mlkem512::keypair();
+    Ok((DecapsulationKey(secret), public.as_bytes().to_vec()))
+}
+
+#[cfg(not(feature = "mlkem"))]
+pub fn keypair() -> Result<(DecapsulationKey, Vec<u8>), Error> {
+    Err(KemError::Unavailable.into())
+}
+
+/// the ciphertext to send back, and the shared secret
+#[cfg(feature = "mlkem")]
+pub fn encapsulate(public: &[u8]) -> Result<(Vec<u8>, KemSecret), Error> {
+    let public = mlkem512::PublicKey::from_bytes(public).map_err(|_| KemError::InvalidLength {
+        what: "encapsulation key",
+        need: PUBLIC_KEY_SIZE,
+        got:  public.len(),
+    })?;
+    let (secret, ciphertext) = mlkem512::encapsulate(&public);
+    Ok((ciphertext.as_bytes().to_vec(), to_secret(secret.as_bytes())))
+}
+
+#[cfg(not(feature = "mlkem"))]
+pub fn encapsulate(_public: &[u8]) -> Result<(Vec<u8>, KemSecret), Error> {
+    Err(KemError::Unavailable.into())
+}
+
+/// a modified ciphertext does not fail here, it yields a different secret
+#[cfg(feature = "mlkem")]
+pub fn decapsulate(key: &DecapsulationKey, ciphertext: &[u8]) -> Result<KemSecret, Error> {
+    let ciphertext = mlkem512::Ciphertext::from_bytes(ciphertext).map_err(|_| KemError::InvalidLength {
+        what: "ciphertext",
+        need: CIPHERTEXT_SIZE,
+        got:  ciphertext.len(),
+    })?;
+    Ok(to_secret(mlkem512::decapsulate(&ciphertext, &key.0).as_bytes()))
+}
+
+#[cfg(not(feature = "mlkem"))]
+pub fn decapsulate(key: &DecapsulationKey, _ciphertext: &[u8]) -> Result<KemSecret, Error> {
+    match *key {}
+}
+
+/// combines a cipher key set by the handshake with the shared secret
+pub fn mix(key: &[u8], secret: &KemSecret) -> [u8; 32] {
+    let mut h = Sha256::default();
+    h.input(b"carrier hybrid key");
+    h.input(key);
+    h.input(secret);
+    let mut r = [0; 32];
+    r.copy_from_slice(&h.result());
+    r
+}
+
+#[cfg(feature = "mlkem")]
+fn to_secret(b: &[u8]) -> KemSecret {
+    let mut r = [0; 32];
+    r.copy_from_slice(b);
+    r
+}
+
+#[cfg(not(feature = "mlkem"))]
+#[test]
+fn unavailable() {
+    assert!(keypair().is_err());
+    assert!(encapsulate(&[0; PUBLIC_KEY_SIZE]).is_err());
+}
+
+#[cfg(feature = "mlkem")]
+#[test]
+fn encapsulation() {
+    let (key, public) = keypair().unwrap();
+    assert_eq!(public.len(), PUBLIC_KEY_SIZE);
+    let (ciphertext, secret) = encapsulate(&public).unwrap();
+    assert_eq!(ciphertext.len(), CIPHERTEXT_SIZE);
+    assert_eq!(decapsulate(&key, &ciphertext).unwrap(), secret);
+
+    let mut modified = ciphertext.clone();
+    modified[0] ^= 1;
+    assert_ne!(decapsulate(&key, &modified).unwrap(), secret);
+    assert!(decapsulate(&key, &ciphertext[1..]).is_err());
+    assert!(encapsulate(&public[1..]).is_err());
+
+    assert_ne!(mix(&[1; 32], &secret), mix(&[1; 32], &[0; 32]));
+}
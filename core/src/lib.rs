@@ -17,6 +17,10 @@ extern crate prost;
 extern crate prost_derive;
 extern crate bytes;
 extern crate hpack;
+#[cfg(feature = "mlkem")]
+extern crate pqcrypto_mlkem;
+#[cfg(feature = "mlkem")]
+extern crate pqcrypto_traits;
 
 #[macro_use]
 #[cfg(target_arch = "wasm32")]
@@ -25,11 +29,20 @@ extern crate wasm_bindgen;
 pub mod identity;
 pub mod noise;
 pub mod packet;
+pub mod pmtud;
 pub mod recovery;
+pub mod recvbuf;
+pub mod flow;
 pub mod replay;
 pub mod stream;
 pub mod transport;
 pub mod certificate;
+pub mod cookie;
+pub mod kem;
+pub mod clock;
+pub mod congestion;
+#[cfg(any(test, feature = "netsim"))]
+pub mod netsim;
 pub mod dns;
 pub mod rpc;
 pub mod headers;
@@ -0,0 +1,434 @@
+//! a deterministic in-process network for testing transport::Channel.
+//!
+//! links behave like `tc qdisc netem` with delay, jitter, loss, duplication and reordering,
+//! but all randomness comes from a seed and time only moves when the simulation advances it.
+//! the same seed always produces the same run.
+
+use clock::{Clock, ManualClock};
+use packet::EncryptedPacket;
+use std::cmp::min;
+use transport::{self, ChannelProgress};
+
+/// how a link treats packets, like the arguments to netem
+#[derive(Clone, Debug, Default)]
+pub struct LinkConfig {
+    /// one way delay in milliseconds
+    pub delay:     u64,
+    /// the delay varies uniformly by up to this many milliseconds in either direction
+    pub jitter:    u64,
+    /// probability that a packet is lost
+    pub loss:      f64,
+    /// probability that a packet arrives twice
+    pub duplicate: f64,
+    /// probability that a packet skips the delay and overtakes the ones before it
+    pub reorder:   f64,
+}
+
+/// xorshift64*. good enough for a simulation and the same on every platform and rand version
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero, or xorshift only ever returns zero
+        match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => Rng(0x9e37_79b9_7f4a_7c15),
+            state => Rng(state),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// true with probability p
+    fn chance(&mut self, p: f64) -> bool {
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        p > 0.0 && sample < p
+    }
+
+    /// uniformly distributed in 0..=max
+    fn upto(&mut self, max: u64) -> u64 {
+        if max == 0 {
+            0
+        } else {
+            self.next() % (max + 1)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    pub sent:       u64,
+    pub bytes:      u64,
+    pub lost:       u64,
+    pub duplicated: u64,
+    pub delivered:  u64,
+}
+
+struct InFlight {
+    deliver: u64,
+    seq:     u64,
+    pkt:     Vec<u8>,
+}
+
+/// one direction of a simulated network path
+pub struct Link {
+    config:    LinkConfig,
+    rng:       Rng,
+    inflight:  Vec<InFlight>,
+    seq:       u64,
+    pub stats: LinkStats,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: Rng::new(seed),
+            inflight: Vec::new(),
+            seq: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn send(&mut self, now: u64, pkt: Vec<u8>) {
+        self.stats.sent += 1;
+        self.stats.bytes += pkt.len() as u64;
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            let deliver = self.delivery_time(now);
+            self.enqueue(deliver, pkt.clone());
+        }
+        let deliver = self.delivery_time(now);
+        self.enqueue(deliver, pkt);
+    }
+
+    fn delivery_time(&mut self, now: u64) -> u64 {
+        if self.rng.chance(self.config.reorder) {
+            return now;
+        }
+        let jitter = self.rng.upto(2 * self.config.jitter);
+        (now + self.config.delay + jitter).saturating_sub(self.config.jitter)
+    }
+
+    fn enqueue(&mut self, deliver: u64, pkt: Vec<u8>) {
+        self.seq += 1;
+        self.inflight.push(InFlight {
+            deliver,
+            seq: self.seq,
+            pkt,
+        });
+    }
+
+    /// the next packet that arrived by now. packets arriving at the same time keep the order they were sent in
+    pub fn recv(&mut self, now: u64) -> Option<Vec<u8>> {
+        let next = self
+            .inflight
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.deliver <= now)
+            .min_by_key(|(_, p)| (p.deliver, p.seq))
+            .map(|(i, _)| i)?;
+        self.stats.delivered += 1;
+        Some(self.inflight.swap_remove(next).pkt)
+    }
+
+    /// when the next packet arrives
+    pub fn next_delivery(&self) -> Option<u64> {
+        self.inflight.iter().map(|p| p.deliver).min()
+    }
+}
+
+/// one end of the simulated network
+pub struct Peer {
+    pub channel: transport::Channel,
+    /// everything progress returned, except packets to send and timers
+    pub events:  Vec<ChannelProgress>,
+    /// when progress must be called again
+    deadline:    u64,
+    /// the channel returned Disconnect or Timeout and is not progressed any more
+    closed:      bool,
+}
+
+impl Peer {
+    fn new(channel: transport::Channel) -> Self {
+        Self {
+            channel,
+            events: Vec::new(),
+            deadline: 0,
+            closed: false,
+        }
+    }
+
+    /// drive the channel until it waits for a timer
+    fn progress(&mut self, now: u64, link: &mut Link) {
+        while !self.closed {
+            match self.channel.progress() {
+                Ok(ChannelProgress::Later(later)) => {
+                    let later = later.as_secs() * 1000 + later.subsec_millis() as u64;
+                    self.deadline = now + later;
+                    return;
+                }
+                Ok(ChannelProgress::SendPacket(pkt)) => link.send(now, pkt),
+                Ok(event @ ChannelProgress::Disconnect) | Ok(event @ ChannelProgress::Timeout) => {
+                    self.events.push(event);
+                    self.deadline = u64::max_value();
+                    self.closed = true;
+                }
+                Ok(event) => self.events.push(event),
+                Err(e) => panic!("transport progress error: {}", e),
+            }
+        }
+    }
+
+    fn recv(&mut self, pkt: Vec<u8>) {
+        match EncryptedPacket::decode(pkt) {
+            Ok(pkt) => {
+                if let Err(e) = self.channel.recv(pkt) {
+                    trace!("netsim: dropping packet: {}", e);
+                }
+            }
+            Err(e) => trace!("netsim: cannot decode packet: {}", e),
+        }
+    }
+}
+
+/// two channels connected by a simulated link in each direction, sharing one manual clock.
+/// the channels must have been created with a clone of the clock
+pub struct Network {
+    pub clock:     ManualClock,
+    pub initiator: Peer,
+    pub responder: Peer,
+    /// from initiator to responder
+    pub forward:   Link,
+    /// from responder to initiator
+    pub backward:  Link,
+}
+
+impl Network {
+    pub fn new(
+        clock: ManualClock,
+        initiator: transport::Channel,
+        responder: transport::Channel,
+        forward: Link,
+        backward: Link,
+    ) -> Self {
+        Self {
+            clock,
+            initiator: Peer::new(initiator),
+            responder: Peer::new(responder),
+            forward,
+            backward,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// deliver everything due, let both channels make progress, then advance the clock to the next event
+    pub fn step(&mut self) {
+        let now = self.clock.now();
+        while let Some(pkt) = self.forward.recv(now) {
+            self.responder.recv(pkt);
+        }
+        while let Some(pkt) = self.backward.recv(now) {
+            self.initiator.recv(pkt);
+        }
+        self.initiator.progress(now, &mut self.forward);
+        self.responder.progress(now, &mut self.backward);
+
+        let mut next = min(self.initiator.deadline, self.responder.deadline);
+        if let Some(deliver) = self.forward.next_delivery() {
+            next = min(next, deliver);
+        }
+        if let Some(deliver) = self.backward.next_delivery() {
+            next = min(next, deliver);
+        }
+        // always move forward, a timer that is due now has just been handled
+        self.clock.set(next.max(now + 1));
+    }
+
+    /// step until done returns true or the clock passes limit. returns whether done returned true
+    pub fn run_until<F: FnMut(&mut Network) -> bool>(&mut self, limit: u64, mut done: F) -> bool {
+        loop {
+            if done(self) {
+                return true;
+            }
+            if self.clock.now() > limit {
+                return false;
+            }
+            self.step();
+        }
+    }
+}
+
+#[test]
+fn rng_seed() {
+    let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+    assert!((0..10).any(|_| rng.next() != 0), "a seed that cancels out must not leave a zero state");
+}
+
+#[cfg(test)]
+fn netem_link(config: LinkConfig, seed: u64) -> (Link, Vec<(u64, Vec<u8>)>) {
+    let mut link = Link::new(config, seed);
+    for i in 0..1000u16 {
+        link.send(0, vec![(i >> 8) as u8, i as u8]);
+    }
+    let mut arrived = Vec::new();
+    for now in 0..=120 {
+        while let Some(pkt) = link.recv(now) {
+            arrived.push((now, pkt));
+        }
+    }
+    (link, arrived)
+}
+
+#[test]
+fn link_netem() {
+    let config = LinkConfig {
+        delay:     100,
+        jitter:    20,
+        loss:      0.3,
+        duplicate: 0.1,
+        reorder:   0.1,
+    };
+    let (link, arrived) = netem_link(config.clone(), 1);
+    assert!(link.stats.lost > 250 && link.stats.lost < 350, "lost {}", link.stats.lost);
+    assert!(link.stats.duplicated > 50 && link.stats.duplicated < 150);
+    assert_eq!(link.next_delivery(), None);
+    assert_eq!(arrived.len() as u64, 1000 - link.stats.lost + link.stats.duplicated);
+    assert!(arrived.iter().filter(|(now, _)| *now == 0).count() > 0, "some packets are reordered");
+    assert!(arrived.iter().all(|(now, _)| *now == 0 || (*now >= 80 && *now <= 120)));
+
+    // the same seed gives the same link
+    let (again, arrived_again) = netem_link(config.clone(), 1);
+    assert_eq!(again.stats, link.stats);
+    assert_eq!(arrived_again, arrived);
+    let (other, _) = netem_link(config, 2);
+    assert_ne!(other.stats, link.stats);
+}
+
+#[cfg(test)]
+pub fn network(seed: u64, config: LinkConfig) -> Network {
+    use packet;
+    network_offering(seed, config, packet::SUPPORTED_VERSIONS)
+}
+
+/// like network, but the initiator only offers these wire versions
+#[cfg(test)]
+pub fn network_offering(seed: u64, config: LinkConfig, versions: &[u8]) -> Network {
+    use identity::Secret;
+    use noise;
+
+    let clock = ManualClock::new(0);
+    let xsecret = Secret::gen();
+    let (mut requester, pkt) = noise::initiate_offering(
+        Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, versions, &noise::offered_suites(), false,
+    ).unwrap();
+    let (responder, _, _, _) = noise::respond(Some(&xsecret), pkt, None).unwrap();
+    let (r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    requester.recv_response(pkt).unwrap();
+    let i = requester.into_transport().unwrap();
+
+    Network::new(
+        clock.clone(),
+        transport::Channel::with_clock(i, "initiator", Box::new(clock.clone())),
+        transport::Channel::with_clock(r, "responder", Box::new(clock.clone())),
+        Link::new(config.clone(), seed),
+        Link::new(config, seed + 1),
+    )
+}
+
+/// send messages from the initiator to the responder and return when the last one arrived
+#[cfg(test)]
+pub fn transfer(net: &mut Network, messages: usize, size: usize) -> u64 {
+    use headers::Headers;
+
+    let stream = net.initiator.channel.open(&Headers::with_path("/hello"), true).unwrap();
+    let mut sent = 0;
+    let mut received = Vec::new();
+
+    let done = net.run_until(600000, |net| {
+        while sent < messages && net.initiator.channel.writable(stream) {
+            net.initiator.channel.stream(stream, vec![sent as u8; size]).unwrap();
+            sent += 1;
+        }
+        for event in net.responder.events.drain(..) {
+            match event {
+                ChannelProgress::ReceiveStream(s, payload) => {
+                    assert_eq!(s, stream);
+                    received.push(payload);
+                }
+                ChannelProgress::ReceiveHeader(s, headers) => {
+                    assert_eq!(s, stream);
+                    assert_eq!(headers.path(), Some(&b"/hello"[..]));
+                }
+                _ => (),
+            }
+        }
+        received.len() == messages
+    });
+    assert!(done, "only {} of {} messages arrived", received.len(), messages);
+
+    for (i, payload) in received.iter().enumerate() {
+        assert_eq!(payload, &vec![i as u8; size], "messages arrive complete and in order");
+    }
+    net.now()
+}
+
+// the scenarios from the README, without root and without crashing the kernel
+
+#[test]
+fn netem_delay() {
+    let mut net = network(
+        1,
+        LinkConfig {
+            delay: 100,
+            jitter: 20,
+            ..LinkConfig::default()
+        },
+    );
+    let finished = transfer(&mut net, 100, 2000);
+    assert_eq!(net.forward.stats.lost, 0);
+    assert!(finished >= 100 && finished < 10000, "finished at {}", finished);
+}
+
+#[test]
+fn netem_loss() {
+    let mut net = network(
+        2,
+        LinkConfig {
+            loss: 0.3,
+            ..LinkConfig::default()
+        },
+    );
+    transfer(&mut net, 100, 2000);
+    assert!(net.forward.stats.lost > 0);
+}
+
+#[test]
+fn netem_deutsche_bahn_wifi() {
+    let config = LinkConfig {
+        delay:     100,
+        jitter:    300,
+        loss:      0.3,
+        duplicate: 0.01,
+        reorder:   0.05,
+    };
+    let mut net = network(3, config.clone());
+    let finished = transfer(&mut net, 50, 2000);
+
+    // the same seed is the same run
+    let mut again = network(3, config);
+    assert_eq!(transfer(&mut again, 50, 2000), finished);
+    assert_eq!(again.forward.stats, net.forward.stats);
+    assert_eq!(again.backward.stats, net.backward.stats);
+}
@@ -1,11 +1,19 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use certificate::CertificateChain;
 use failure::Error;
 use identity::{Identity, Signature, Secret, Address};
-use packet::{self, RoutingDirection, RoutingKey};
-use snow::{self, params::NoiseParams, Builder};
+use kem;
+use packet::{self, RoutingDirection, RoutingKey, SUPPORTED_VERSIONS};
+use pmtud;
+use recvbuf::RecvBuffer;
+use snow::{self, params::{CipherChoice, NoiseParams}, Builder};
 use snow::resolvers::{FallbackResolver, CryptoResolver};
 use std::io::Write;
 use std::io::Read;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Fail)]
 enum NoiseError {
@@ -36,38 +44,260 @@ enum NoiseError {
 
     #[fail(display = "invalid cookie. probably a replay")]
     InvalidCookie,
+
+    #[fail(display = "rekey for epoch {} but current epoch is {}", epoch, current)]
+    InvalidEpoch { epoch: u16, current: u16 },
+
+    #[fail(display = "no common wire version, peer supports {:?}", theirs)]
+    NoCommonVersion { theirs: Vec<u8> },
+
+    #[fail(display = "packet with version {} but the channel speaks version {}", version, this)]
+    WrongVersion { version: u8, this: u8 },
+
+    #[fail(display = "certificate chain of {} certificates exceeds maximum of {}", count, max)]
+    ChainTooLong { count: usize, max: usize },
+
+    #[fail(display = "certificate of {} bytes is too large for a handshake", len)]
+    CertificateTooLarge { len: usize },
+
+    #[fail(display = "invalid packet counter 0")]
+    InvalidCounter,
+
+    #[fail(display = "no common cipher suite, peer offered {:?}", theirs)]
+    NoCommonSuite { theirs: Vec<u8> },
+
+    #[fail(display = "payload of {} bytes exceeds maximum of {}", len, max)]
+    PayloadTooLarge { len: usize, max: usize },
+
+    #[fail(display = "{} of {} exceeds maximum of {} in a handshake", what, len, max)]
+    HandshakeFieldTooLong { what: &'static str, len: usize, max: usize },
+
+    #[fail(display = "route 0 is reserved for handshakes")]
+    InvalidRoute,
+
+    #[fail(display = "hybrid handshake of {} bytes exceeds the packet size every path carries of {}", size, max)]
+    HybridHandshakeTooBig { size: usize, max: usize },
 }
 
+/// longest certificate chain accepted in a handshake
+pub const MAX_CHAIN_LEN: usize = 16;
+
+/// a symmetric key shared by all peers of a closed deployment.
+/// it is mixed into the handshake in addition to the identities, so a leaked identity alone cannot join
+pub type Psk = [u8; 32];
+
+/// the psk is mixed in at the end of the second handshake message, as in wireguard.
+/// a peer without a psk fails on the first message because the protocol name differs,
+/// a peer with a different psk fails to read the response or anything after it
+const PSK_LOCATION: u8 = 2;
+
+/// the aead protecting transport packets, negotiated in the handshake.
+/// the handshake messages themselves are always protected by ChaChaPoly, which every peer supports
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherSuite {
+    ChaChaPoly,
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    fn id(self) -> u8 {
+        match self {
+            CipherSuite::ChaChaPoly => 0x01,
+            CipherSuite::Aes256Gcm => 0x02,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<CipherSuite> {
+        match id {
+            0x01 => Some(CipherSuite::ChaChaPoly),
+            0x02 => Some(CipherSuite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    fn noise_name(self) -> &'static str {
+        match self {
+            CipherSuite::ChaChaPoly => "ChaChaPoly",
+            CipherSuite::Aes256Gcm => "AESGCM",
+        }
+    }
+
+    /// AES-256-GCM needs the aesgcm feature
+    pub fn available(self) -> bool {
+        match self {
+            CipherSuite::ChaChaPoly => true,
+            CipherSuite::Aes256Gcm => cfg!(feature = "aesgcm"),
+        }
+    }
+}
+
+/// the suites an initiator offers, preferred first.
+/// AES-256-GCM is only preferred with hardware support, without it ChaChaPoly is faster
+pub fn offered_suites() -> Vec<CipherSuite> {
+    let mut suites = Vec::new();
+    if CipherSuite::Aes256Gcm.available() && aes_hardware() {
+        suites.push(CipherSuite::Aes256Gcm);
+    }
+    suites.push(CipherSuite::ChaChaPoly);
+    suites
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn aes_hardware() -> bool {
+    is_x86_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn aes_hardware() -> bool {
+    false
+}
+
+/// every suite this build supports
+pub fn available_suites() -> Vec<CipherSuite> {
+    [CipherSuite::ChaChaPoly, CipherSuite::Aes256Gcm]
+        .iter()
+        .cloned()
+        .filter(|suite| suite.available())
+        .collect()
+}
+
+/// the first of their suites, in their order of preference, that we support
+fn negotiate_suite(ours: &[CipherSuite], theirs: &[CipherSuite]) -> Option<CipherSuite> {
+    theirs.iter().find(|suite| ours.contains(suite)).cloned()
+}
+
+/// what the handshake negotiated, shared with the ciphers of its session
+#[derive(Default)]
+struct Negotiated {
+    /// id of the cipher suite, 0 until the handshake is complete
+    suite:  AtomicUsize,
+    /// the ML-KEM secret of a hybrid handshake, once both sides know it
+    kem:    Mutex<Option<kem::KemSecret>>,
+}
+
+fn noise_params(pattern: &str, suite: CipherSuite) -> NoiseParams {
+    format!("Noise_{}_25519_{}_SHA256", pattern, suite.noise_name()).parse().unwrap()
+}
+
+fn handshake_params(remote_static: bool, psk: bool) -> NoiseParams {
+    let pattern = match (remote_static, psk) {
+        (true, false)   => "NK",
+        (true, true)    => "NKpsk2",
+        (false, false)  => "NN",
+        (false, true)   => "NNpsk2",
+    };
+    noise_params(pattern, CipherSuite::ChaChaPoly)
+}
+
+/// how the encrypted payload of transport packets is padded.
+/// handshake packets are always padded to buckets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Padding {
+    /// packets are only as large as their content
+    None,
+    /// the encrypted payload is padded to a multiple of 256 bytes, hiding the exact size of messages
+    Buckets,
+}
+
+/// received payloads are sliced out of one buffer of this size.
+/// a payload that is kept around keeps the whole buffer alive
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// give up on a rekey request that was not answered after this many milliseconds
+const REKEY_TIMEOUT: u64 = 30000;
+
 pub struct Transport {
     counter:   u64,
     noise:     snow::Session,
     route:     RoutingKey,
     direction: RoutingDirection,
+
+    epoch:          u16,
+    rekey:          Option<Rekey>,
+    previous:       Option<snow::Session>,
+    peer_switched:  bool,
+
+    recvbuf:        RecvBuffer,
+    padding:        Padding,
+    version:        u8,
+    suite:          CipherSuite,
+    hybrid:         bool,
+    /// what the channel handshake negotiated. rekeys are mixed with its kem secret as well
+    negotiated:     Arc<Negotiated>,
+}
+
+/// a rekey in progress for the next epoch.
+/// the new keys are negotiated with a Noise_NN handshake with the negotiated cipher suite,
+/// carried in Rekey frames, which are authenticated by the current epoch.
+/// after a hybrid handshake, every key it sets is mixed with the kem secret,
+/// so later epochs are as hard to break as the first.
+enum Rekey {
+    /// we sent the request at the given time and wait for the response
+    Requested {
+        noise: snow::Session,
+        sent:  u64,
+    },
+    /// we responded. the new keys are used for receiving as soon as the peer switched to them,
+    /// and for sending after that.
+    Responded {
+        noise:   snow::Session,
+        request: Vec<u8>,
+        message: Vec<u8>,
+    },
 }
 
 pub struct HandshakeRequester {
     noise:      snow::Session,
     timestamp:  u64,
     route:      Option<RoutingKey>,
+    version:    Option<u8>,
+    offered:    Vec<CipherSuite>,
+    negotiated: Arc<Negotiated>,
+    kem:        Option<kem::DecapsulationKey>,
 }
 
 pub struct HandshakeResponder {
     noise:      snow::Session,
     timestamp:  u64,
+    version:    u8,
+    chosen:     CipherSuite,
+    negotiated: Arc<Negotiated>,
+    /// ML-KEM ciphertext for a hybrid initiator
+    kem:        Option<Vec<u8>>,
+}
+
+/// what a handshake message carries besides the keys
+struct HandshakePayload {
+    identity:   Identity,
+    timestamp:  u64,
+    chain:      CertificateChain,
+    versions:   Vec<u8>,
+    suites:     Vec<u8>,
+    kem:        Vec<u8>,
 }
 
 enum SendMode<'a> {
     Transport {
         counter: u64,
         payload: &'a [u8],
+        padding: Padding,
+        version: u8,
     },
     InsecureHandshake{
         identity:   Identity,
         timestamp:  u64,
+        chain:      &'a CertificateChain,
+        versions:   &'a [u8],
+        suites:     &'a [CipherSuite],
+        kem:        &'a [u8],
     },
     Handshake{
         identity:   Identity,
         timestamp:  u64,
+        chain:      &'a CertificateChain,
+        versions:   &'a [u8],
+        suites:     &'a [CipherSuite],
+        kem:        &'a [u8],
     },
 }
 
@@ -82,11 +312,21 @@ fn send(
     } else {
         None
     };
+    let (padding, version) = if let &SendMode::Transport { padding, version, .. } = &payload {
+        (padding, version)
+    } else {
+        (Padding::Buckets, packet::HANDSHAKE_VERSION)
+    };
 
     let mut inbuf = Vec::new();
     let overhead = match payload {
         SendMode::Transport { payload, .. } => {
-            assert!(payload.len() + 100 < u16::max_value() as usize);
+            if payload.len() + 100 >= u16::max_value() as usize {
+                return Err(NoiseError::PayloadTooLarge {
+                    len: payload.len(),
+                    max: u16::max_value() as usize - 101,
+                }.into());
+            }
             inbuf.write_u16::<BigEndian>(payload.len() as u16)?;
             inbuf.extend_from_slice(payload);
             16
@@ -94,10 +334,18 @@ fn send(
         SendMode::InsecureHandshake{
             identity,
             timestamp,
+            chain,
+            versions,
+            suites,
+            kem,
         } => {
             assert_eq!(identity.as_bytes().len(), 32);
             inbuf.write_all(&identity.as_bytes())?;
             inbuf.write_u64::<BigEndian>(timestamp)?;
+            write_chain(&mut inbuf, chain)?;
+            write_versions(&mut inbuf, versions)?;
+            write_suites(&mut inbuf, suites)?;
+            write_kem(&mut inbuf, kem)?;
 
               32 // ephermal
             + 64 // signature
@@ -105,10 +353,18 @@ fn send(
         SendMode::Handshake{
             identity,
             timestamp,
+            chain,
+            versions,
+            suites,
+            kem,
         } => {
             assert_eq!(identity.as_bytes().len(), 32);
             inbuf.write_all(&identity.as_bytes())?;
             inbuf.write_u64::<BigEndian>(timestamp)?;
+            write_chain(&mut inbuf, chain)?;
+            write_versions(&mut inbuf, versions)?;
+            write_suites(&mut inbuf, suites)?;
+            write_kem(&mut inbuf, kem)?;
 
               16 // tag
             + 32 // ephermal
@@ -116,8 +372,10 @@ fn send(
         }
     };
 
-    let padding = 256 - ((inbuf.len() + overhead) % 256);
-    inbuf.extend_from_slice(vec![0u8; padding].as_ref());
+    if padding == Padding::Buckets {
+        let padding = 256 - ((inbuf.len() + overhead) % 256);
+        inbuf.extend_from_slice(vec![0u8; padding].as_ref());
+    }
 
     let mut buf = vec![0; inbuf.len() + overhead];
 
@@ -134,16 +392,70 @@ fn send(
     }
 
     let pkt = packet::EncryptedPacket {
-        version: 0x08,
+        version,
         route,
         direction,
         counter,
-        payload: buf,
+        payload: buf.into(),
     };
 
     Ok(pkt)
 }
 
+fn write_chain(w: &mut Vec<u8>, chain: &CertificateChain) -> Result<(), Error> {
+    if chain.len() > MAX_CHAIN_LEN {
+        return Err(NoiseError::ChainTooLong {
+            count: chain.len(),
+            max:   MAX_CHAIN_LEN,
+        }.into());
+    }
+    w.write_u16::<BigEndian>(chain.len() as u16)?;
+    for crt in chain {
+        if crt.len() > u16::max_value() as usize {
+            return Err(NoiseError::CertificateTooLarge { len: crt.len() }.into());
+        }
+        w.write_u16::<BigEndian>(crt.len() as u16)?;
+        w.write_all(crt)?;
+    }
+    Ok(())
+}
+
+/// the wire versions follow the certificate chain.
+/// peers from before version negotiation read them as padding
+fn write_versions(w: &mut Vec<u8>, versions: &[u8]) -> Result<(), Error> {
+    check_field_len("versions", versions.len(), u8::max_value() as usize)?;
+    w.write_u8(versions.len() as u8)?;
+    w.write_all(versions)?;
+    Ok(())
+}
+
+/// the cipher suites follow the wire versions.
+/// peers from before suite negotiation read them as padding, and only speak ChaChaPoly
+fn write_suites(w: &mut Vec<u8>, suites: &[CipherSuite]) -> Result<(), Error> {
+    check_field_len("cipher suites", suites.len(), u8::max_value() as usize)?;
+    w.write_u8(suites.len() as u8)?;
+    for suite in suites {
+        w.write_u8(suite.id())?;
+    }
+    Ok(())
+}
+
+/// the ML-KEM encapsulation key of a hybrid initiator follows the suites, everyone else sends none
+fn write_kem(w: &mut Vec<u8>, public: &[u8]) -> Result<(), Error> {
+    check_field_len("kem key bytes", public.len(), u16::max_value() as usize)?;
+    w.write_u16::<BigEndian>(public.len() as u16)?;
+    w.write_all(public)?;
+    Ok(())
+}
+
+/// handshake fields carry their length in a u8 or u16
+fn check_field_len(what: &'static str, len: usize, max: usize) -> Result<(), Error> {
+    if len > max {
+        return Err(NoiseError::HandshakeFieldTooLong { what, len, max }.into());
+    }
+    Ok(())
+}
+
 impl Transport {
     pub fn send(&mut self, payload: &[u8]) -> Result<packet::EncryptedPacket, Error> {
         self.counter += 1;
@@ -154,13 +466,42 @@ impl Transport {
             SendMode::Transport {
                 counter: self.counter,
                 payload,
+                padding: self.padding,
+                version: self.version,
             },
         )?;
-        assert_eq!(pkt.payload.len() % 256, 0);
+        if self.padding == Padding::Buckets && pkt.payload.len() % 256 != 0 {
+            return Err(NoiseError::PktMisaligned { len: pkt.payload.len() }.into());
+        }
         Ok(pkt)
     }
 
-    pub fn recv(&mut self, pkt: packet::EncryptedPacket) -> Result<Vec<u8>, Error> {
+    /// change how packets sent from now on are padded. the peer accepts any padding
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// the wire version negotiated in the handshake
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// the cipher suite negotiated in the handshake
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// whether the keys were also exchanged with ML-KEM
+    pub fn hybrid(&self) -> bool {
+        self.hybrid
+    }
+
+    /// the returned payload is a slice of the receive buffer, it is not copied again after decryption
+    pub fn recv(&mut self, pkt: packet::EncryptedPacket) -> Result<Bytes, Error> {
         if pkt.route != self.route {
             return Err(NoiseError::WrongRoute {
                 dest: pkt.route,
@@ -172,22 +513,45 @@ impl Transport {
             return Err(NoiseError::WrongDirection { dir: pkt.direction }.into());
         }
 
-        let mut outbuf = vec![0; pkt.payload.len()];
-        let len = self
-            .noise
-            .read_message_with_nonce(pkt.counter - 1, &pkt.payload, &mut outbuf)?;
-        outbuf.truncate(len);
+        if pkt.version != self.version {
+            return Err(NoiseError::WrongVersion {
+                version: pkt.version,
+                this:    self.version,
+            }.into());
+        }
+
+        // counters start at 1, the nonce is one less
+        if pkt.counter == 0 {
+            return Err(NoiseError::InvalidCounter.into());
+        }
+
+        let mut recvbuf = mem::replace(&mut self.recvbuf, RecvBuffer::new(RECV_BUFFER_SIZE));
+        let decrypted = self.decrypt(pkt.counter - 1, &pkt.payload, recvbuf.reserve(pkt.payload.len()));
+        let plaintext = recvbuf.take(*decrypted.as_ref().unwrap_or(&0));
+        self.recvbuf = recvbuf;
+        let len = decrypted?;
 
         if len < 2 {
             return Err(NoiseError::TooSmall { need: 2, got:  len }.into());
         }
-        let len = (&outbuf[..]).read_u16::<BigEndian>()? as usize;
-        let mut payload = outbuf.split_off(2);
-        if len > payload.len() {
+        let size = (&plaintext[..]).read_u16::<BigEndian>()? as usize;
+        if size > len - 2 {
             return Err(NoiseError::DecryptedInvalidPayloadLen.into());
         }
-        payload.truncate(len);
-        Ok(payload)
+        Ok(plaintext.slice(2, 2 + size))
+    }
+
+    fn decrypt(&mut self, nonce: u64, payload: &[u8], outbuf: &mut [u8]) -> Result<usize, Error> {
+        match self.noise.read_message_with_nonce(nonce, payload, outbuf) {
+            Ok(len) => {
+                self.peer_switched = true;
+                Ok(len)
+            }
+            Err(e) => match self.recv_other_epoch(nonce, payload, outbuf) {
+                Some(len) => Ok(len),
+                None => Err(e.into()),
+            },
+        }
     }
 
     pub fn is_initiator(&self) -> bool {
@@ -197,14 +561,188 @@ impl Transport {
     pub fn route(&self) -> RoutingKey {
         self.route
     }
+
+    /// the last used packet counter
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    /// current key epoch, starting at 0 with the keys from the handshake
+    pub fn epoch(&self) -> u16 {
+        self.epoch
+    }
+
+    /// start negotiating keys for the next epoch.
+    /// returns the payload of a Rekey request frame, or None if a rekey is already in progress
+    pub fn rekey_request(&mut self, now: u64) -> Result<Option<(u16, Vec<u8>)>, Error> {
+        match self.rekey {
+            // the peer may have failed to respond, or its response did not match our request.
+            // either way it won't be answered anymore, so start over with a new one
+            Some(Rekey::Requested { sent, .. }) if now >= sent + REKEY_TIMEOUT => (),
+            Some(_) => return Ok(None),
+            None => (),
+        }
+        let epoch = self.epoch.wrapping_add(1);
+        let mut noise = new_handshake_builder(noise_params("NN", self.suite), &self.negotiated)
+            .prologue(&rekey_prologue(epoch))
+            .build_initiator()?;
+
+        let mut message = vec![0; 1024];
+        let len = noise.write_message(&[], &mut message)?;
+        message.truncate(len);
+
+        self.rekey = Some(Rekey::Requested { noise, sent: now });
+        Ok(Some((epoch, message)))
+    }
+
+    /// handle a Rekey frame from the peer.
+    /// returns the payload of a Rekey response frame, if one needs to be sent
+    pub fn rekey_recv(&mut self, epoch: u16, response: bool, message: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if epoch != self.epoch.wrapping_add(1) {
+            if epoch == self.epoch {
+                // retransmission of a rekey we already completed
+                return Ok(None);
+            }
+            return Err(NoiseError::InvalidEpoch {
+                epoch,
+                current: self.epoch,
+            }.into());
+        }
+
+        let rekey = mem::replace(&mut self.rekey, None);
+        match (rekey, response) {
+            (Some(Rekey::Requested { mut noise, .. }), true) => {
+                let mut outbuf = vec![0; message.len()];
+                noise.read_message(message, &mut outbuf)?;
+                let noise = noise.into_stateless_transport_mode()?;
+
+                // we're switching our sending key now.
+                // the peer already accepts the new key and will follow once it sees us using it
+                let previous = mem::replace(&mut self.noise, noise);
+                self.previous = Some(previous);
+                self.epoch = epoch;
+                self.peer_switched = false;
+                Ok(None)
+            }
+            (Some(Rekey::Requested { noise, sent }), false) => {
+                if self.is_initiator() {
+                    // both sides requested a rekey at the same time. the initiator's request wins
+                    self.rekey = Some(Rekey::Requested { noise, sent });
+                    return Ok(None);
+                }
+                self.rekey_respond(epoch, message)
+            }
+            (Some(Rekey::Responded { noise, request, message: response }), false) => {
+                if request != message {
+                    // the peer gave up on its previous request and sent a new one
+                    return self.rekey_respond(epoch, message);
+                }
+                // retransmission of a request we already responded to
+                self.rekey = Some(Rekey::Responded {
+                    noise,
+                    request,
+                    message: response.clone(),
+                });
+                Ok(Some(response))
+            }
+            (rekey, true) => {
+                // response to a request we didn't send, or already superseded
+                self.rekey = rekey;
+                Ok(None)
+            }
+            (None, false) => self.rekey_respond(epoch, message),
+        }
+    }
+
+    fn rekey_respond(&mut self, epoch: u16, message: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut noise = new_handshake_builder(noise_params("NN", self.suite), &self.negotiated)
+            .prologue(&rekey_prologue(epoch))
+            .build_responder()?;
+
+        let mut outbuf = vec![0; message.len()];
+        noise.read_message(message, &mut outbuf)?;
+
+        let mut response = vec![0; 1024];
+        let len = noise.write_message(&[], &mut response)?;
+        response.truncate(len);
+
+        let noise = noise.into_stateless_transport_mode()?;
+        self.rekey = Some(Rekey::Responded {
+            noise,
+            request: message.to_vec(),
+            message: response.clone(),
+        });
+        Ok(Some(response))
+    }
+
+    /// forget the keys of the previous epoch, if the peer is known to have switched.
+    /// returns true if there are no previous keys left
+    pub fn expire_previous(&mut self) -> bool {
+        if self.peer_switched {
+            self.previous = None;
+        }
+        self.previous.is_none()
+    }
+
+    fn recv_other_epoch(&mut self, nonce: u64, payload: &[u8], outbuf: &mut [u8]) -> Option<usize> {
+        let next = if let Some(Rekey::Responded { ref mut noise, .. }) = self.rekey {
+            noise.read_message_with_nonce(nonce, payload, outbuf).ok()
+        } else {
+            None
+        };
+
+        if let Some(len) = next {
+            // the peer switched to the keys we responded with, so we switch too
+            if let Some(Rekey::Responded { noise, .. }) = mem::replace(&mut self.rekey, None) {
+                let previous = mem::replace(&mut self.noise, noise);
+                self.previous = Some(previous);
+                self.epoch = self.epoch.wrapping_add(1);
+                self.peer_switched = true;
+            }
+            return Some(len);
+        }
+
+        if let Some(ref mut previous) = self.previous {
+            return previous.read_message_with_nonce(nonce, payload, outbuf).ok();
+        }
+
+        None
+    }
+}
+
+/// handshakes are not fragmented, and the key and ciphertext of ML-KEM already fill a packet of the base size.
+/// anything larger, like a certificate chain on top, would be dropped on paths that only carry the base size
+fn check_hybrid_size(pkt: &packet::EncryptedPacket) -> Result<(), Error> {
+    let size = packet::HEADER_SIZE + pkt.payload.len();
+    if size > pmtud::BASE_PACKET_SIZE {
+        return Err(NoiseError::HybridHandshakeTooBig { size, max: pmtud::BASE_PACKET_SIZE }.into());
+    }
+    Ok(())
+}
+
+fn rekey_prologue(epoch: u16) -> Vec<u8> {
+    let mut prologue = b"carrier rekey ".to_vec();
+    prologue.write_u16::<BigEndian>(epoch).unwrap();
+    prologue
 }
 
 impl HandshakeResponder {
+    /// whether the initiator asked for a hybrid handshake.
+    /// a responder that requires one can check this before responding
+    pub fn hybrid(&self) -> bool {
+        self.kem.is_some()
+    }
+
+    /// the chain is sent to the initiator along with our identity
     pub fn send_response(
         mut self,
         route:      RoutingKey,
         secret:     &Secret,
+        chain:      &CertificateChain,
     ) -> Result<(Transport, packet::EncryptedPacket), Error> {
+        if route == 0 {
+            return Err(NoiseError::InvalidRoute.into());
+        }
         let mut pkt = send(
             &mut self.noise,
             route,
@@ -212,13 +750,26 @@ impl HandshakeResponder {
             SendMode::Handshake{
                 timestamp:      self.timestamp,
                 identity:       secret.identity(),
+                chain:          chain,
+                versions:       &[self.version],
+                suites:         &[self.chosen],
+                kem:            &[],
             },
         )?;
         let signature = secret.sign(b"carrier handshake hash 1", self.noise.get_handshake_hash()?);
         pkt.payload.extend_from_slice(&signature.as_bytes());
-        assert_eq!(pkt.payload.len() % 256, 0);
-        assert_eq!(pkt.payload.len() % 256, 0);
-        assert_ne!(route, 0);
+
+        // the initiator needs the ciphertext before it can read the rest, so it goes unencrypted at the end
+        if let Some(ref ciphertext) = self.kem {
+            pkt.payload.extend_from_slice(ciphertext);
+            check_hybrid_size(&pkt)?;
+        }
+        if pkt.payload.len() % 256 != 0 {
+            return Err(NoiseError::PktMisaligned { len: pkt.payload.len() }.into());
+        }
+
+        // the handshake is complete, everything from here on uses the negotiated suite
+        self.negotiated.suite.store(self.chosen.id() as usize, Ordering::SeqCst);
 
         Ok((
             Transport {
@@ -226,18 +777,33 @@ impl HandshakeResponder {
                 noise:     self.noise.into_stateless_transport_mode()?,
                 route:     route,
                 direction: RoutingDirection::Responder2Initiator,
+
+                epoch:          0,
+                rekey:          None,
+                previous:       None,
+                peer_switched:  false,
+
+                recvbuf:        RecvBuffer::new(RECV_BUFFER_SIZE),
+                padding:        Padding::Buckets,
+                version:        self.version,
+                suite:          self.chosen,
+                hybrid:         self.kem.is_some(),
+                negotiated:     self.negotiated,
             },
             pkt,
         ))
     }
 }
 
-fn recv_handshake(noise: &mut snow::Session, pkt: packet::EncryptedPacket)
-    -> Result<(Identity, u64), Error>
-{
+/// peers from before version negotiation only speak the handshake version,
+/// peers from before suite negotiation send no suites, and only hybrid initiators send a kem key
+fn recv_handshake(noise: &mut snow::Session, pkt: packet::EncryptedPacket) -> Result<HandshakePayload, Error> {
     if pkt.payload.len() % 256 != 0 {
         return Err(NoiseError::PktMisaligned { len: pkt.payload.len() }.into());
     }
+    if pkt.payload.len() == 0 {
+        return Err(NoiseError::TooSmall { need: 256, got: 0 }.into());
+    }
 
     let mut signature = [0; 64];
     signature.copy_from_slice(&pkt.payload[pkt.payload.len() - 64..pkt.payload.len()]);
@@ -253,39 +819,103 @@ fn recv_handshake(noise: &mut snow::Session, pkt: packet::EncryptedPacket)
     let identity = Identity::from_bytes(&outbuf[0..32])?;
     let timestamp = (&outbuf[32..40]).read_u64::<BigEndian>()?;
 
-    let mut reader = &outbuf[40..];
+    let mut reader = &outbuf[40..len];
 
     let mut chain = Vec::new();
-    let numcerts = reader.read_u16::<BigEndian>()?;
+    let numcerts = reader.read_u16::<BigEndian>()? as usize;
+    if numcerts > MAX_CHAIN_LEN {
+        return Err(NoiseError::ChainTooLong {
+            count: numcerts,
+            max:   MAX_CHAIN_LEN,
+        }.into());
+    }
     for _ in 0..numcerts {
-        let len = reader.read_u16::<BigEndian>()?;
-        let mut crt = vec![0;len as usize];
-        reader.read_exact(&mut crt)?;
-        chain.push(crt);
+        let len = reader.read_u16::<BigEndian>()? as usize;
+        if len > reader.len() {
+            return Err(NoiseError::TooSmall { need: len, got: reader.len() }.into());
+        }
+        chain.push(reader[..len].to_vec());
+        reader = &reader[len..];
     }
 
+    let mut versions = vec![0; reader.read_u8()? as usize];
+    reader.read_exact(&mut versions)?;
+    if versions.is_empty() {
+        versions.push(packet::HANDSHAKE_VERSION);
+    }
+
+    let mut suites = vec![0; reader.read_u8()? as usize];
+    reader.read_exact(&mut suites)?;
+
+    let mut kem = vec![0; reader.read_u16::<BigEndian>()? as usize];
+    reader.read_exact(&mut kem)?;
+
     identity.verify(
         b"carrier handshake hash 1",
         noise.get_handshake_hash()?,
         &signature,
     )?;
 
-    Ok((identity, timestamp))
+    Ok(HandshakePayload {
+        identity,
+        timestamp,
+        chain,
+        versions,
+        suites,
+        kem,
+    })
 }
 
 impl HandshakeRequester {
-    pub fn recv_response(&mut self, pkt: packet::EncryptedPacket) -> Result<Identity,Error> {
+    /// returns the identity of the responder and the certificate chain it presented.
+    /// the chain is not verified, that is up to the caller
+    pub fn recv_response(&mut self, mut pkt: packet::EncryptedPacket) -> Result<(Identity, CertificateChain), Error> {
 
         let route = pkt.route;
-        let (identity, timestamp) = recv_handshake(&mut self.noise, pkt)?;
+        if let Some(ref key) = self.kem {
+            let len = pkt.payload.len();
+            if len < kem::CIPHERTEXT_SIZE {
+                return Err(NoiseError::TooSmall { need: kem::CIPHERTEXT_SIZE, got: len }.into());
+            }
+            let secret = kem::decapsulate(key, &pkt.payload[len - kem::CIPHERTEXT_SIZE..])?;
+            *self.negotiated.kem.lock().unwrap() = Some(secret);
+            pkt.payload = pkt.payload.slice_to(len - kem::CIPHERTEXT_SIZE);
+        }
+        // reading the response needs the secret already, but a response that does not verify must not leave it behind
+        let payload = match recv_handshake(&mut self.noise, pkt) {
+            Ok(payload) => payload,
+            Err(e) => {
+                *self.negotiated.kem.lock().unwrap() = None;
+                return Err(e);
+            }
+        };
 
-        if timestamp != self.timestamp {
+        if payload.timestamp != self.timestamp {
             return Err(NoiseError::InvalidCookie.into());
         }
 
+        // the responder picks exactly one of the versions we offered
+        let versions = payload.versions;
+        if versions.len() != 1 || !SUPPORTED_VERSIONS.contains(&versions[0]) {
+            return Err(NoiseError::NoCommonVersion { theirs: versions }.into());
+        }
+
+        // and one of the suites, unless it predates suite negotiation
+        let suite = match payload.suites.len() {
+            0 => Some(CipherSuite::ChaChaPoly),
+            1 => CipherSuite::from_id(payload.suites[0]),
+            _ => None,
+        };
+        let suite = match suite {
+            Some(suite) if self.offered.contains(&suite) => suite,
+            _ => return Err(NoiseError::NoCommonSuite { theirs: payload.suites }.into()),
+        };
+
         self.route = Some(route);
+        self.version = Some(versions[0]);
+        self.negotiated.suite.store(suite.id() as usize, Ordering::SeqCst);
 
-        Ok(identity)
+        Ok((payload.identity, payload.chain))
     }
 
     pub fn into_transport(self) -> Result<Transport, Error> {
@@ -296,30 +926,76 @@ impl HandshakeRequester {
                 .route
                 .expect("into_transport can only be called after recv_response"),
             direction: RoutingDirection::Initiator2Responder,
+
+            epoch:          0,
+            rekey:          None,
+            previous:       None,
+            peer_switched:  false,
+
+            recvbuf:        RecvBuffer::new(RECV_BUFFER_SIZE),
+            padding:        Padding::Buckets,
+            version:        self.version.expect("into_transport can only be called after recv_response"),
+            suite:          CipherSuite::from_id(self.negotiated.suite.load(Ordering::SeqCst) as u8)
+                .expect("into_transport can only be called after recv_response"),
+            hybrid:         self.kem.is_some(),
+            negotiated:     self.negotiated,
         })
     }
 }
 
+/// the chain is sent to the responder along with our identity.
+/// with a psk, the responder must use the same psk
 pub fn initiate(
     remote_static:  Option<&Address>,
     secret:         &Secret,
     timestamp:      u64,
+    chain:          &CertificateChain,
+    psk:            Option<&Psk>,
+) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
+    initiate_offering(remote_static, secret, timestamp, chain, psk, SUPPORTED_VERSIONS, &offered_suites(), false)
+}
+
+/// like initiate, but the keys are also exchanged with ML-KEM, so that recorded traffic
+/// cannot be decrypted by breaking X25519 alone. needs the mlkem feature on both sides
+pub fn initiate_hybrid(
+    remote_static:  Option<&Address>,
+    secret:         &Secret,
+    timestamp:      u64,
+    chain:          &CertificateChain,
+    psk:            Option<&Psk>,
 ) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
-    let mut noise = if let Some(remote_static) = remote_static {
-        let params: NoiseParams = "Noise_NK_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
-            .remote_public_key(remote_static.as_bytes())
-            .prologue("carrier has arrived".as_bytes())
-            .build_initiator()
-            .expect("building noise session")
+    initiate_offering(remote_static, secret, timestamp, chain, psk, SUPPORTED_VERSIONS, &offered_suites(), true)
+}
+
+/// initiate with a choice of versions, cipher suites and key exchange
+pub(crate) fn initiate_offering(
+    remote_static:  Option<&Address>,
+    secret:         &Secret,
+    timestamp:      u64,
+    chain:          &CertificateChain,
+    psk:            Option<&Psk>,
+    versions:       &[u8],
+    suites:         &[CipherSuite],
+    hybrid:         bool,
+) -> Result<(HandshakeRequester, packet::EncryptedPacket), Error> {
+    let (kem, public) = if hybrid {
+        let (key, public) = kem::keypair()?;
+        (Some(key), public)
     } else {
-        let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
-            .prologue("carrier has arrived".as_bytes())
-            .build_initiator()
-            .expect("building noise session")
+        (None, Vec::new())
     };
 
+    let negotiated = Arc::new(Negotiated::default());
+    let mut builder = new_handshake_builder(handshake_params(remote_static.is_some(), psk.is_some()), &negotiated)
+        .prologue("carrier has arrived".as_bytes());
+    if let Some(remote_static) = remote_static {
+        builder = builder.remote_public_key(remote_static.as_bytes());
+    }
+    if let Some(psk) = psk {
+        builder = builder.psk(PSK_LOCATION, psk);
+    }
+    let mut noise = builder.build_initiator().expect("building noise session");
+
     let identity = secret.identity();
 
     let mut pkt = send(
@@ -330,57 +1006,112 @@ pub fn initiate(
             SendMode::Handshake{
                 identity,
                 timestamp,
+                chain,
+                versions,
+                suites,
+                kem:    &public,
             }
         } else {
             SendMode::InsecureHandshake{
                 identity,
                 timestamp,
+                chain,
+                versions,
+                suites,
+                kem:    &public,
             }
         }
     )?;
 
     let signature = secret.sign(b"carrier handshake hash 1", noise.get_handshake_hash()?);
     pkt.payload.extend_from_slice(&signature.as_bytes());
-    assert_eq!(pkt.payload.len() % 256, 0);
+    if pkt.payload.len() % 256 != 0 {
+        return Err(NoiseError::PktMisaligned { len: pkt.payload.len() }.into());
+    }
+    if hybrid {
+        check_hybrid_size(&pkt)?;
+    }
 
     let s = HandshakeRequester {
         timestamp,
-        noise:  noise,
-        route:  None,
+        noise:      noise,
+        route:      None,
+        version:    None,
+        offered:    suites.to_vec(),
+        negotiated,
+        kem,
     };
 
     Ok((s, pkt))
 }
 
+/// returns the identity, timestamp and certificate chain of the initiator.
+/// the chain is not verified, so it can be checked with an Authenticator before responding.
+/// with a psk, the initiator must have used the same psk
 pub fn respond(
     xsecret:    Option<&Secret>,
     pkt:        packet::EncryptedPacket,
-) -> Result<(HandshakeResponder, Identity, u64), Error> {
-
-    let mut noise = if let Some(xsecret) = xsecret{
-        let params: NoiseParams = "Noise_NK_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
-            .local_private_key(xsecret.as_bytes())
-            .prologue("carrier has arrived".as_bytes())
-            .build_responder()
-            .expect("building noise session")
+    psk:        Option<&Psk>,
+) -> Result<(HandshakeResponder, Identity, u64, CertificateChain), Error> {
+    respond_supporting(xsecret, pkt, psk, SUPPORTED_VERSIONS, &available_suites())
+}
+
+fn respond_supporting(
+    xsecret:    Option<&Secret>,
+    pkt:        packet::EncryptedPacket,
+    psk:        Option<&Psk>,
+    versions:   &[u8],
+    suites:     &[CipherSuite],
+) -> Result<(HandshakeResponder, Identity, u64, CertificateChain), Error> {
+
+    let negotiated = Arc::new(Negotiated::default());
+    let mut builder = new_handshake_builder(handshake_params(xsecret.is_some(), psk.is_some()), &negotiated)
+        .prologue("carrier has arrived".as_bytes());
+    if let Some(xsecret) = xsecret {
+        builder = builder.local_private_key(xsecret.as_bytes());
+    }
+    if let Some(psk) = psk {
+        builder = builder.psk(PSK_LOCATION, psk);
+    }
+    let mut noise = builder.build_responder().expect("building noise session");
+
+    let payload = recv_handshake(&mut noise, pkt)?;
+    let version = match packet::negotiate_version(versions, &payload.versions) {
+        Some(version) => version,
+        None => return Err(NoiseError::NoCommonVersion { theirs: payload.versions }.into()),
+    };
+
+    let theirs: Vec<CipherSuite> = if payload.suites.is_empty() {
+        vec![CipherSuite::ChaChaPoly]
     } else {
-        let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
-        new_noise_builder(params)
-            .prologue("carrier has arrived".as_bytes())
-            .build_responder()
-            .expect("building noise session")
+        payload.suites.iter().filter_map(|id| CipherSuite::from_id(*id)).collect()
+    };
+    let chosen = match negotiate_suite(suites, &theirs) {
+        Some(suite) => suite,
+        None => return Err(NoiseError::NoCommonSuite { theirs: payload.suites }.into()),
     };
 
-    let (identity, timestamp) = recv_handshake(&mut noise, pkt)?;
+    // every key set from here on is mixed with the kem secret, starting with the response
+    let kem = if payload.kem.is_empty() {
+        None
+    } else {
+        let (ciphertext, secret) = kem::encapsulate(&payload.kem)?;
+        *negotiated.kem.lock().unwrap() = Some(secret);
+        Some(ciphertext)
+    };
 
     Ok((
         HandshakeResponder {
             noise,
-            timestamp,
+            timestamp: payload.timestamp,
+            version,
+            chosen,
+            negotiated,
+            kem,
         },
-        identity,
-        timestamp,
+        payload.identity,
+        payload.timestamp,
+        payload.chain,
     ))
 }
 
@@ -434,12 +1165,444 @@ impl CryptoResolver for RandResolver {
     }
 }
 
-fn new_noise_builder<'builder>(params: NoiseParams) -> Builder<'builder> {
-    Builder::with_resolver(params, Box::new(
-            FallbackResolver::new(
-                Box::new(snow::resolvers::HaclStarResolver::default()),
-                Box::new(RandResolver::default()),
-                )))
+/// the handshake is always protected by ChaChaPoly, because the responder cannot know the negotiated suite
+/// before reading the first message. the transport keys split off the handshake are set on both ciphers,
+/// and the negotiated one takes over once the handshake is complete and the suite is stored.
+/// no key is ever used with both ciphers.
+///
+/// in a hybrid handshake, both sides know the kem secret before the second message,
+/// so every key set while processing it is mixed with the secret, including the transport keys.
+/// rekeys of a hybrid channel are mixed with the same secret
+struct NegotiatedCipher {
+    negotiated: Arc<Negotiated>,
+    chacha:     Box<snow::types::Cipher>,
+    aes:        Option<Box<snow::types::Cipher>>,
+}
+
+impl NegotiatedCipher {
+    fn current(&self) -> &snow::types::Cipher {
+        let suite = self.negotiated.suite.load(Ordering::SeqCst);
+        match self.aes {
+            Some(ref aes) if suite == CipherSuite::Aes256Gcm.id() as usize => &**aes,
+            _ => &*self.chacha,
+        }
+    }
+}
+
+impl snow::types::Cipher for NegotiatedCipher {
+    fn name(&self) -> &'static str {
+        self.chacha.name()
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        let mixed = self.negotiated.kem.lock().unwrap().map(|secret| kem::mix(key, &secret));
+        let key = match mixed {
+            Some(ref mixed) => &mixed[..],
+            None => key,
+        };
+        self.chacha.set(key);
+        if let Some(ref mut aes) = self.aes {
+            aes.set(key);
+        }
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        self.current().encrypt(nonce, authtext, plaintext, out)
+    }
+
+    fn decrypt(&self, nonce: u64, authtext: &[u8], ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+        self.current().decrypt(nonce, authtext, ciphertext, out)
+    }
+}
+
+struct NegotiatedResolver {
+    negotiated: Arc<Negotiated>,
+    inner:      Box<CryptoResolver>,
+}
+
+impl CryptoResolver for NegotiatedResolver {
+    fn resolve_rng(&self) -> Option<Box<snow::types::Random>> {
+        self.inner.resolve_rng()
+    }
+
+    fn resolve_dh (&self, choice: &snow::params::DHChoice)
+        -> Option<Box<(dyn snow::types::Dh + 'static)>>
+    {
+        self.inner.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &snow::params::HashChoice)
+        -> Option<Box<(dyn snow::types::Hash + 'static)>>
+    {
+        self.inner.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice)
+        -> Option<Box<(dyn snow::types::Cipher + 'static)>>
+    {
+        Some(Box::new(NegotiatedCipher {
+            negotiated: self.negotiated.clone(),
+            chacha:     self.inner.resolve_cipher(choice)?,
+            aes:        self.inner.resolve_cipher(&CipherChoice::AESGCM),
+        }))
+    }
+}
+
+/// hacl-star does not implement AES-GCM, ring does
+fn resolver() -> Box<CryptoResolver> {
+    let fallback: Box<CryptoResolver> = Box::new(RandResolver::default());
+    #[cfg(feature = "aesgcm")]
+    let fallback: Box<CryptoResolver> = Box::new(FallbackResolver::new(
+            Box::new(snow::resolvers::RingResolver::default()),
+            fallback,
+            ));
+    Box::new(FallbackResolver::new(
+            Box::new(snow::resolvers::HaclStarResolver::default()),
+            fallback,
+            ))
+}
+
+/// the handshake stores what it negotiated as soon as it is known, and rekeys reuse it
+fn new_handshake_builder<'builder>(params: NoiseParams, negotiated: &Arc<Negotiated>) -> Builder<'builder> {
+    Builder::with_resolver(params, Box::new(NegotiatedResolver {
+        negotiated: negotiated.clone(),
+        inner:      resolver(),
+    }))
+}
+
+/// initiator and responder of a completed handshake
+#[cfg(test)]
+fn pair() -> (Transport, Transport) {
+    let xsecret = Secret::gen();
+    let (mut requester, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    let (r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    requester.recv_response(pkt).unwrap();
+    (requester.into_transport().unwrap(), r)
+}
+
+#[test]
+fn rekey() {
+    let (mut i, mut r) = pair();
+
+    let (epoch, request) = i.rekey_request(0).unwrap().unwrap();
+    assert_eq!(epoch, 1);
+    assert!(i.rekey_request(0).unwrap().is_none(), "rekey is already in progress");
+
+    // in flight while the keys are switched
+    let inflight1 = r.send(b"old1").unwrap();
+    let inflight2 = r.send(b"old2").unwrap();
+
+    let response = r.rekey_recv(epoch, false, &request).unwrap().unwrap();
+    assert_eq!(r.epoch(), 0, "responder only switches once the initiator did");
+    assert_eq!(r.rekey_recv(epoch, false, &request).unwrap(), Some(response.clone()));
+
+    assert!(i.rekey_recv(epoch, true, &response).unwrap().is_none());
+    assert_eq!(i.epoch(), 1);
+
+    let pkt = i.send(b"new").unwrap();
+    assert_eq!(r.recv(pkt).unwrap(), &b"new"[..]);
+    assert_eq!(r.epoch(), 1);
+
+    assert_eq!(i.recv(inflight1).unwrap(), &b"old1"[..]);
+    assert!(!i.expire_previous(), "peer did not send anything with the new key yet");
+
+    let pkt = r.send(b"new").unwrap();
+    assert_eq!(i.recv(pkt).unwrap(), &b"new"[..]);
+    assert!(i.expire_previous());
+    assert!(i.recv(inflight2).is_err(), "previous epoch must be rejected after expiry");
+}
+
+#[test]
+fn rekey_timeout() {
+    let (mut i, mut r) = pair();
+
+    let (epoch, lost) = i.rekey_request(0).unwrap().unwrap();
+    let stale = r.rekey_recv(epoch, false, &lost).unwrap().unwrap();
+    assert!(i.rekey_request(REKEY_TIMEOUT - 1).unwrap().is_none());
+
+    // the response never arrived, so the request is replaced by a new one
+    let (epoch, request) = i.rekey_request(REKEY_TIMEOUT).unwrap().unwrap();
+    assert_eq!(epoch, 1);
+    let response = r.rekey_recv(epoch, false, &request).unwrap().unwrap();
+    assert!(response != stale, "peer must answer the new request, not repeat its old response");
+
+    assert!(i.rekey_recv(epoch, true, &response).unwrap().is_none());
+    assert_eq!(i.epoch(), 1);
+    let pkt = i.send(b"new").unwrap();
+    assert_eq!(r.recv(pkt).unwrap(), &b"new"[..]);
+    assert_eq!(r.epoch(), 1);
+}
+
+#[test]
+fn padding() {
+    let (mut i, mut r) = pair();
+
+    let pkt = r.send(b"hello").unwrap();
+    assert_eq!(pkt.payload.len(), 256);
+    assert_eq!(i.recv(pkt).unwrap(), &b"hello"[..]);
+
+    r.set_padding(Padding::None);
+    let pkt = r.send(b"hello").unwrap();
+    assert_eq!(pkt.payload.len(), 2 + 5 + 16, "length prefix, payload and tag");
+    assert_eq!(i.recv(pkt).unwrap(), &b"hello"[..]);
+}
+
+#[test]
+fn send_errors() {
+    let xsecret = Secret::gen();
+    let (_, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert!(responder.send_response(0, &Secret::gen(), &Vec::new()).is_err(), "route 0 is for handshakes");
+
+    let (mut requester, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    let (mut r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    requester.recv_response(pkt).unwrap();
+    assert!(r.send(&vec![0; u16::max_value() as usize]).is_err());
+    assert!(r.send(b"hello").is_ok());
+
+    let mut w = Vec::new();
+    assert!(write_versions(&mut w, &[0x09; 256]).is_err());
+    assert!(write_suites(&mut w, &[CipherSuite::ChaChaPoly; 256]).is_err());
+    assert!(w.is_empty());
+}
+
+#[test]
+fn version_negotiation() {
+    let xsecret = Secret::gen();
+    let (mut requester, pkt) = initiate_offering(
+        Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, &[0x09, 0x08], &offered_suites(), false,
+    ).unwrap();
+    assert_eq!(pkt.version, packet::HANDSHAKE_VERSION);
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    let (mut r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    requester.recv_response(pkt).unwrap();
+    let mut i = requester.into_transport().unwrap();
+    assert_eq!(i.version(), 0x09);
+    assert_eq!(r.version(), 0x09);
+
+    let mut pkt = i.send(b"hello").unwrap();
+    assert_eq!(pkt.version, 0x09);
+    pkt.version = 0x08;
+    assert!(r.recv(pkt).is_err(), "packets must carry the negotiated version");
+
+    // an initiator that only speaks the original frame set
+    let (_, pkt) = initiate_offering(
+        Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, &[0x08], &offered_suites(), false,
+    ).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert_eq!(responder.version, 0x08);
+
+    // an initiator from before version negotiation sends no versions at all
+    let (_, pkt) = initiate_offering(
+        Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, &[], &offered_suites(), false,
+    ).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert_eq!(responder.version, packet::HANDSHAKE_VERSION);
+
+    let (_, pkt) = initiate_offering(
+        Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, &[0x0a], &offered_suites(), false,
+    ).unwrap();
+    assert!(respond(Some(&xsecret), pkt, None).is_err());
+}
+
+#[test]
+fn cipher_suites() {
+    let xsecret = Secret::gen();
+    let handshake = |offered: &[CipherSuite], supported: &[CipherSuite]| -> Result<(Transport, Transport), Error> {
+        let (mut requester, pkt) = initiate_offering(
+            Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None, SUPPORTED_VERSIONS, offered, false,
+        )?;
+        let (responder, _, _, _) = respond_supporting(Some(&xsecret), pkt, None, SUPPORTED_VERSIONS, supported)?;
+        let (r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new())?;
+        requester.recv_response(pkt)?;
+        Ok((requester.into_transport()?, r))
+    };
+
+    let (i, r) = handshake(&[CipherSuite::ChaChaPoly], &available_suites()).unwrap();
+    assert_eq!(i.suite(), CipherSuite::ChaChaPoly);
+    assert_eq!(r.suite(), CipherSuite::ChaChaPoly);
+
+    // the responder picks the first suite of the initiator it supports
+    let (i, r) = handshake(&[CipherSuite::Aes256Gcm, CipherSuite::ChaChaPoly], &[CipherSuite::ChaChaPoly]).unwrap();
+    assert_eq!(i.suite(), CipherSuite::ChaChaPoly);
+    assert_eq!(r.suite(), CipherSuite::ChaChaPoly);
+
+    // an initiator from before suite negotiation offers nothing and gets ChaChaPoly
+    let (i, r) = handshake(&[], &available_suites()).unwrap();
+    assert_eq!(i.suite(), CipherSuite::ChaChaPoly);
+    assert_eq!(r.suite(), CipherSuite::ChaChaPoly);
+
+    assert!(handshake(&[CipherSuite::Aes256Gcm], &[CipherSuite::ChaChaPoly]).is_err());
+
+    if CipherSuite::Aes256Gcm.available() {
+        let offered = [CipherSuite::Aes256Gcm, CipherSuite::ChaChaPoly];
+        let (mut i, mut r) = handshake(&offered, &available_suites()).unwrap();
+        assert_eq!(i.suite(), CipherSuite::Aes256Gcm);
+        assert_eq!(r.suite(), CipherSuite::Aes256Gcm);
+        let pkt = i.send(b"hello").unwrap();
+        assert_eq!(&r.recv(pkt).unwrap()[..], b"hello");
+
+        // rekeys stay on the negotiated suite
+        let (epoch, request) = i.rekey_request(0).unwrap().unwrap();
+        let response = r.rekey_recv(epoch, false, &request).unwrap().unwrap();
+        i.rekey_recv(epoch, true, &response).unwrap();
+        let pkt = i.send(b"again").unwrap();
+        assert_eq!(&r.recv(pkt).unwrap()[..], b"again");
+        assert_eq!(r.suite(), CipherSuite::Aes256Gcm);
+    }
+}
+
+#[test]
+fn certificate_chain() {
+    use certificate::{Authenticator, AuthenticatorSide, CertificateRequest};
+
+    let xsecret = Secret::gen();
+    let shadow = Secret::gen().address();
+    let door = Secret::gen();
+    let authority = Secret::gen();
+    let initiator = Secret::gen();
+
+    let chain = vec![
+        CertificateRequest::new(32, initiator.identity())
+            .subscribe(shadow.clone(), vec![door.identity()], &["open"])
+            .sign(&authority, 1),
+    ];
+    let (mut requester, pkt) = initiate(Some(&xsecret.address()), &initiator, 1, &chain, None).unwrap();
+    let (responder, identity, _, received) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert_eq!(received, chain);
+
+    // the responder can authorize the initiator before responding
+    let mut auth = Authenticator::new(AuthenticatorSide::Subscribe, shadow, door.identity());
+    auth.allow(authority.identity(), vec!["open".to_string()]);
+    auth.authorize(&identity, "open".to_string(), &received).unwrap();
+    assert!(auth.authorize(&identity, "open".to_string(), &Vec::new()).is_err());
+
+    let (_, pkt) = responder.send_response(2, &door, &chain).unwrap();
+    let (_, received) = requester.recv_response(pkt).unwrap();
+    assert_eq!(received, chain);
+
+    let too_long = vec![chain[0].clone(); MAX_CHAIN_LEN + 1];
+    assert!(initiate(Some(&xsecret.address()), &initiator, 1, &too_long, None).is_err());
+}
+
+#[test]
+fn preshared_key() {
+    let xsecret = Secret::gen();
+    let psk = [7; 32];
+
+    let (mut requester, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), Some(&psk)).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, Some(&psk)).unwrap();
+    let (mut r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    requester.recv_response(pkt).unwrap();
+    let mut i = requester.into_transport().unwrap();
+    let pkt = i.send(b"hello").unwrap();
+    assert_eq!(&r.recv(pkt).unwrap()[..], b"hello");
+
+    // without the psk, the handshake is rejected right away
+    let (_, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None).unwrap();
+    assert!(respond(Some(&xsecret), pkt, Some(&psk)).is_err());
+
+    // with a different psk, the initiator cannot read the response
+    let (mut requester, pkt) =
+        initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), Some(&[8; 32])).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, Some(&psk)).unwrap();
+    let (_, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    assert!(requester.recv_response(pkt).is_err());
+}
+
+#[test]
+fn hybrid_handshake() {
+    use certificate::CertificateRequest;
+
+    let xsecret = Secret::gen();
+    let hybrid = || initiate_hybrid(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None);
+    if !kem::available() {
+        assert!(hybrid().is_err());
+        return;
+    }
+
+    let (mut requester, pkt) = hybrid().unwrap();
+    assert_eq!(packet::HEADER_SIZE + pkt.payload.len(), 1044, "must fit the packet size every path carries");
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert!(responder.hybrid());
+    let (mut r, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    assert_eq!(packet::HEADER_SIZE + pkt.payload.len(), 1044);
+    requester.recv_response(pkt).unwrap();
+    let mut i = requester.into_transport().unwrap();
+    assert!(i.hybrid());
+    assert!(r.hybrid());
+    let pkt = i.send(b"hello").unwrap();
+    assert_eq!(&r.recv(pkt).unwrap()[..], b"hello");
+
+    // rekeys are mixed with the kem secret too, a peer that does not know it cannot follow
+    let (epoch, request) = i.rekey_request(0).unwrap().unwrap();
+    let response = r.rekey_recv(epoch, false, &request).unwrap().unwrap();
+    let secret = i.negotiated.kem.lock().unwrap().take();
+    assert!(i.rekey_recv(epoch, true, &response).is_err());
+    *i.negotiated.kem.lock().unwrap() = secret;
+
+    let (epoch, request) = i.rekey_request(0).unwrap().unwrap();
+    let response = r.rekey_recv(epoch, false, &request).unwrap().unwrap();
+    i.rekey_recv(epoch, true, &response).unwrap();
+    let pkt = i.send(b"again").unwrap();
+    assert_eq!(&r.recv(pkt).unwrap()[..], b"again");
+
+    // the ciphertext is not encrypted, but a modified one yields different keys
+    let (mut requester, pkt) = hybrid().unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    let (_, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    let mut payload = pkt.payload.to_vec();
+    let len = payload.len();
+    payload[len - 1] ^= 1;
+    let pkt = packet::EncryptedPacket {
+        payload: payload.into(),
+        ..pkt
+    };
+    assert!(requester.recv_response(pkt).is_err());
+    assert!(requester.negotiated.kem.lock().unwrap().is_none(), "a failed response leaves no secret behind");
+
+    // a response without a ciphertext is rejected
+    let (mut requester, _) = hybrid().unwrap();
+    let (_, pkt) = initiate(Some(&xsecret.address()), &Secret::gen(), 1, &Vec::new(), None).unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert!(!responder.hybrid());
+    let (_, pkt) = responder.send_response(2, &Secret::gen(), &Vec::new()).unwrap();
+    assert!(requester.recv_response(pkt).is_err());
+
+    // handshakes are not fragmented, so there is no room for a certificate chain on either side
+    let secret = Secret::gen();
+    let chain = vec![CertificateRequest::new(32, secret.identity()).sign(&Secret::gen(), 1)];
+    assert!(initiate_hybrid(Some(&xsecret.address()), &secret, 1, &chain, None).is_err());
+    let (_, pkt) = hybrid().unwrap();
+    let (responder, _, _, _) = respond(Some(&xsecret), pkt, None).unwrap();
+    assert!(responder.send_response(2, &secret, &chain).is_err());
+}
+
+#[test]
+fn malformed_packets() {
+    let handshake = |payload: Vec<u8>| packet::EncryptedPacket {
+        version:   packet::HANDSHAKE_VERSION,
+        route:     0,
+        direction: RoutingDirection::Initiator2Responder,
+        counter:   0,
+        payload:   payload.into(),
+    };
+    assert!(respond(None, handshake(Vec::new()), None).is_err());
+
+    // NN carries the payload of the initiator in plaintext after the ephemeral key
+    let mut payload = vec![0; 32 + 32 + 8];
+    payload.extend_from_slice(&[0xff, 0xff]);
+    payload.resize(256, 0);
+    assert!(respond(None, handshake(payload.clone()), None).is_err(), "too many certificates");
+    payload[72..76].copy_from_slice(&[0x00, 0x01, 0xff, 0xff]);
+    assert!(respond(None, handshake(payload), None).is_err(), "certificate longer than the packet");
+
+    let (mut i, mut r) = pair();
+    let mut pkt = i.send(b"hello").unwrap();
+    pkt.counter = 0;
+    assert!(r.recv(pkt).is_err());
 }
 
 /*
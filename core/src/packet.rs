@@ -1,34 +1,77 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 use failure::Error;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 #[derive(Debug, Fail)]
 enum PacketError {
     #[fail(display = "invalid version: {}", version)]
     InvalidVersion { version: u8 },
 
+    #[fail(display = "unsupported version: {}", version)]
+    UnsupportedVersion { version: u8 },
+
     #[fail(display = "invalid frame type: {}", typ)]
     InvalidFrameType { typ: u8 },
+
+    #[fail(display = "{} frame cannot be sent with version {}", typ, version)]
+    UnsupportedFrame { typ: &'static str, version: u8 },
+
+    #[fail(display = "invalid ack range below packet {}", smallest)]
+    InvalidAckRange { smallest: u64 },
+
+    #[fail(display = "cannot encode empty ack")]
+    EmptyAck,
+
+    #[fail(display = "{} frame with {} bytes is too large to encode", typ, len)]
+    FrameTooLarge { typ: &'static str, len: usize },
 }
 
 pub type RoutingKey = u64;
 
+/// the wire version spoken before versions were negotiated.
+/// handshake packets always carry it, so that responders of any version can parse them
+pub const HANDSHAKE_VERSION: u8 = 0x08;
+
+/// the first wire version with frame types above 0x07, ack ranges and the header table size in Config.
+/// 0x08 peers only know the frames from before version negotiation
+pub const EXTENDED_VERSION: u8 = 0x09;
+
+/// wire versions this implementation speaks, most preferred first
+pub const SUPPORTED_VERSIONS: &'static [u8] = &[0x09, 0x08];
+
+/// most packet counters in the 0x01 ack of version 0x08. older ones are left out
+const MAX_LEGACY_ACKS: usize = 64;
+
+/// pick the version to speak with a peer that supports `theirs`.
+/// our preference wins, since the responder decides
+pub fn negotiate_version(ours: &[u8], theirs: &[u8]) -> Option<u8> {
+    ours.iter().find(|v| theirs.contains(v)).cloned()
+}
+
+/// version, reserved bytes, route and counter in front of the payload of every packet
+pub const HEADER_SIZE: usize = 20;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RoutingDirection {
     Initiator2Responder,
     Responder2Initiator,
 }
 
+#[derive(Clone)]
 pub struct EncryptedPacket {
     pub version:   u8,
     pub route:     RoutingKey,
     pub direction: RoutingDirection,
     pub counter:   u64,
-    pub payload:   Vec<u8>,
+    pub payload:   Bytes,
 }
 
 impl EncryptedPacket {
-    pub fn decode(mut inbuf: &[u8]) -> Result<EncryptedPacket, Error> {
+    /// the payload is a slice of the received buffer, nothing is copied
+    pub fn decode<B: Into<Bytes>>(buf: B) -> Result<EncryptedPacket, Error> {
+        let buf = buf.into();
+        let mut inbuf = &buf[..];
         let version = inbuf.read_u8()?;
         let mut reserved = [0; 3];
         inbuf.read_exact(&mut reserved)?;
@@ -44,11 +87,11 @@ impl EncryptedPacket {
         let route = route.as_ref().read_u64::<BigEndian>()?;
         let counter = inbuf.read_u64::<BigEndian>()?;
 
-        if version != 0x08 || reserved != [0xff, 0xff, 0xff] {
+        if !SUPPORTED_VERSIONS.contains(&version) || reserved != [0xff, 0xff, 0xff] {
             return Err(PacketError::InvalidVersion { version }.into());
         }
 
-        let payload = inbuf.to_vec();
+        let payload = buf.slice_from(HEADER_SIZE);
 
         Ok(EncryptedPacket {
             version,
@@ -59,8 +102,9 @@ impl EncryptedPacket {
         })
     }
 
-    pub fn encode(mut self) -> Vec<u8> {
-        let mut w = [self.version].to_vec();
+    pub fn encode(self) -> Vec<u8> {
+        let mut w = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        w.push(self.version);
         w.extend_from_slice(&[0xff; 3]);
 
         let mut route = [0; 8];
@@ -71,7 +115,7 @@ impl EncryptedPacket {
         };
         w.write(&route).unwrap();
         w.write_u64::<BigEndian>(self.counter).unwrap();
-        w.append(&mut self.payload);
+        w.extend_from_slice(&self.payload);
         w
     }
 }
@@ -82,31 +126,162 @@ fn decode_with_payload() {
         0x08, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // routing key
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packet counter
         0xf0, 0x0d, // payload
-    ]).unwrap();
-    assert_eq!(pl.payload.as_slice(), &[0xf0, 0x0d]);
+    ][..]).unwrap();
+    assert_eq!(&pl.payload[..], &[0xf0, 0x0d]);
+    assert_eq!(pl.encode().len(), HEADER_SIZE + 2);
 }
 
 #[test]
 fn decode_invalid_packets() {
-    assert!(EncryptedPacket::decode(&[]).is_err());
-    assert!(EncryptedPacket::decode(&[0; 128]).is_err());
-    assert!(EncryptedPacket::decode(&[0x08; 128]).is_err());
+    assert!(EncryptedPacket::decode(Bytes::new()).is_err());
+    assert!(EncryptedPacket::decode(vec![0; 128]).is_err());
+    assert!(EncryptedPacket::decode(vec![0x08; 128]).is_err());
+}
+
+#[test]
+fn versions() {
+    assert_eq!(negotiate_version(&[0x09, 0x08], &[0x08, 0x09]), Some(0x09));
+    assert_eq!(negotiate_version(&[0x09, 0x08], &[0x08]), Some(0x08));
+    assert_eq!(negotiate_version(&[0x09], &[0x08]), None);
+
+    // transport packets of a version we don't speak are dropped before decryption
+    let mut pkt = vec![0x07, 0xff, 0xff, 0xff];
+    pkt.extend_from_slice(&[0; 16]);
+    assert!(EncryptedPacket::decode(pkt).is_err());
+
+    assert!(Frame::decode(0x08, &[0x02][..]).is_ok());
+    assert!(Frame::decode(0x07, &[0x02][..]).is_err());
+
+    // 0x08 only knows the frames up to Config
+    let frame = Frame::Datagram { payload: Bytes::from(&b"dgram"[..]) };
+    let mut w = Vec::new();
+    frame.encode(&mut w).unwrap();
+    assert!(Frame::decode(0x08, &w[..]).is_err());
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap().len(), 1);
+    let mut w = Vec::new();
+    assert!(frame.encode_for(0x08, &mut w).is_err());
+    assert!(w.is_empty());
+
+    let frame = Frame::Config { timeout: Some(1), sleeping: false, header_table_size: Some(64) };
+    let mut w = Vec::new();
+    assert_eq!(frame.encode_for(0x08, &mut w).unwrap(), frame.len_for(0x08));
+    assert_eq!(w, &[0x07, 0x80, 0x00, 0x02, 0x00, 0x01]);
+    assert_eq!(frame.len_for(0x09), frame.len());
+}
+
+/// an inclusive range of acknowledged packet counters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AckRange {
+    pub smallest: u64,
+    pub largest:  u64,
+}
+
+impl AckRange {
+    pub fn contains(&self, counter: u64) -> bool {
+        counter >= self.smallest && counter <= self.largest
+    }
+
+    /// collapse individual packet counters into ranges, ordered from largest to smallest
+    pub fn from_counters(counters: &[u64]) -> Vec<AckRange> {
+        let mut counters = counters.to_vec();
+        counters.sort_unstable_by(|a, b| b.cmp(a));
+        counters.dedup();
+
+        let mut ranges: Vec<AckRange> = Vec::new();
+        for counter in counters {
+            if let Some(last) = ranges.last_mut() {
+                if last.smallest == counter + 1 {
+                    last.smallest = counter;
+                    continue;
+                }
+            }
+            ranges.push(AckRange {
+                smallest: counter,
+                largest:  counter,
+            });
+        }
+        ranges
+    }
+}
+
+/// ECN codepoint of a received IP packet, RFC 3168
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Ecn {
+    /// from the lower two bits of the IPv4 TOS or IPv6 traffic class
+    pub fn from_bits(bits: u8) -> Ecn {
+        match bits & 0x03 {
+            0x01 => Ecn::Ect1,
+            0x02 => Ecn::Ect0,
+            0x03 => Ecn::Ce,
+            _ => Ecn::NotEct,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match self {
+            Ecn::NotEct => 0x00,
+            Ecn::Ect1 => 0x01,
+            Ecn::Ect0 => 0x02,
+            Ecn::Ce => 0x03,
+        }
+    }
+}
+
+/// total number of received packets per ECN codepoint, echoed in acks
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce:   u64,
+}
+
+impl EcnCounts {
+    pub fn count(&mut self, ecn: Ecn) {
+        match ecn {
+            Ecn::NotEct => (),
+            Ecn::Ect1 => self.ect1 += 1,
+            Ecn::Ect0 => self.ect0 += 1,
+            Ecn::Ce => self.ce += 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ect0 == 0 && self.ect1 == 0 && self.ce == 0
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Frame {
+    /// a header block. with a sequence number it was encoded by the persistent hpack context of the channel,
+    /// otherwise on its own
     Header {
         stream:  u32,
-        payload: Vec<u8>,
+        seq:     Option<u32>,
+        payload: Bytes,
+    },
+    /// the beginning of a header block of the persistent hpack context that does not fit into one packet.
+    /// the block continues with the next sequence number, up to the Header frame that ends it
+    HeaderFragment {
+        stream:  u32,
+        seq:     u32,
+        payload: Bytes,
     },
     Stream {
         stream:  u32,
         order:   u64,
-        payload: Vec<u8>,
+        payload: Bytes,
     },
     Ack {
         delay: u64,
-        acked: Vec<u64>,
+        acked: Vec<AckRange>,
+        ecn:   Option<EcnCounts>,
     },
     Ping,
     Disconnect,
@@ -115,35 +290,88 @@ pub enum Frame {
         order:  u64,
     },
     Config {
-        timeout:  Option<u16>,
-        sleeping: bool,
-    }
+        timeout:           Option<u16>,
+        sleeping:          bool,
+        /// largest hpack dynamic table the sender accepts for header blocks sent to it
+        header_table_size: Option<u32>,
+    },
+    Rekey {
+        epoch:    u16,
+        response: bool,
+        payload:  Bytes,
+    },
+    Fragment {
+        stream:  u32,
+        order:   u64,
+        payload: Bytes,
+    },
+    MaxData {
+        limit: u64,
+    },
+    MaxStreamData {
+        stream: u32,
+        limit:  u64,
+    },
+    Datagram {
+        payload: Bytes,
+    },
+    Reset {
+        stream:     u32,
+        code:       u32,
+        final_size: u64,
+    },
+    PathChallenge {
+        data: u64,
+    },
+    PathResponse {
+        data: u64,
+    },
 }
 
 impl Frame {
     pub fn name(&self) -> &'static str {
         match self {
             Frame::Header { .. } => "Header",
+            Frame::HeaderFragment { .. } => "HeaderFragment",
             Frame::Stream { .. } => "Stream",
             Frame::Ack { .. } => "Ack",
             Frame::Ping => "Ping",
             Frame::Disconnect => "Disconnect",
             Frame::Close { .. } => "Close",
             Frame::Config { .. } => "Config",
+            Frame::Rekey { .. } => "Rekey",
+            Frame::Fragment { .. } => "Fragment",
+            Frame::MaxData { .. } => "MaxData",
+            Frame::MaxStreamData { .. } => "MaxStreamData",
+            Frame::Datagram { .. } => "Datagram",
+            Frame::Reset { .. } => "Reset",
+            Frame::PathChallenge { .. } => "PathChallenge",
+            Frame::PathResponse { .. } => "PathResponse",
         }
     }
 
     pub fn len(&self) -> usize {
         match self {
-            Frame::Header { payload, .. } => 1 + 4 + 2 + payload.len(),
+            Frame::Header { seq, payload, .. } => 1 + 4 + if seq.is_some() { 4 } else { 0 } + 2 + payload.len(),
+            Frame::HeaderFragment { payload, .. } => 1 + 4 + 4 + 2 + payload.len(),
             Frame::Stream { payload, .. } => 1 + 4 + 8 + 2 + payload.len(),
-            Frame::Ack { acked, .. } => 1 + 2 + 2 + 8 * acked.len(),
+            Frame::Ack { acked, ecn, .. } => {
+                1 + 2 + 8 + 4 + 2 + 8 * acked.len().saturating_sub(1) + if ecn.is_some() { 3 * 8 } else { 0 }
+            }
             Frame::Ping => 1,
             Frame::Disconnect => 1,
             Frame::Close { .. } => 1 + 4 + 8,
-            Frame::Config { timeout, .. } =>  {
-                1 + 1 + 2 + if timeout.is_some() { 2 } else { 0 }
+            Frame::Config { timeout, header_table_size, .. } =>  {
+                1 + 1 + 2 + if timeout.is_some() { 2 } else { 0 } + if header_table_size.is_some() { 4 } else { 0 }
             }
+            Frame::Rekey { payload, .. } => 1 + 2 + 1 + 2 + payload.len(),
+            Frame::Fragment { payload, .. } => 1 + 4 + 8 + 2 + payload.len(),
+            Frame::MaxData { .. } => 1 + 8,
+            Frame::MaxStreamData { .. } => 1 + 4 + 8,
+            Frame::Datagram { payload } => 1 + 2 + payload.len(),
+            Frame::Reset { .. } => 1 + 4 + 4 + 8,
+            Frame::PathChallenge { .. } => 1 + 8,
+            Frame::PathResponse { .. } => 1 + 8,
         }
     }
 
@@ -159,43 +387,153 @@ impl Frame {
             _ => false,
         }
     }
+    pub fn is_datagram(&self) -> bool {
+        match self {
+            Frame::Datagram { .. } => true,
+            _ => false,
+        }
+    }
 
-    pub fn order(&self) -> u64 {
+    /// a header block of the persistent hpack context. it must reach the peer even if its stream is reset,
+    /// because the peer cannot decode any later header block without it
+    pub fn is_sequenced_header(&self) -> bool {
+        match self {
+            Frame::Header { seq: Some(_), .. } => true,
+            Frame::HeaderFragment { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// the stream this frame belongs to, if any
+    pub fn stream_id(&self) -> Option<u32> {
+        match self {
+            Frame::Header { stream, .. } => Some(*stream),
+            Frame::HeaderFragment { stream, .. } => Some(*stream),
+            Frame::Stream { stream, .. } => Some(*stream),
+            Frame::Fragment { stream, .. } => Some(*stream),
+            Frame::Close { stream, .. } => Some(*stream),
+            Frame::MaxStreamData { stream, .. } => Some(*stream),
+            Frame::Reset { stream, .. } => Some(*stream),
+            _ => None,
+        }
+    }
+
+    /// position of the frame within its stream, or None for frames that are not ordered
+    pub fn order(&self) -> Option<u64> {
+        match self {
+            Frame::Header { .. } => Some(1),
+            Frame::Stream { order, .. } => Some(*order),
+            Frame::Close { order, .. } => Some(*order),
+            Frame::Fragment { order, .. } => Some(*order),
+            _ => None,
+        }
+    }
+
+    /// encoded size for a peer of the given wire version
+    pub fn len_for(&self, version: u8) -> usize {
+        if version >= EXTENDED_VERSION {
+            return self.len();
+        }
         match self {
-            Frame::Header { .. } => 1,
-            Frame::Stream { order, .. } => *order,
-            Frame::Close { order, .. } => *order,
-            _ => panic!("trying to order unordered frame"),
+            Frame::Ack { acked, .. } => 1 + 2 + 2 + 8 * legacy_counters(acked).len(),
+            Frame::Config { timeout, .. } => 1 + 1 + 2 + if timeout.is_some() { 2 } else { 0 },
+            _ => self.len(),
         }
     }
 
+    /// encode for a peer of the given wire version.
+    /// 0x08 peers get the 0x01 ack and no header table size, and frames they don't know are an error
+    pub fn encode_for<W: Write>(&self, version: u8, mut w: W) -> Result<usize, Error> {
+        if version >= EXTENDED_VERSION {
+            return self.encode(w);
+        }
+        match self {
+            Frame::Ack { delay, acked, .. } => {
+                check_ack_ranges(acked)?;
+                let counters = legacy_counters(acked);
+                w.write_u8(0x01)?;
+                w.write_u16::<BigEndian>(*delay as u16)?;
+                w.write_u16::<BigEndian>(counters.len() as u16)?;
+                for counter in &counters {
+                    w.write_u64::<BigEndian>(*counter)?;
+                }
+                Ok(self.len_for(version))
+            }
+            Frame::Config { timeout, sleeping, .. } => Frame::Config {
+                timeout:           *timeout,
+                sleeping:          *sleeping,
+                header_table_size: None,
+            }.encode(w),
+            Frame::Header { seq: None, .. }
+            | Frame::Stream { .. }
+            | Frame::Ping
+            | Frame::Disconnect
+            | Frame::Close { .. } => self.encode(w),
+            _ => Err(PacketError::UnsupportedFrame {
+                typ: self.name(),
+                version,
+            }.into()),
+        }
+    }
+
+    /// nothing is written if the frame cannot be encoded
     pub fn encode<W: Write>(&self, mut w: W) -> Result<usize, Error> {
         let len = self.len();
         match self {
-            Frame::Header { stream, payload } => {
-                assert!(payload.len() + 12 < u16::max_value() as usize);
-                w.write_u8(0x04)?;
+            Frame::Header { stream, seq, payload } => {
+                let plen = self.payload_len(payload)?;
+                match seq {
+                    None => {
+                        w.write_u8(0x04)?;
+                        w.write_u32::<BigEndian>(*stream)?;
+                    }
+                    Some(seq) => {
+                        w.write_u8(0x12)?;
+                        w.write_u32::<BigEndian>(*stream)?;
+                        w.write_u32::<BigEndian>(*seq)?;
+                    }
+                }
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
+            }
+            Frame::HeaderFragment { stream, seq, payload } => {
+                let plen = self.payload_len(payload)?;
+                w.write_u8(0x13)?;
                 w.write_u32::<BigEndian>(*stream)?;
-                w.write_u16::<BigEndian>(payload.len() as u16)?;
-                assert_eq!(w.write(payload)?, payload.len());
+                w.write_u32::<BigEndian>(*seq)?;
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
             }
             Frame::Stream { stream, order, payload } => {
-                assert!(payload.len() + 12 < u16::max_value() as usize);
+                let plen = self.payload_len(payload)?;
                 w.write_u8(0x05)?;
                 w.write_u32::<BigEndian>(*stream)?;
                 w.write_u64::<BigEndian>(*order)?;
-                w.write_u16::<BigEndian>(payload.len() as u16)?;
-                assert_eq!(w.write(payload)?, payload.len());
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
             }
-            Frame::Ack { delay, acked } => {
-                assert!(acked.len() < u16::max_value() as usize / 8);
-                w.write_u8(0x01)?;
+            Frame::Ack { delay, acked, ecn } => {
+                check_ack_ranges(acked)?;
+                w.write_u8(if ecn.is_some() { 0x0f } else { 0x0a })?;
                 w.write_u16::<BigEndian>(*delay as u16)?;
-                w.write_u16::<BigEndian>(acked.len() as u16)?;
-                let mut acked = acked.clone();
-                acked.sort_unstable();
-                for ack in acked {
-                    w.write_u64::<BigEndian>(ack)?;
+
+                let first = acked[0];
+                w.write_u64::<BigEndian>(first.largest)?;
+                w.write_u32::<BigEndian>((first.largest - first.smallest) as u32)?;
+                w.write_u16::<BigEndian>(acked.len() as u16 - 1)?;
+
+                let mut smallest = first.smallest;
+                for range in &acked[1..] {
+                    let gap = smallest - range.largest - 2;
+                    let len = range.largest - range.smallest;
+                    w.write_u32::<BigEndian>(gap as u32)?;
+                    w.write_u32::<BigEndian>(len as u32)?;
+                    smallest = range.smallest;
+                }
+                if let Some(ecn) = ecn {
+                    w.write_u64::<BigEndian>(ecn.ect0)?;
+                    w.write_u64::<BigEndian>(ecn.ect1)?;
+                    w.write_u64::<BigEndian>(ecn.ce)?;
                 }
             }
             Frame::Ping => {
@@ -209,7 +547,7 @@ impl Frame {
                 w.write_u32::<BigEndian>(*stream)?;
                 w.write_u64::<BigEndian>(*order)?;
             }
-            Frame::Config { timeout, sleeping } => {
+            Frame::Config { timeout, sleeping, header_table_size } => {
                 w.write_u8(0x07)?;
                 let mut flags:   u8 = 0x00;
                 let mut datalen: u16 = 0;
@@ -223,32 +561,120 @@ impl Frame {
                     flags |= 0b01000000;
                 }
 
+                if let Some(_) = header_table_size {
+                    flags |= 0b00100000;
+                    datalen += 4;
+                }
+
                 w.write_u8(flags)?;
                 w.write_u16::<BigEndian>(datalen)?;
 
                 if let Some(timeout) = timeout {
                     w.write_u16::<BigEndian>(*timeout)?;
                 }
+                if let Some(size) = header_table_size {
+                    w.write_u32::<BigEndian>(*size)?;
+                }
+            }
+            Frame::Rekey { epoch, response, payload } => {
+                let plen = self.payload_len(payload)?;
+                w.write_u8(0x08)?;
+                w.write_u16::<BigEndian>(*epoch)?;
+                w.write_u8(if *response { 0x01 } else { 0x00 })?;
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
+            }
+            Frame::Fragment { stream, order, payload } => {
+                let plen = self.payload_len(payload)?;
+                w.write_u8(0x09)?;
+                w.write_u32::<BigEndian>(*stream)?;
+                w.write_u64::<BigEndian>(*order)?;
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
+            }
+            Frame::MaxData { limit } => {
+                w.write_u8(0x0b)?;
+                w.write_u64::<BigEndian>(*limit)?;
+            }
+            Frame::MaxStreamData { stream, limit } => {
+                w.write_u8(0x0c)?;
+                w.write_u32::<BigEndian>(*stream)?;
+                w.write_u64::<BigEndian>(*limit)?;
+            }
+            Frame::Datagram { payload } => {
+                let plen = self.payload_len(payload)?;
+                w.write_u8(0x0d)?;
+                w.write_u16::<BigEndian>(plen)?;
+                w.write_all(payload)?;
+            }
+            Frame::Reset { stream, code, final_size } => {
+                w.write_u8(0x0e)?;
+                w.write_u32::<BigEndian>(*stream)?;
+                w.write_u32::<BigEndian>(*code)?;
+                w.write_u64::<BigEndian>(*final_size)?;
+            }
+            Frame::PathChallenge { data } => {
+                w.write_u8(0x10)?;
+                w.write_u64::<BigEndian>(*data)?;
+            }
+            Frame::PathResponse { data } => {
+                w.write_u8(0x11)?;
+                w.write_u64::<BigEndian>(*data)?;
             }
         }
         Ok(len)
     }
 
-    pub fn decode<R: Read>(mut r: R) -> Result<Vec<Frame>, Error> {
+    /// the 2 byte length prefix of a payload, including some room for the rest of the frame
+    fn payload_len(&self, payload: &[u8]) -> Result<u16, Error> {
+        if payload.len() + 12 >= u16::max_value() as usize {
+            return Err(PacketError::FrameTooLarge {
+                typ: self.name(),
+                len: payload.len(),
+            }.into());
+        }
+        Ok(payload.len() as u16)
+    }
+
+    /// decode the frames of a packet in the negotiated wire version.
+    /// payloads are slices of buf, nothing is copied
+    pub fn decode<B: Into<Bytes>>(version: u8, buf: B) -> Result<Vec<Frame>, Error> {
+        match version {
+            0x08 => Frame::decode_v8(buf.into()),
+            0x09 => Frame::decode_v9(buf.into()),
+            version => Err(PacketError::UnsupportedVersion { version }.into()),
+        }
+    }
+
+    /// only the frames from before version negotiation
+    fn decode_v8(buf: Bytes) -> Result<Vec<Frame>, Error> {
+        Frame::decode_frames(buf, 0x07)
+    }
+
+    fn decode_v9(buf: Bytes) -> Result<Vec<Frame>, Error> {
+        Frame::decode_frames(buf, 0x13)
+    }
+
+    /// frame types above last are invalid
+    fn decode_frames(buf: Bytes, last: u8) -> Result<Vec<Frame>, Error> {
+        let mut r = &buf[..];
         let mut f = Vec::new();
 
         loop {
             match r.read_u8() {
                 Err(_) => return Ok(f),
+                Ok(typ) if typ > last => return Err(PacketError::InvalidFrameType { typ }.into()),
                 Ok(0x00) => (),
                 Ok(0x01) => {
+                    // legacy ack with individual packet counters
                     let delay = r.read_u16::<BigEndian>()? as u64;
                     let count = r.read_u16::<BigEndian>()?;
                     let mut acked = Vec::new();
                     for _ in 0..count {
                         acked.push(r.read_u64::<BigEndian>()?);
                     }
-                    f.push(Frame::Ack { delay, acked });
+                    let acked = AckRange::from_counters(&acked);
+                    f.push(Frame::Ack { delay, acked, ecn: None });
                 }
                 Ok(0x02) => {
                     f.push(Frame::Ping);
@@ -259,16 +685,18 @@ impl Frame {
                 Ok(0x04) => {
                     let stream = r.read_u32::<BigEndian>()?;
                     let len = r.read_u16::<BigEndian>()?;
-                    let mut payload = vec![0; len as usize];
-                    r.read_exact(&mut payload)?;
-                    f.push(Frame::Header { stream, payload });
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::Header {
+                        stream,
+                        seq: None,
+                        payload,
+                    });
                 }
                 Ok(0x05) => {
                     let stream = r.read_u32::<BigEndian>()?;
                     let order = r.read_u64::<BigEndian>()?;
                     let len = r.read_u16::<BigEndian>()?;
-                    let mut payload = vec![0; len as usize];
-                    r.read_exact(&mut payload)?;
+                    let payload = take(&buf, &mut r, len as usize)?;
                     f.push(Frame::Stream { stream, order, payload });
                 }
                 Ok(0x06) => {
@@ -280,8 +708,7 @@ impl Frame {
                     let flags    = r.read_u8()?;
                     let datalen  = r.read_u16::<BigEndian>()?;
 
-                    let mut data = vec![0;datalen as usize];
-                    r.read_exact(&mut data)?;
+                    let data = take(&buf, &mut r, datalen as usize)?;
                     let mut r = &data[..];
 
                     let timeout = if flags & 0b10000000 > 0 {
@@ -292,7 +719,117 @@ impl Frame {
 
                     let sleeping = flags & 0b01000000 > 0;
 
-                    f.push(Frame::Config {timeout, sleeping});
+                    let header_table_size = if flags & 0b00100000 > 0 {
+                        Some(r.read_u32::<BigEndian>()?)
+                    } else {
+                        None
+                    };
+
+                    f.push(Frame::Config {timeout, sleeping, header_table_size});
+                }
+                Ok(0x08) => {
+                    let epoch = r.read_u16::<BigEndian>()?;
+                    let response = r.read_u8()? & 0x01 > 0;
+                    let len = r.read_u16::<BigEndian>()?;
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::Rekey { epoch, response, payload });
+                }
+                Ok(0x09) => {
+                    let stream = r.read_u32::<BigEndian>()?;
+                    let order = r.read_u64::<BigEndian>()?;
+                    let len = r.read_u16::<BigEndian>()?;
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::Fragment { stream, order, payload });
+                }
+                Ok(typ @ 0x0a) | Ok(typ @ 0x0f) => {
+                    let delay = r.read_u16::<BigEndian>()? as u64;
+                    let largest = r.read_u64::<BigEndian>()?;
+                    let len = r.read_u32::<BigEndian>()? as u64;
+                    let count = r.read_u16::<BigEndian>()?;
+
+                    if len > largest {
+                        return Err(PacketError::InvalidAckRange { smallest: largest }.into());
+                    }
+                    let mut range = AckRange {
+                        smallest: largest - len,
+                        largest,
+                    };
+                    let mut acked = vec![range];
+                    for _ in 0..count {
+                        let gap = r.read_u32::<BigEndian>()? as u64;
+                        let len = r.read_u32::<BigEndian>()? as u64;
+                        if gap + len + 2 > range.smallest {
+                            return Err(PacketError::InvalidAckRange {
+                                smallest: range.smallest,
+                            }.into());
+                        }
+                        let largest = range.smallest - gap - 2;
+                        range = AckRange {
+                            smallest: largest - len,
+                            largest,
+                        };
+                        acked.push(range);
+                    }
+                    let ecn = if typ == 0x0f {
+                        Some(EcnCounts {
+                            ect0: r.read_u64::<BigEndian>()?,
+                            ect1: r.read_u64::<BigEndian>()?,
+                            ce:   r.read_u64::<BigEndian>()?,
+                        })
+                    } else {
+                        None
+                    };
+                    f.push(Frame::Ack { delay, acked, ecn });
+                }
+                Ok(0x0b) => {
+                    let limit = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::MaxData { limit });
+                }
+                Ok(0x0c) => {
+                    let stream = r.read_u32::<BigEndian>()?;
+                    let limit = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::MaxStreamData { stream, limit });
+                }
+                Ok(0x0d) => {
+                    let len = r.read_u16::<BigEndian>()?;
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::Datagram { payload });
+                }
+                Ok(0x0e) => {
+                    let stream = r.read_u32::<BigEndian>()?;
+                    let code = r.read_u32::<BigEndian>()?;
+                    let final_size = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::Reset {
+                        stream,
+                        code,
+                        final_size,
+                    });
+                }
+                Ok(0x10) => {
+                    let data = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::PathChallenge { data });
+                }
+                Ok(0x11) => {
+                    let data = r.read_u64::<BigEndian>()?;
+                    f.push(Frame::PathResponse { data });
+                }
+                Ok(0x12) => {
+                    let stream = r.read_u32::<BigEndian>()?;
+                    let seq = r.read_u32::<BigEndian>()?;
+                    let len = r.read_u16::<BigEndian>()?;
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::Header {
+                        stream,
+                        seq: Some(seq),
+                        payload,
+                    });
+                }
+                Ok(0x13) => {
+                    let stream = r.read_u32::<BigEndian>()?;
+                    let seq = r.read_u32::<BigEndian>()?;
+                    let len = r.read_u16::<BigEndian>()?;
+                    let payload = take(&buf, &mut r, len as usize)?;
+                    f.push(Frame::HeaderFragment { stream, seq, payload });
                 }
                 Ok(typ) => return Err(PacketError::InvalidFrameType { typ }.into()),
             };
@@ -300,20 +837,73 @@ impl Frame {
     }
 }
 
+/// ranges must be descending, not adjacent, and each fit the 4 byte fields of the encoding
+fn check_ack_ranges(acked: &[AckRange]) -> Result<(), Error> {
+    if acked.is_empty() {
+        return Err(PacketError::EmptyAck.into());
+    }
+    if acked.len() >= u16::max_value() as usize / 8 {
+        return Err(PacketError::FrameTooLarge {
+            typ: "Ack",
+            len: acked.len() * 8,
+        }.into());
+    }
+
+    let mut previous: Option<AckRange> = None;
+    for range in acked {
+        let gap = match previous {
+            None => 0,
+            // the gap is encoded minus 2, since adjacent ranges would have been merged
+            Some(previous) if range.largest < previous.smallest.saturating_sub(1) => {
+                previous.smallest - range.largest - 2
+            }
+            Some(_) => u64::max_value(),
+        };
+        if range.smallest > range.largest
+            || range.largest - range.smallest > u32::max_value() as u64
+            || gap > u32::max_value() as u64
+        {
+            return Err(PacketError::InvalidAckRange { smallest: range.smallest }.into());
+        }
+        previous = Some(*range);
+    }
+    Ok(())
+}
+
+/// the counters of ack ranges, largest first, for the 0x01 ack of version 0x08
+fn legacy_counters(acked: &[AckRange]) -> Vec<u64> {
+    acked
+        .iter()
+        .flat_map(|range| (range.smallest..=range.largest).rev())
+        .take(MAX_LEGACY_ACKS)
+        .collect()
+}
+
+/// slice len bytes at the position of r out of buf, and advance r past them
+fn take(buf: &Bytes, r: &mut &[u8], len: usize) -> Result<Bytes, Error> {
+    if len > r.len() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let start = buf.len() - r.len();
+    *r = &r[len..];
+    Ok(buf.slice(start, start + len))
+}
+
 #[test]
 fn config_frames() {
     let frame = Frame::Config{
         timeout: None,
         sleeping: false,
+        header_table_size: None,
     };
     let mut w = Vec::new();
     let written = frame.encode(&mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(w,&[0x07, 0x00, 0x00, 0x00]);
 
-    let frames = Frame::decode(&w[..]).unwrap();
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
     assert_eq!(frames.len(), 1);
-    if let Frame::Config { timeout: None ,  sleeping: false} = frames[0] {
+    if let Frame::Config { timeout: None ,  sleeping: false, header_table_size: None} = frames[0] {
     } else {
         assert!(false, "expected config frame");
     }
@@ -321,28 +911,122 @@ fn config_frames() {
     let frame = Frame::Config{
         timeout: Some(1292),
         sleeping: true,
+        header_table_size: None,
     };
     let mut w = Vec::new();
     let written = frame.encode(&mut w).unwrap();
     assert_eq!(written, w.len());
     assert_eq!(w,&[0x07, 0b11000000, 0, 2, 5, 12]);
 
-    let frames = Frame::decode(&w[..]).unwrap();
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
     assert_eq!(frames.len(), 1);
-    if let Frame::Config { timeout: Some(1292),  sleeping: true} = frames[0] {
+    if let Frame::Config { timeout: Some(1292),  sleeping: true, header_table_size: None} = frames[0] {
     } else {
         assert!(false, "expected config frame");
     }
 
+    let frame = Frame::Config{
+        timeout: None,
+        sleeping: false,
+        header_table_size: Some(4096),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(w,&[0x07, 0b00100000, 0, 4, 0, 0, 0x10, 0]);
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+
 
 
 }
 
+#[test]
+fn header_frames() {
+    let frame = Frame::Header {
+        stream:  0x63,
+        seq:     None,
+        payload: vec![0xaa].into(),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(w, &[0x04, 0x00, 0x00, 0x00, 0x63, 0x00, 0x01, 0xaa]);
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap(), vec![frame]);
+
+    let frame = Frame::Header {
+        stream:  0x63,
+        seq:     Some(7),
+        payload: vec![0xaa].into(),
+    };
+    assert!(frame.is_sequenced_header());
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[0x12, 0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x00, 0x07, 0x00, 0x01, 0xaa]
+    );
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap(), vec![frame]);
+
+    let frame = Frame::HeaderFragment {
+        stream:  0x63,
+        seq:     7,
+        payload: vec![0xaa].into(),
+    };
+    assert!(frame.is_sequenced_header());
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[0x13, 0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x00, 0x07, 0x00, 0x01, 0xaa]
+    );
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap(), vec![frame]);
+    assert!(Frame::decode(0x08, &w[..]).is_err());
+}
+
+#[test]
+fn rekey_frames() {
+    let frame = Frame::Rekey {
+        epoch:    3,
+        response: true,
+        payload:  vec![0xaa, 0xbb].into(),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(w, &[0x08, 0x00, 0x03, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+}
+
+#[test]
+fn fragment_frames() {
+    let frame = Frame::Fragment {
+        stream:  0x63,
+        order:   0x1223,
+        payload: vec![0xaa].into(),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[0x09, 0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x23, 0x00, 0x01, 0xaa]
+    );
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+}
+
 #[test]
 fn encode_frame() {
     let frame = Frame::Stream {
         order:   0x1223,
-        payload: b"hello".to_vec(),
+        payload: b"hello".to_vec().into(),
         stream:  0x63,
     };
 
@@ -359,18 +1043,225 @@ fn encode_frame() {
 
     let frame = Frame::Ack {
         delay: 0x01,
-        acked: vec![0x872],
+        acked: AckRange::from_counters(&[0x872]),
+        ecn:   None,
     };
     let mut w = Vec::new();
     let written = frame.encode(&mut w).unwrap();
     assert_eq!(written, w.len());
-    assert_eq!(written, 1 + 2 + 2 + 8);
+    assert_eq!(written, 1 + 2 + 8 + 4 + 2);
     assert_eq!(
         w,
-        &[0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x72,]
+        &[0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
     );
 }
 
+#[test]
+fn flow_control_frames() {
+    let frames = vec![
+        Frame::MaxData { limit: 0x1000 },
+        Frame::MaxStreamData {
+            stream: 0x63,
+            limit:  0x2000,
+        },
+    ];
+    let mut w = Vec::new();
+    for frame in &frames {
+        let written = frame.encode(&mut w).unwrap();
+        assert_eq!(written, frame.len());
+    }
+    assert_eq!(
+        w,
+        &[
+            0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x20, 0x00,
+        ]
+    );
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap(), frames);
+}
+
+#[test]
+fn datagram_frames() {
+    let frame = Frame::Datagram {
+        payload: b"hello".to_vec().into(),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(w, &[0x0d, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o']);
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+}
+
+#[test]
+fn reset_frames() {
+    let frame = Frame::Reset {
+        stream:     0x63,
+        code:       0x0102,
+        final_size: 0x1000,
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[0x0e, 0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00]
+    );
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+}
+
+#[test]
+fn path_frames() {
+    let frames = vec![
+        Frame::PathChallenge { data: 0x0102 },
+        Frame::PathResponse { data: 0x0102 },
+    ];
+    let mut w = Vec::new();
+    for frame in &frames {
+        let written = frame.encode(&mut w).unwrap();
+        assert_eq!(written, frame.len());
+    }
+    assert_eq!(
+        w,
+        &[
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02,
+        ]
+    );
+    assert_eq!(Frame::decode(0x09, &w[..]).unwrap(), frames);
+}
+
+#[test]
+fn ack_ranges() {
+    let acked = AckRange::from_counters(&[1, 2, 3, 5, 9, 8, 3]);
+    assert_eq!(
+        acked,
+        vec![
+            AckRange {
+                smallest: 8,
+                largest:  9,
+            },
+            AckRange {
+                smallest: 5,
+                largest:  5,
+            },
+            AckRange {
+                smallest: 1,
+                largest:  3,
+            },
+        ]
+    );
+
+    let frame = Frame::Ack {
+        delay: 0x02,
+        acked,
+        ecn: None,
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(
+        w,
+        &[
+            0x0a, 0x00, 0x02, // type, delay
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, // largest
+            0x00, 0x00, 0x00, 0x01, // first range
+            0x00, 0x02, // range count
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // gap to 5, length
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // gap to 3, length
+        ]
+    );
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+
+    // ranges reaching below packet 0
+    assert!(Frame::decode(0x09, &[0x0a, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0x02, 0, 0][..]).is_err());
+    assert!(
+        Frame::decode(
+            0x09,
+            &[0x0a, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0, 0x00, 0x00, 0x01, 0, 0, 0, 0x03, 0, 0, 0, 0][..]
+        ).is_err()
+    );
+}
+
+#[test]
+fn legacy_ack() {
+    let frame = Frame::Ack {
+        delay: 0x02,
+        acked: AckRange::from_counters(&[3, 4, 9]),
+        ecn:   Some(EcnCounts { ect0: 1, ect1: 0, ce: 0 }),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode_for(0x08, &mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(written, frame.len_for(0x08));
+    assert_eq!(
+        w,
+        &[
+            0x01, 0x00, 0x02, 0x00, 0x03, // type, delay, count
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+        ]
+    );
+    let frames = Frame::decode(0x08, &w[..]).unwrap();
+    assert_eq!(frames[0].len_for(0x08), written);
+
+    // only the newest counters of long ranges
+    let frame = Frame::Ack {
+        delay: 0,
+        acked: vec![AckRange { smallest: 0, largest: 1000 }],
+        ecn:   None,
+    };
+    let mut w = Vec::new();
+    frame.encode_for(0x08, &mut w).unwrap();
+    assert_eq!(w.len(), 1 + 2 + 2 + 8 * MAX_LEGACY_ACKS);
+    assert_eq!(&w[5..13], &[0, 0, 0, 0, 0, 0, 0x03, 0xe8]);
+}
+
+#[test]
+fn ack_ecn() {
+    let frame = Frame::Ack {
+        delay: 0x03,
+        acked: AckRange::from_counters(&[7]),
+        ecn:   Some(EcnCounts {
+            ect0: 5,
+            ect1: 0,
+            ce:   2,
+        }),
+    };
+    let mut w = Vec::new();
+    let written = frame.encode(&mut w).unwrap();
+    assert_eq!(written, w.len());
+    assert_eq!(written, frame.len());
+    assert_eq!(
+        w,
+        &[
+            0x0f, 0x00, 0x03, // type, delay
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, // largest
+            0x00, 0x00, 0x00, 0x00, // first range
+            0x00, 0x00, // range count
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, // ect0
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ect1
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // ce
+        ]
+    );
+
+    let frames = Frame::decode(0x09, &w[..]).unwrap();
+    assert_eq!(frames, vec![frame]);
+
+    let mut counts = EcnCounts::default();
+    assert!(counts.is_empty());
+    for bits in 0..8 {
+        counts.count(Ecn::from_bits(bits));
+    }
+    assert_eq!(counts, EcnCounts { ect0: 2, ect1: 2, ce: 2 });
+    assert_eq!(Ecn::from_bits(Ecn::Ce.bits()), Ecn::Ce);
+}
+
 #[test]
 fn decode_frame() {
     let r = [
@@ -378,8 +1269,9 @@ fn decode_frame() {
         b'h', b'e', b'l', b'l', b'o', 0x00, 0x01, 0x00, 0x05, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12,
         0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x23, 0x00, 0x00, 0x00,
     ];
+    let r = Bytes::from(r.to_vec());
 
-    let frames = Frame::decode(&r[..]).unwrap();
+    let frames = Frame::decode(0x08, r.clone()).unwrap();
     assert_eq!(frames.len(), 2);
     if let Frame::Stream {
         order,
@@ -388,15 +1280,64 @@ fn decode_frame() {
     } = frames[0]
     {
         assert_eq!(order, 0x1223);
-        assert_eq!(payload, b"hello");
+        assert_eq!(&payload[..], b"hello");
+        assert_eq!(payload.as_ptr(), r[18..].as_ptr(), "payload is not copied");
         assert_eq!(stream, 0x63);
     } else {
         assert!(false, "expected stream frame");
     }
-    if let Frame::Ack { delay, ref acked } = frames[1] {
+    if let Frame::Ack { delay, ref acked, .. } = frames[1] {
         assert_eq!(delay, 0x05);
-        assert_eq!(acked, &[0x1224, 0x1223]);
+        assert_eq!(
+            acked,
+            &[AckRange {
+                smallest: 0x1223,
+                largest:  0x1224,
+            }]
+        );
     } else {
         assert!(false, "expected ack frame");
     }
 }
+
+#[test]
+fn truncated_frames() {
+    let frames = vec![
+        Frame::Header { stream: 1, seq: Some(2), payload: Bytes::from(&b"hi"[..]) },
+        Frame::Stream { stream: 1, order: 2, payload: Bytes::from(&b"hello"[..]) },
+        Frame::Ack {
+            delay: 1,
+            acked: AckRange::from_counters(&[1, 2, 5]),
+            ecn:   Some(EcnCounts { ect0: 1, ect1: 0, ce: 0 }),
+        },
+        Frame::Config { timeout: Some(1), sleeping: false, header_table_size: Some(64) },
+        Frame::Rekey { epoch: 1, response: true, payload: Bytes::from(&b"key"[..]) },
+        Frame::Datagram { payload: Bytes::from(&b"dgram"[..]) },
+        Frame::Reset { stream: 1, code: 2, final_size: 3 },
+    ];
+    for frame in frames {
+        let mut w = Vec::new();
+        frame.encode(&mut w).unwrap();
+        for len in 1..w.len() {
+            assert!(Frame::decode(0x09, &w[..len]).is_err(), "{} truncated to {} bytes", frame.name(), len);
+        }
+    }
+}
+
+#[test]
+fn encode_invalid_frames() {
+    let mut w = Vec::new();
+    let frame = Frame::Datagram { payload: vec![0; u16::max_value() as usize].into() };
+    assert!(frame.encode(&mut w).is_err());
+    assert!(Frame::Ack { delay: 0, acked: Vec::new(), ecn: None }.encode(&mut w).is_err());
+
+    let ascending = vec![AckRange { smallest: 1, largest: 1 }, AckRange { smallest: 5, largest: 5 }];
+    assert!(Frame::Ack { delay: 0, acked: ascending, ecn: None }.encode(&mut w).is_err());
+    let adjacent = vec![AckRange { smallest: 5, largest: 5 }, AckRange { smallest: 4, largest: 4 }];
+    assert!(Frame::Ack { delay: 0, acked: adjacent, ecn: None }.encode(&mut w).is_err());
+    let inverted = vec![AckRange { smallest: 5, largest: 1 }];
+    assert!(Frame::Ack { delay: 0, acked: inverted, ecn: None }.encode(&mut w).is_err());
+    assert!(w.is_empty(), "nothing is written for invalid frames");
+
+    assert_eq!(Frame::Ping.order(), None);
+}
@@ -0,0 +1,284 @@
+//! packetization layer path mtu discovery, loosely following RFC 8899 (DPLPMTUD).
+//!
+//! the search runs over the number of 256 byte buckets that packets are padded to,
+//! even if the channel does not pad its packets.
+//! a probe is a ping padded to the candidate size. it is not tracked by loss recovery,
+//! so a lost probe never counts as congestion. the candidate is confirmed once the peer acks it.
+//! the search is a binary search between the largest confirmed size and the smallest size
+//! that got lost MAX_PROBES times in a row.
+
+use noise::Padding;
+use packet::AckRange;
+use std::cmp::{max, min};
+
+/// unencrypted packet header: version, reserved, routing key and counter
+pub const PACKET_HEADER_SIZE: usize = 20;
+
+/// the 16 byte authentication tag and the 2 byte length prefix of the encrypted payload
+const SEAL_OVERHEAD: usize = 18;
+
+const BUCKET_SIZE: usize = 256;
+
+/// every path is assumed to carry packets of this size.
+/// leaves room for tunnel encapsulation below the IPv6 minimum MTU of 1280
+pub const BASE_PACKET_SIZE: usize = PACKET_HEADER_SIZE + 4 * BUCKET_SIZE;
+
+/// the search never goes beyond this. fits into a 9000 byte jumbo frame
+pub const MAX_PACKET_SIZE: usize = PACKET_HEADER_SIZE + 34 * BUCKET_SIZE;
+
+/// a candidate size is considered too big after this many lost probes in a row
+const MAX_PROBES: u8 = 3;
+
+/// minimum time in milliseconds to wait for a probe to be acked
+const MIN_PROBE_TIMEOUT: u64 = 500;
+
+/// milliseconds after a completed search until we try larger sizes again
+const RAISE_TIMER: u64 = 600000;
+
+/// encoded size of a transport packet carrying frames_len bytes of frames
+pub fn packet_size(frames_len: usize, padding: Padding) -> usize {
+    let sealed = frames_len + SEAL_OVERHEAD;
+    match padding {
+        Padding::None => PACKET_HEADER_SIZE + sealed,
+        Padding::Buckets => PACKET_HEADER_SIZE + sealed + (BUCKET_SIZE - sealed % BUCKET_SIZE),
+    }
+}
+
+/// the largest frames_len that still results in a packet of packet_size bytes.
+/// with Padding::None, exactly this many bytes of frames result in a packet of packet_size bytes
+pub fn frames_capacity(packet_size: usize, padding: Padding) -> usize {
+    match padding {
+        Padding::None => packet_size - PACKET_HEADER_SIZE - SEAL_OVERHEAD,
+        Padding::Buckets => packet_size - PACKET_HEADER_SIZE - SEAL_OVERHEAD - 1,
+    }
+}
+
+fn buckets(packet_size: usize) -> usize {
+    (packet_size - PACKET_HEADER_SIZE) / BUCKET_SIZE
+}
+
+fn bucket_size(buckets: usize) -> usize {
+    PACKET_HEADER_SIZE + buckets * BUCKET_SIZE
+}
+
+struct Probe {
+    counter:  u64,
+    deadline: u64,
+}
+
+pub struct Pmtud {
+    /// largest confirmed size in buckets
+    lo:    usize,
+    /// smallest size in buckets that did not get through
+    hi:    usize,
+    probe: Option<Probe>,
+    /// consecutive lost probes of the current candidate
+    lost:  u8,
+    /// when to search for larger sizes again, once the search is complete
+    raise_time: u64,
+    /// false if the socket cannot set don't fragment. probes could then arrive fragmented
+    enabled:    bool,
+}
+
+impl Pmtud {
+    pub fn new() -> Self {
+        Self {
+            lo:         buckets(BASE_PACKET_SIZE),
+            hi:         buckets(MAX_PACKET_SIZE) + 1,
+            probe:      None,
+            lost:       0,
+            raise_time: 0,
+            enabled:    true,
+        }
+    }
+
+    /// stay at the base size and never probe
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// largest packet size confirmed to get through
+    pub fn current(&self) -> usize {
+        bucket_size(self.lo)
+    }
+
+    fn searching(&self) -> bool {
+        self.hi - self.lo > 1
+    }
+
+    fn candidate(&self) -> usize {
+        (self.lo + self.hi) / 2
+    }
+
+    /// the size of the next probe to send, if one is due
+    pub fn probe(&self) -> Option<usize> {
+        if !self.enabled || self.probe.is_some() || !self.searching() {
+            return None;
+        }
+        Some(bucket_size(self.candidate()))
+    }
+
+    pub fn on_probe_sent(&mut self, counter: u64, now: u64, smoothed_rtt: u64) {
+        self.probe = Some(Probe {
+            counter,
+            deadline: now + max(3 * smoothed_rtt, MIN_PROBE_TIMEOUT),
+        });
+    }
+
+    /// returns true if a larger size got confirmed
+    pub fn on_ack(&mut self, acked: &[AckRange], now: u64) -> bool {
+        let counter = match self.probe {
+            Some(ref probe) => probe.counter,
+            None => return false,
+        };
+        if !acked.iter().any(|range| range.contains(counter)) {
+            return false;
+        }
+        self.probe = None;
+        self.lost = 0;
+        self.lo = self.candidate();
+        if !self.searching() {
+            self.raise_time = now + RAISE_TIMER;
+        }
+        true
+    }
+
+    pub fn on_timer(&mut self, now: u64) {
+        let expired = match self.probe {
+            Some(ref probe) => now >= probe.deadline,
+            None => false,
+        };
+        if expired {
+            self.probe = None;
+            self.lost += 1;
+            if self.lost >= MAX_PROBES {
+                self.lost = 0;
+                self.hi = self.candidate();
+                if !self.searching() {
+                    self.raise_time = now + RAISE_TIMER;
+                }
+            }
+        } else if !self.searching() && now >= self.raise_time {
+            // the path may have changed
+            self.hi = buckets(MAX_PACKET_SIZE) + 1;
+        }
+    }
+
+    /// the local stack refused to send a packet of size bytes, since it exceeds the mtu the stack knows.
+    /// unlike a lost probe this is certain, so the candidate is given up right away.
+    /// returns true if the current size changed
+    pub fn on_too_big(&mut self, size: usize, now: u64) -> bool {
+        if buckets(size) <= self.lo {
+            return self.on_black_hole();
+        }
+        self.probe = None;
+        self.lost = 0;
+        self.hi = min(self.hi, buckets(size));
+        if !self.searching() {
+            self.raise_time = now + RAISE_TIMER;
+        }
+        false
+    }
+
+    /// packets of the current size no longer get through.
+    /// fall back to the base size and search again. returns true if the size changed
+    pub fn on_black_hole(&mut self) -> bool {
+        self.probe = None;
+        self.lost = 0;
+        self.hi = buckets(MAX_PACKET_SIZE) + 1;
+        if self.lo == buckets(BASE_PACKET_SIZE) {
+            return false;
+        }
+        self.lo = buckets(BASE_PACKET_SIZE);
+        true
+    }
+
+    /// when on_timer must be called next
+    pub fn deadline(&self) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+        match self.probe {
+            Some(ref probe) => Some(probe.deadline),
+            None if !self.searching() => Some(self.raise_time),
+            None => None,
+        }
+    }
+}
+
+#[test]
+fn sizes() {
+    let b = Padding::Buckets;
+    assert_eq!(packet_size(0, b), PACKET_HEADER_SIZE + 256);
+    assert_eq!(packet_size(frames_capacity(BASE_PACKET_SIZE, b), b), BASE_PACKET_SIZE);
+    assert_eq!(packet_size(frames_capacity(BASE_PACKET_SIZE, b) + 1, b), BASE_PACKET_SIZE + 256);
+    assert_eq!(packet_size(frames_capacity(MAX_PACKET_SIZE, b), b), MAX_PACKET_SIZE);
+
+    let n = Padding::None;
+    assert_eq!(packet_size(10, n), PACKET_HEADER_SIZE + 10 + 18);
+    assert_eq!(packet_size(frames_capacity(BASE_PACKET_SIZE, n), n), BASE_PACKET_SIZE);
+}
+
+#[test]
+fn search() {
+    // a path that carries up to 1500 bytes
+    let path = 1500;
+    let mut pmtud = Pmtud::new();
+    let mut now = 0;
+    let mut counter = 0;
+
+    while let Some(size) = pmtud.probe() {
+        counter += 1;
+        now += 10;
+        pmtud.on_probe_sent(counter, now, 100);
+        assert_eq!(pmtud.deadline(), Some(now + 500));
+        if size <= path {
+            assert!(pmtud.on_ack(&AckRange::from_counters(&[counter]), now));
+        } else {
+            assert!(!pmtud.on_ack(&AckRange::from_counters(&[counter - 1]), now));
+            now += 500;
+            pmtud.on_timer(now);
+        }
+    }
+    assert_eq!(pmtud.current(), 1300);
+    assert_eq!(pmtud.deadline(), Some(now + RAISE_TIMER));
+
+    assert!(pmtud.on_black_hole());
+    assert_eq!(pmtud.current(), BASE_PACKET_SIZE);
+    assert_eq!(pmtud.probe(), Some(bucket_size((buckets(BASE_PACKET_SIZE) + buckets(MAX_PACKET_SIZE) + 1) / 2)));
+}
+
+#[test]
+fn too_big() {
+    // the local stack knows that the path carries up to 1500 bytes
+    let mut pmtud = Pmtud::new();
+    let mut now = 0;
+    let mut counter = 0;
+
+    while let Some(size) = pmtud.probe() {
+        counter += 1;
+        now += 10;
+        if size > 1500 {
+            assert!(!pmtud.on_too_big(size, now));
+        } else {
+            pmtud.on_probe_sent(counter, now, 100);
+            assert!(pmtud.on_ack(&AckRange::from_counters(&[counter]), now));
+        }
+    }
+    assert_eq!(pmtud.current(), 1300);
+
+    // the path shrank below the current size
+    assert!(pmtud.on_too_big(1300, now));
+    assert_eq!(pmtud.current(), BASE_PACKET_SIZE);
+
+    let pmtud = Pmtud::disabled();
+    assert_eq!(pmtud.probe(), None);
+    assert_eq!(pmtud.deadline(), None);
+}
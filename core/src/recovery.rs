@@ -1,6 +1,8 @@
-//! an implementation of draft-ietf-quic-recovery-13
+//! an implementation of draft-ietf-quic-recovery-13,
+//! with time threshold loss detection from later drafts
 
-use packet::Frame;
+use congestion::{self, CongestionController};
+use packet::{AckRange, Ecn, EcnCounts, Frame};
 use std::cmp::{max, min};
 use std::collections::HashMap;
 
@@ -18,8 +20,8 @@ const REORDERING_THRESHOLD: u64 = 3;
 /// Maximum reordering in time space before time based loss detection considers a packet lost. In fraction of an RTT.
 const TIME_REORDERING_FRACTION: f64 = 0.125;
 
-/// Whether time based loss detection is in use.  If false, uses FACK style loss detection.
-const USING_TIME_LOSS_DETECTION: bool = false;
+/// Timer granularity. Time threshold loss detection never waits less than this.
+const GRANULARITY: u64 = 1;
 
 /// Minimum time in the future a tail loss probe alarm may be set for.
 const MIN_TLP_TIMEOUT: u64 = 10;
@@ -27,19 +29,22 @@ const MIN_TLP_TIMEOUT: u64 = 10;
 /// Minimum time in the future an RTO alarm may be set for.
 const MIN_RTO_TIMEOUT: u64 = 200;
 
-// 4.8.1.  Congestion Control Settings
-
-/// The max packet size is used for calculating initial and minimum congestion windows.
-const INITIAL_MSS: u64 = 1460;
-
-/// Limit on the initial amount of outstanding data in bytes.
-const INITIAL_WINDOW: u64 = 10 * INITIAL_MSS;
-
-/// Minimum congestion window in bytes.
-const MINIMUM_WINDOW: u64 = 2 * INITIAL_MSS;
-
-/// Reduction in congestion window when a new loss event is detected.
-const LOSS_REDUCTION_FACTOR: f64 = 0.5;
+/// packets marked ECT(0) on a new path before waiting for the peer to confirm the marks, RFC 9000 13.4.2
+const ECN_TESTING_PACKETS: u64 = 10;
+
+/// ECN validation, RFC 9000 13.4.2.
+/// a path that bleaches or mangles codepoints, or a peer that does not count them, turns marking off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EcnState {
+    /// the first ECN_TESTING_PACKETS packets on a path are marked
+    Testing { sent: u64 },
+    /// waiting for the peer to confirm the marks of the testing packets. nothing else is marked meanwhile
+    Unknown,
+    /// the peer counted what we marked, every packet is marked
+    Capable,
+    /// nothing is marked, and CE counts from the peer are ignored
+    Failed,
+}
 
 #[derive(Default)]
 pub struct Pkt {
@@ -47,10 +52,29 @@ pub struct Pkt {
     ackonly:   bool,
     time_sent: u64,
     bytes:     usize,
+    /// sent with ECT(0)
+    ecn:       bool,
 
     frames: Vec<Frame>,
 }
 
+/// how packets are declared lost, configurable per channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LossDetectionMode {
+    /// FACK style: lost once REORDERING_THRESHOLD later packets have been acked,
+    /// plus the early retransmit alarm of draft 13
+    PacketThreshold,
+    /// later drafts: also lost once an acked packet was sent more than
+    /// 1 + TIME_REORDERING_FRACTION RTTs later
+    TimeThreshold,
+}
+
+impl Default for LossDetectionMode {
+    fn default() -> Self {
+        LossDetectionMode::PacketThreshold
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum LossDetection {
     None,
@@ -60,6 +84,24 @@ pub enum LossDetection {
     Unrecoverable,
 }
 
+/// rtt estimate, congestion control and loss counters. times in milliseconds, sizes in bytes
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryStats {
+    /// 0 until the first rtt sample
+    pub smoothed_rtt:          u64,
+    pub min_rtt:               u64,
+    pub rttvar:                u64,
+    pub latest_rtt:            u64,
+    pub congestion_window:     u64,
+    /// None until the first loss
+    pub ssthresh:              Option<u64>,
+    pub bytes_in_flight:       usize,
+    /// packets declared lost
+    pub packets_lost:          u64,
+    /// packets whose frames were handed back to be sent again, including tail loss probes and timeouts
+    pub packets_retransmitted: u64,
+}
+
 pub struct QuicRecovery {
     // Loss Detection
     /// a future in time space when on_loss_detection_alarm must be called
@@ -128,17 +170,30 @@ pub struct QuicRecovery {
     /// congestion feedback.
     bytes_in_flight: usize,
 
-    /// Maximum number of bytes-in-flight that may be sent.
-    congestion_window: u64,
+    /// decides how many bytes may be in flight, see congestion.rs
+    congestion: Box<CongestionController + Send>,
+    algorithm:  congestion::Algorithm,
+
+    loss_detection_mode: LossDetectionMode,
+
+    /// packets declared lost when an RTO got verified.
+    /// if any of them is acked after all, the RTO was spurious and the congestion response is undone
+    lost_by_rto: Vec<u64>,
 
-    /// The largest packet number sent when QUIC detects a loss.
-    /// When a larger packet is acknowledged, QUIC exits recovery.
-    end_of_recovery: u64,
+    /// The largest number of packets the peer reported as received with a CE mark.
+    ecn_ce_count: u64,
 
-    /// Slow start threshold in bytes.  When the congestion window
-    /// is below ssthresh, the mode is slow start and the window grows by
-    /// the number of bytes acknowledged.
-    ssthresh: u64,
+    ecn_state: EcnState,
+    /// the counts of the last ack that passed validation
+    ecn_validated: EcnCounts,
+    /// marked packets lost while testing. if all of them are, the path drops marked packets
+    ecn_testing_lost: u64,
+
+    packets_lost:          u64,
+    packets_retransmitted: u64,
+
+    /// The max packet size of the path.
+    mss: u64,
 }
 
 impl QuicRecovery {
@@ -160,9 +215,113 @@ impl QuicRecovery {
             min_rtt: <u64>::max_value(),
             max_ack_delay: 0,
             bytes_in_flight: 0,
-            congestion_window: INITIAL_WINDOW,
-            end_of_recovery: 0,
-            ssthresh: <u64>::max_value(),
+            congestion: congestion::Algorithm::default().controller(congestion::INITIAL_MSS),
+            algorithm: congestion::Algorithm::default(),
+            mss: congestion::INITIAL_MSS,
+            loss_detection_mode: LossDetectionMode::default(),
+            lost_by_rto: Vec::new(),
+            ecn_ce_count: 0,
+            ecn_state: EcnState::Testing { sent: 0 },
+            ecn_validated: EcnCounts::default(),
+            ecn_testing_lost: 0,
+            packets_lost: 0,
+            packets_retransmitted: 0,
+        }
+    }
+
+    pub fn set_loss_detection_mode(&mut self, mode: LossDetectionMode) {
+        self.loss_detection_mode = mode;
+    }
+
+    /// the path mtu changed
+    pub fn set_mss(&mut self, mss: u64) {
+        self.mss = mss;
+        self.congestion.set_mss(mss);
+    }
+
+    /// replace the congestion controller. the new one starts over with the initial window
+    pub fn set_congestion(&mut self, algorithm: congestion::Algorithm) {
+        self.congestion = algorithm.controller(self.mss);
+        self.algorithm = algorithm;
+    }
+
+    /// the peer moved to a different network path. nothing learned about the old path applies,
+    /// so congestion control and the rtt estimate start over. packets in flight are still tracked
+    pub fn on_path_change(&mut self, mss: u64) {
+        self.mss = mss;
+        self.congestion = self.algorithm.controller(mss);
+        self.lost_by_rto.clear();
+        self.latest_rtt = 0;
+        self.smoothed_rtt = 0;
+        self.rttvar = 0.0;
+        self.min_rtt = <u64>::max_value();
+        self.max_ack_delay = 0;
+        self.ecn_state = EcnState::Testing { sent: 0 };
+        self.ecn_testing_lost = 0;
+    }
+
+    pub fn ecn_state(&self) -> EcnState {
+        self.ecn_state
+    }
+
+    /// codepoint for packets that are not tracked, like probes. they are only marked on a validated path
+    pub fn ecn(&self) -> Ecn {
+        if self.ecn_state == EcnState::Capable {
+            Ecn::Ect0
+        } else {
+            Ecn::NotEct
+        }
+    }
+
+    fn ecn_failed(&mut self, reason: &str) {
+        if self.ecn_state != EcnState::Failed {
+            debug!("ecn validation failed, {}. packets are no longer marked", reason);
+            self.ecn_state = EcnState::Failed;
+        }
+    }
+
+    /// 13.4.2.1. Receiving ACK Frames with ECN Counts
+    fn validate_ecn(&mut self, newly_marked: u64, ecn: Option<EcnCounts>) {
+        if self.ecn_state == EcnState::Failed || newly_marked == 0 {
+            return;
+        }
+        let ecn = match ecn {
+            Some(ecn) => ecn,
+            None => return self.ecn_failed("marked packets were acked without ecn counts"),
+        };
+        let last = self.ecn_validated;
+        if ecn.ect0 < last.ect0 || ecn.ect1 < last.ect1 || ecn.ce < last.ce {
+            return self.ecn_failed("ecn counts decreased");
+        }
+        if ecn.ect1 > last.ect1 {
+            return self.ecn_failed("the path marked packets ECT(1)");
+        }
+        if (ecn.ect0 - last.ect0) + (ecn.ce - last.ce) < newly_marked {
+            return self.ecn_failed("the path removed marks");
+        }
+        self.ecn_validated = ecn;
+        if self.ecn_state != EcnState::Capable {
+            debug!("ecn validated");
+            self.ecn_state = EcnState::Capable;
+        }
+    }
+
+    fn on_marked_packet_lost(&mut self) {
+        match self.ecn_state {
+            EcnState::Testing { .. } | EcnState::Unknown => {
+                self.ecn_testing_lost += 1;
+                if self.ecn_testing_lost >= ECN_TESTING_PACKETS {
+                    self.ecn_failed("all testing packets were lost");
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn rtt(&self) -> congestion::Rtt {
+        congestion::Rtt {
+            smoothed: self.smoothed_rtt,
+            min:      if self.min_rtt == <u64>::max_value() { 0 } else { self.min_rtt },
         }
     }
 
@@ -176,10 +335,27 @@ impl QuicRecovery {
             return 0;
         }
 
-        if self.bytes_in_flight > self.congestion_window as usize {
+        let congestion_window = self.congestion.window() as usize;
+        if self.bytes_in_flight > congestion_window {
             0
         } else {
-            self.congestion_window as usize - self.bytes_in_flight
+            congestion_window - self.bytes_in_flight
+        }
+    }
+
+    pub fn stats(&self) -> RecoveryStats {
+        let rtt = self.rtt();
+        let ssthresh = self.congestion.ssthresh();
+        RecoveryStats {
+            smoothed_rtt:          rtt.smoothed,
+            min_rtt:               rtt.min,
+            rttvar:                self.rttvar.round() as u64,
+            latest_rtt:            self.latest_rtt,
+            congestion_window:     self.congestion.window(),
+            ssthresh:              if ssthresh == <u64>::max_value() { None } else { Some(ssthresh) },
+            bytes_in_flight:       self.bytes_in_flight,
+            packets_lost:          self.packets_lost,
+            packets_retransmitted: self.packets_retransmitted,
         }
     }
 
@@ -192,23 +368,48 @@ impl QuicRecovery {
         self.loss_detection_alarm
     }
 
+    /// drop all frames of a stream from packets in flight, so they are never retransmitted.
+    /// header blocks of the persistent hpack context are kept, the peer needs them to decode later ones.
+    /// the packets themselves stay in flight for RTT and congestion control
+    pub fn discard_stream(&mut self, stream: u32) {
+        for pkt in self.sent_packets.values_mut() {
+            pkt.frames.retain(|frame| frame.stream_id() != Some(stream) || frame.is_sequenced_header());
+        }
+    }
+
     /// 3.5.4.  Loss Detection
-    pub fn on_packet_sent(&mut self, seq: u64, frames: Vec<Frame>, now: u64) {
+    ///
+    /// returns the codepoint the packet must be sent with
+    pub fn on_packet_sent(&mut self, seq: u64, frames: Vec<Frame>, now: u64) -> Ecn {
         let (bytes, ackonly) = frames.iter().fold((0, true), |(bytes, ackonly), frame| {
             let ackonly = ackonly && frame.is_ack();
             (bytes + if frame.is_ack() { 0 } else { frame.len() }, ackonly)
         });
 
+        let ecn = match self.ecn_state {
+            EcnState::Testing { sent } => {
+                self.ecn_state = if sent + 1 >= ECN_TESTING_PACKETS {
+                    EcnState::Unknown
+                } else {
+                    EcnState::Testing { sent: sent + 1 }
+                };
+                true
+            }
+            EcnState::Capable => true,
+            EcnState::Unknown | EcnState::Failed => false,
+        };
+
         let pkt = Pkt {
             seq,
             ackonly,
             time_sent: now,
             bytes,
+            ecn,
             frames,
         };
 
         assert!(
-            self.largest_sent_packet < seq
+            self.largest_sent_packet < seq,
             "cannot send packet older than last one"
         );
         self.largest_sent_packet = seq;
@@ -222,50 +423,99 @@ impl QuicRecovery {
 
         if !ackonly {
             self.time_of_last_sent_retransmittable_packet = now;
-            self.on_packet_sent_cc(bytes);
+            self.on_packet_sent_cc(seq, bytes, now);
             self.set_loss_detection_alarm();
         }
+
+        if ecn {
+            Ecn::Ect0
+        } else {
+            Ecn::NotEct
+        }
     }
 
     /// 3.5.5. Loss Detection
     ///
     /// returns packets that are lost
-    pub fn on_ack_received(&mut self, delay: u64, acked: Vec<u64>, now: u64) -> LossDetection {
-        if let Some(largest) = acked.get(0) {
-            //TODO how does this behave when we get an older ack?
-            self.largest_acked_packet = *largest;
-
-            if self.sent_packets.contains_key(largest) {
-                self.latest_rtt = now - self.sent_packets[largest].time_sent;
-                self.update_rtt(delay.into());
+    pub fn on_ack_received(
+        &mut self,
+        delay: u64,
+        acked: Vec<AckRange>,
+        ecn: Option<EcnCounts>,
+        now: u64,
+    ) -> LossDetection {
+        // reordered acks carry older counts, so only those that raise the largest acked packet validate ecn
+        let newer = acked.get(0).map(|range| range.largest > self.largest_acked_packet).unwrap_or(false);
+        if let Some(range) = acked.get(0) {
+            let largest = range.largest;
+            // acks may arrive out of order. an older ack must not move the largest acked packet back
+            self.largest_acked_packet = max(self.largest_acked_packet, largest);
+
+            // only sample the rtt if the largest packet is newly acked
+            let sample = self.sent_packets.get(&largest).map(|pkt| (pkt.time_sent, pkt.ackonly));
+            if let Some((time_sent, ackonly)) = sample {
+                self.latest_rtt = now - time_sent;
+                self.update_rtt(delay.into(), ackonly);
             }
         }
 
+        // a packet that we declared lost after an RTO arrived after all
+        if self.lost_by_rto.iter().any(|seq| acked.iter().any(|range| range.contains(*seq))) {
+            debug!("spurious retransmission timeout, undoing congestion response");
+            self.lost_by_rto.clear();
+            self.congestion.on_spurious_retransmission_timeout();
+        }
+
         // Find all newly acked packets.
-        for acked in acked {
-            self.on_packet_acked(acked);
+        // ranges may cover far more packet numbers than we have in flight,
+        // so look up what is in flight rather than walking the ranges.
+        let mut newly_acked: Vec<u64> = self
+            .sent_packets
+            .keys()
+            .filter(|seq| acked.iter().any(|range| range.contains(**seq)))
+            .cloned()
+            .collect();
+        newly_acked.sort_unstable();
+        let newly_marked = newly_acked.iter().filter(|seq| self.sent_packets[*seq].ecn).count() as u64;
+        let mut lost_frames = Vec::new();
+        for acked in newly_acked {
+            lost_frames.append(&mut self.on_packet_acked(acked, now));
+        }
+
+        // Process ECN information if present.
+        // a router marked packets instead of dropping them. respond like to a loss,
+        // but nothing needs to be retransmitted
+        if newer {
+            self.validate_ecn(newly_marked, ecn);
+        }
+        if self.ecn_state == EcnState::Failed {
+            // counts that failed validation cannot be trusted
+        } else if let Some(ecn) = ecn {
+            if ecn.ce > self.ecn_ce_count {
+                self.ecn_ce_count = ecn.ce;
+                if let Some(range) = acked.get(0) {
+                    debug!("congestion experienced, {} packets marked", ecn.ce);
+                    self.congestion.on_loss(range.largest, self.largest_sent_packet, now);
+                }
+            }
         }
 
-        let loss = self.detect_lost_packets(now);
+        let loss = match self.detect_lost_packets(now) {
+            LossDetection::Lost(mut frames) => {
+                lost_frames.append(&mut frames);
+                LossDetection::Lost(lost_frames)
+            }
+            _ if !lost_frames.is_empty() => LossDetection::Lost(lost_frames),
+            loss => loss,
+        };
 
         self.set_loss_detection_alarm();
 
         loss
-
-        // TODO
-        // Process ECN information if present.
-        // if (ACK frame contains ECN information):
-        //    ProcessECN(ack)
-
-        // TODO: this isnt done in their pseudocode, but its mentioned in the text:
-        // 3.3.3.  Retransmission Timeout
-        // When an acknowledgment is received for a packet sent on an RTO event,
-        // any unacknowledged packets with lower packet numbers than those
-        // acknowledged MUST be marked as lost.
     }
 
     /// 3.5.5. Loss Detection
-    fn update_rtt(&mut self, ack_delay: u64) {
+    fn update_rtt(&mut self, ack_delay: u64, ackonly: bool) {
         // min_rtt ignores ack delay.
         self.min_rtt = min(self.min_rtt, self.latest_rtt);
         // Adjust for ack delay if it's plausible.
@@ -273,7 +523,7 @@ impl QuicRecovery {
             self.latest_rtt -= ack_delay;
             // Only save into max ack delay if it's used
             // for rtt calculation and is not ack only.
-            if !self.sent_packets[&self.largest_acked_packet].ackonly {
+            if !ackonly {
                 self.max_ack_delay = max(self.max_ack_delay, ack_delay);
             }
         }
@@ -294,11 +544,13 @@ impl QuicRecovery {
     }
 
     /// 3.5.6.  On Packet Acknowledgment
-    fn on_packet_acked(&mut self, acked_packet: u64) {
+    ///
+    /// returns frames of packets that are lost because an RTO got verified
+    fn on_packet_acked(&mut self, acked_packet: u64, now: u64) -> Vec<Frame> {
         match self.sent_packets.remove(&acked_packet) {
             Some(v) => {
                 if !v.ackonly {
-                    self.on_packet_acked_cc(v)
+                    self.on_packet_acked_cc(v, now)
                 }
             }
             None => {
@@ -308,19 +560,60 @@ impl QuicRecovery {
 
         // If a packet sent prior to RTO was acked, then the RTO
         // was spurious.  Otherwise, inform congestion control.
-        if self.rto_count > 0 && acked_packet > self.largest_sent_before_rto {
-            self.on_retransmission_timeout_verified();
-        }
+        let lost = if self.rto_count > 0 && acked_packet > self.largest_sent_before_rto {
+            self.on_retransmission_timeout_verified(acked_packet)
+        } else {
+            Vec::new()
+        };
 
         self.tlp_count = 0;
         self.rto_count = 0;
+        lost
+    }
+
+    /// 3.3.3.  Retransmission Timeout
+    ///
+    /// When an acknowledgment is received for a packet sent on an RTO event,
+    /// any unacknowledged packets with lower packet numbers than those
+    /// acknowledged MUST be marked as lost.
+    fn on_retransmission_timeout_verified(&mut self, acked_packet: u64) -> Vec<Frame> {
+        self.congestion.on_retransmission_timeout_verified();
+
+        let mut lost: Vec<u64> = self
+            .sent_packets
+            .keys()
+            .filter(|seq| **seq < acked_packet)
+            .cloned()
+            .collect();
+        lost.sort_unstable();
+
+        self.lost_by_rto.clear();
+        let mut frames = Vec::new();
+        for seq in lost {
+            let mut pkt = self.sent_packets.remove(&seq).unwrap();
+            self.bytes_in_flight -= pkt.bytes;
+            if !pkt.ackonly {
+                self.lost_by_rto.push(seq);
+                self.packets_lost += 1;
+            }
+            if pkt.ecn {
+                self.on_marked_packet_lost();
+            }
+            // probes usually sent the frames again already
+            if !pkt.ackonly && !pkt.frames.is_empty() {
+                self.packets_retransmitted += 1;
+            }
+            frames.append(&mut pkt.frames);
+        }
+        frames
     }
 
     //
     // TODO this loss detection triggers too early on poorly ordered networks.
     // See 3.2.1.  Fast Retransmit. It's unclear if this is a problem for our use case.
     //
-    // TODO: this is significantly worse on lossy networks than tcp, wtf is up with that?
+    // on lossy networks, NewReno treats every random loss as congestion.
+    // channels on wireless links should use congestion::Algorithm::Westwood
     //
     /// 3.5.9. Loss Detection | DetectLostPackets
     ///
@@ -328,14 +621,17 @@ impl QuicRecovery {
     fn detect_lost_packets(&mut self, now: u64) -> LossDetection {
         self.loss_time = 0;
         let mut lost_packets = Vec::new();
-        let delay_until_lost = if USING_TIME_LOSS_DETECTION {
-            (1.0 + TIME_REORDERING_FRACTION) * max(self.latest_rtt, self.smoothed_rtt) as f64
-        } else if self.largest_acked_packet == self.largest_sent_packet {
-            // Early retransmit alarm.
-            5.0 / 4.0 * max(self.latest_rtt, self.smoothed_rtt) as f64
-        } else {
-            0.0
-        }.round() as u64;
+        let rtt = max(self.latest_rtt, self.smoothed_rtt) as f64;
+        let delay_until_lost = match self.loss_detection_mode {
+            LossDetectionMode::TimeThreshold => {
+                max(((1.0 + TIME_REORDERING_FRACTION) * rtt).round() as u64, GRANULARITY)
+            }
+            LossDetectionMode::PacketThreshold if self.largest_acked_packet == self.largest_sent_packet => {
+                // Early retransmit alarm.
+                (5.0 / 4.0 * rtt).round() as u64
+            }
+            LossDetectionMode::PacketThreshold => 0,
+        };
 
         for unacked in self.sent_packets.values() {
             if unacked.seq >= self.largest_acked_packet {
@@ -344,13 +640,17 @@ impl QuicRecovery {
             let time_since_sent = now - unacked.time_sent;
             let delta = self.largest_acked_packet - unacked.seq;
             if (delay_until_lost != 0 && time_since_sent > delay_until_lost)
-                || (!USING_TIME_LOSS_DETECTION && delta > REORDERING_THRESHOLD)
+                || delta > REORDERING_THRESHOLD
             {
                 if !unacked.ackonly {
                     lost_packets.push(unacked.seq);
                 }
-            } else if delay_until_lost != 0 {
-                self.loss_time = min(self.loss_time, now + delay_until_lost - time_since_sent);
+            } else if delay_until_lost != 0 && !unacked.ackonly {
+                // the first millisecond at which it will be more than delay_until_lost
+                let loss_time = unacked.time_sent + delay_until_lost + 1;
+                if self.loss_time == 0 || loss_time < self.loss_time {
+                    self.loss_time = loss_time;
+                }
             }
         }
 
@@ -367,11 +667,20 @@ impl QuicRecovery {
             // Remove lost packets from bytes_in_flight.
             self.bytes_in_flight -= pkt.bytes;
             largest_lost_packet = max(largest_lost_packet, pkt.seq);
+            self.packets_lost += 1;
+            if pkt.ecn {
+                self.on_marked_packet_lost();
+            }
+            if !pkt.frames.is_empty() {
+                self.packets_retransmitted += 1;
+            }
 
             lost_frames.append(&mut pkt.frames);
         }
         if lost_frames.len() > 0 {
-            self.congestion_event(largest_lost_packet);
+            // a new congestion event, older RTO losses can no longer be undone
+            self.lost_by_rto.clear();
+            self.congestion.on_loss(largest_lost_packet, self.largest_sent_packet, now);
             LossDetection::Lost(lost_frames)
         } else {
             LossDetection::None
@@ -387,10 +696,13 @@ impl QuicRecovery {
             return;
         }
 
-        let alarm_duration = if self.loss_time != 0 {
+        if self.loss_time != 0 {
             // Early retransmit timer or time loss detection.
-            self.loss_time - self.time_of_last_sent_retransmittable_packet
-        } else {
+            self.loss_detection_alarm = Some(self.loss_time);
+            return;
+        }
+
+        let alarm_duration = {
             // RTO or TLP alarm
             // Calculate RTO duration
             let mut alarm_duration = self.smoothed_rtt + (4.0 * self.rttvar) as u64 + self.max_ack_delay;
@@ -431,6 +743,7 @@ impl QuicRecovery {
             }
             self.bytes_in_flight -= pkt.bytes;
             pkt.bytes = 0;
+            self.packets_retransmitted += 1;
             r.append(&mut pkt.frames);
             n -= 1;
             if n < 1 {
@@ -472,48 +785,16 @@ impl QuicRecovery {
     }
 
     /// 4.8.4.  Congestion Control
-    fn on_packet_sent_cc(&mut self, bytes: usize) {
+    fn on_packet_sent_cc(&mut self, seq: u64, bytes: usize, now: u64) {
         self.bytes_in_flight += bytes;
+        self.congestion.on_packet_sent(seq, bytes, now);
     }
 
     /// 4.8.5. Congestion Control
-    fn in_recovery(&self, packet_number: u64) -> bool {
-        packet_number <= self.end_of_recovery
-    }
-
-    /// 4.8.5. Congestion Control
-    fn on_packet_acked_cc(&mut self, acked_packet: Pkt) {
+    fn on_packet_acked_cc(&mut self, acked_packet: Pkt, now: u64) {
         self.bytes_in_flight -= acked_packet.bytes;
-
-        if self.in_recovery(acked_packet.seq) {
-            // Do not increase congestion window in recovery period.
-            return;
-        }
-
-        if self.congestion_window < self.ssthresh {
-            // Slow start.
-            self.congestion_window += acked_packet.bytes as u64;
-        } else {
-            // Congestion avoidance.
-            self.congestion_window += INITIAL_MSS * acked_packet.bytes as u64 / self.congestion_window;
-        }
-    }
-
-    /// 4.8.6 On New Congestion Event
-    fn congestion_event(&mut self, packet_number: u64) {
-        if !self.in_recovery(packet_number) {
-            self.end_of_recovery = self.largest_sent_packet;
-            self.congestion_window = (self.congestion_window as f64 * LOSS_REDUCTION_FACTOR) as u64;
-            self.congestion_window = max(self.congestion_window, MINIMUM_WINDOW);
-            self.ssthresh = self.congestion_window
-        }
-    }
-
-    // 4.8.9.  On Retransmission Timeout Verified
-    // QUIC decreases the congestion window to the minimum value once the
-    // retransmission timeout has been verified.
-    fn on_retransmission_timeout_verified(&mut self) {
-        self.congestion_window = MINIMUM_WINDOW;
+        let rtt = self.rtt();
+        self.congestion.on_ack(acked_packet.seq, acked_packet.bytes, now, rtt);
     }
 }
 
@@ -531,7 +812,7 @@ fn lossless() {
         seq += 1;
         let frame = Frame::Stream {
             order:   seq,
-            payload: vec![],
+            payload: vec![].into(),
             stream:  1,
         };
         qr.on_packet_sent(seq, vec![frame], clock);
@@ -542,7 +823,7 @@ fn lossless() {
     // -------
     // at 15ms ack everything
     clock = 15;
-    let loss = qr.on_ack_received(10, vec![10, 9, 8, 7, 6, 5, 4, 3, 2, 1], clock);
+    let loss = qr.on_ack_received(10, AckRange::from_counters(&[10, 9, 8, 7, 6, 5, 4, 3, 2, 1]), None, clock);
     assert_eq!(loss, LossDetection::None);
     assert_eq!(qr.loss_detection_alarm, None, "no alarm should be set");
 }
@@ -561,7 +842,7 @@ fn high_latency() {
         seq += 1;
         let frame = Frame::Stream {
             order:   seq,
-            payload: vec![],
+            payload: vec![].into(),
             stream:  1,
         };
         qr.on_packet_sent(seq, vec![frame], clock);
@@ -647,7 +928,7 @@ fn high_latency() {
     // 300ms, finally getting an ACK for a package
     clock = 300;
     // no loss
-    let loss = qr.on_ack_received(8, vec![1], clock);
+    let loss = qr.on_ack_received(8, AckRange::from_counters(&[1]), None, clock);
     assert_eq!(loss, LossDetection::None);
 
     /* TODO: i broke those tests because RTO now removes the pkts
@@ -681,7 +962,7 @@ fn low_latency_high_loss() {
         seq += 1;
         let frame = Frame::Stream {
             order:   seq,
-            payload: vec![0; 1000],
+            payload: vec![0; 1000].into(),
             stream:  1,
         };
         qr.on_packet_sent(seq, vec![frame], clock);
@@ -690,7 +971,7 @@ fn low_latency_high_loss() {
     assert_eq!(qr.largest_sent_packet, 10);
     assert_eq!(qr.latest_rtt, 0, "RTT cannot be calculated yet");
     assert_eq!(qr.smoothed_rtt, 0, "RTT cannot be calculated yet");
-    assert_eq!(qr.congestion_window, INITIAL_WINDOW);
+    assert_eq!(qr.congestion.window(), congestion::INITIAL_WINDOW);
     assert_eq!(
         qr.loss_detection_alarm,
         Some(20),
@@ -701,10 +982,10 @@ fn low_latency_high_loss() {
     // packet 2 ack
     clock = 5;
     // no loss
-    let loss = qr.on_ack_received(1, vec![2], clock);
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[2]), None, clock);
     assert_eq!(
-        qr.congestion_window,
-        INITIAL_WINDOW + 1015,
+        qr.congestion.window(),
+        congestion::INITIAL_WINDOW + 1015,
         "congestion windows should increase by ack'd frame"
     );
     assert_eq!(loss, LossDetection::None);
@@ -718,10 +999,10 @@ fn low_latency_high_loss() {
     // packet 4 ack
     clock = 10;
     // no loss
-    let loss = qr.on_ack_received(1, vec![4], clock);
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[4]), None, clock);
     assert_eq!(
-        qr.congestion_window,
-        INITIAL_WINDOW + 2030,
+        qr.congestion.window(),
+        congestion::INITIAL_WINDOW + 2030,
         "congestion windows should increase by ack'd frame"
     );
     assert_eq!(loss, LossDetection::None);
@@ -735,7 +1016,7 @@ fn low_latency_high_loss() {
     // packet 6 ack
     clock = 15;
     // lost packet 1
-    let loss = qr.on_ack_received(1, vec![6], clock);
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[6]), None, clock);
     let frames = if let LossDetection::Lost(frames) = loss {
         assert_eq!(frames.len(), 1);
         frames
@@ -743,8 +1024,8 @@ fn low_latency_high_loss() {
         panic!("expected lost frames");
     };
     assert_eq!(
-        qr.congestion_window,
-        (INITIAL_WINDOW + 3045) / 2,
+        qr.congestion.window(),
+        (congestion::INITIAL_WINDOW + 3045) / 2,
         "congestion windows should be halfed on loss"
     );
     //retransmit packet 1
@@ -754,9 +1035,9 @@ fn low_latency_high_loss() {
     // --------
     // ack finally arriving
     clock = 20;
-    let loss = qr.on_ack_received(1, vec![5, 4, 3, 2], clock);
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[5, 4, 3, 2]), None, clock);
     assert_eq!(loss, LossDetection::None);
-    assert_eq!(qr.congestion_window, 8822, "congestion windows should recover");
+    assert_eq!(qr.congestion.window(), 8822, "congestion windows should recover");
 }
 
 
@@ -775,7 +1056,7 @@ fn tlp_vs_ping() {
 
     clock += 100;
     seq += 1;
-    let frame = Frame::Stream { order: seq, payload: vec![0; 1000], stream:  1};
+    let frame = Frame::Stream { order: seq, payload: vec![0; 1000].into(), stream:  1};
     qr.on_packet_sent(seq, vec![frame], clock);
 
     assert_eq!(
@@ -785,7 +1066,7 @@ fn tlp_vs_ping() {
     );
 
     clock += 100;
-    let loss = qr.on_ack_received(1, vec![1], clock);
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[1]), None, clock);
     assert_eq!(loss, LossDetection::None);
     assert_eq!(qr.latest_rtt, 100);
 
@@ -797,7 +1078,7 @@ fn tlp_vs_ping() {
         seq     += 1;
         let frame = Frame::Stream {
             order:   seq,
-            payload: vec![0; 1000],
+            payload: vec![0; 1000].into(),
             stream:  1,
         };
         qr.on_packet_sent(seq, vec![frame], clock);
@@ -807,3 +1088,295 @@ fn tlp_vs_ping() {
     }
 
 }
+
+#[test]
+fn ack_ranges() {
+    let mut qr = QuicRecovery::new();
+
+    let mut clock = 0;
+    for seq in 1..11 {
+        clock += 1;
+        let frame = Frame::Stream {
+            order:   seq,
+            payload: vec![0; 100].into(),
+            stream:  1,
+        };
+        qr.on_packet_sent(seq, vec![frame], clock);
+    }
+
+    // one ack with two ranges: 1-3 and 8-10, leaving a hole at 4-7
+    clock = 20;
+    let loss = qr.on_ack_received(1, AckRange::from_counters(&[1, 2, 3, 8, 9, 10]), None, clock);
+    assert_eq!(qr.largest_acked_packet, 10);
+    let frames = if let LossDetection::Lost(frames) = loss {
+        frames
+    } else {
+        panic!("expected lost frames");
+    };
+    assert_eq!(
+        frames.iter().filter_map(|frame| frame.order()).collect::<Vec<u64>>(),
+        vec![4, 5, 6],
+        "packets more than REORDERING_THRESHOLD below the largest ack are lost"
+    );
+    assert_eq!(qr.sent_packets.len(), 1, "only packet 7 is still in flight");
+}
+
+#[test]
+fn discard_stream() {
+    let mut qr = QuicRecovery::new();
+    qr.on_packet_sent(
+        1,
+        vec![
+            Frame::Stream {
+                order:   1,
+                payload: vec![0; 100].into(),
+                stream:  1,
+            },
+            Frame::Stream {
+                order:   1,
+                payload: vec![0; 100].into(),
+                stream:  2,
+            },
+        ],
+        1,
+    );
+    qr.discard_stream(1);
+    assert_eq!(qr.bytes_in_flight(), 230, "discarded frames are still in flight");
+
+    let loss = qr.on_loss_detection_alarm(20);
+    if let LossDetection::TailLossProbe(re) = loss {
+        assert_eq!(re.iter().map(|frame| frame.stream_id()).collect::<Vec<_>>(), vec![Some(2)]);
+    } else {
+        panic!("expected TLP");
+    }
+}
+
+#[cfg(test)]
+fn stream_frame(order: u64) -> Frame {
+    Frame::Stream {
+        order,
+        payload: vec![0; 100].into(),
+        stream: 1,
+    }
+}
+
+#[test]
+fn reordering_packet_threshold() {
+    let mut qr = QuicRecovery::new();
+    for seq in 1..6 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+
+    // acks arrive out of order, but never more than REORDERING_THRESHOLD apart
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[3]), None, 50);
+    assert_eq!(loss, LossDetection::None);
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[4]), None, 51);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.largest_acked_packet, 4);
+
+    // an older ack
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), None, 52);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.largest_acked_packet, 4, "older acks do not move the largest acked packet back");
+    assert_eq!(qr.sent_packets.len(), 1);
+}
+
+#[test]
+fn reordering_time_threshold() {
+    let mut qr = QuicRecovery::new();
+    qr.set_loss_detection_mode(LossDetectionMode::TimeThreshold);
+    for seq in 1..4 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+
+    // packet 3 overtakes 1 and 2. rtt is 100, so they are lost more than 113ms after they were sent
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[3]), None, 103);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.latest_rtt, 100);
+    assert_eq!(qr.loss_time, 1 + 114);
+    assert_eq!(qr.loss_detection_alarm, Some(1 + 114));
+
+    // packet 2 arrives late, but within the time threshold.
+    // its rtt of 108 pushes the threshold for packet 1 out to 122
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[2]), None, 110);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.loss_detection_alarm, Some(1 + 123), "packet 1 is still outstanding");
+
+    // packet 1 never arrives
+    let loss = qr.on_loss_detection_alarm(123);
+    assert_eq!(loss, LossDetection::None);
+    let loss = qr.on_loss_detection_alarm(124);
+    assert_eq!(loss, LossDetection::Lost(vec![stream_frame(1)]));
+    assert_eq!(qr.loss_detection_alarm, None);
+    assert_eq!(qr.congestion.window(), (congestion::INITIAL_WINDOW + 230) / 2, "halved after packets 2 and 3 were acked");
+}
+
+#[test]
+fn reordering_packet_threshold_ignores_time() {
+    let mut qr = QuicRecovery::new();
+    for seq in 1..5 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+
+    // without the time threshold, a late packet 1 is not lost as long as fewer than 3 packets overtook it
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[3]), None, 103);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.loss_time, 0, "no loss alarm, only the tlp alarm");
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), None, 500);
+    assert_eq!(loss, LossDetection::None);
+}
+
+#[test]
+fn rto_verified() {
+    let mut qr = QuicRecovery::new();
+    for seq in 1..4 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+
+    // two tail loss probes and an RTO
+    let loss = qr.on_loss_detection_alarm(13);
+    assert_eq!(loss, LossDetection::TailLossProbe(vec![stream_frame(1)]));
+    qr.on_packet_sent(4, vec![stream_frame(1)], 13);
+    let loss = qr.on_loss_detection_alarm(23);
+    assert_eq!(loss, LossDetection::TailLossProbe(vec![stream_frame(2)]));
+    qr.on_packet_sent(5, vec![stream_frame(2)], 23);
+    let loss = qr.on_loss_detection_alarm(223);
+    assert_eq!(loss, LossDetection::RetransmissionTimeout(vec![stream_frame(3), stream_frame(1)]));
+    qr.on_packet_sent(6, vec![stream_frame(3), stream_frame(1)], 223);
+    let window = qr.congestion.window();
+
+    // the RTO packet is acked first, so everything before it is lost
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[6]), None, 300);
+    assert_eq!(loss, LossDetection::Lost(vec![stream_frame(2)]), "only packet 5 still carried frames");
+    assert!(qr.sent_packets.is_empty());
+    assert_eq!(qr.bytes_in_flight(), 0);
+    assert_eq!(qr.congestion.window(), 2 * congestion::INITIAL_MSS);
+
+    // the original packet 2 arrives after all. the RTO was spurious
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[2]), None, 310);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.congestion.window(), window + 230, "the window from before the RTO is restored");
+    assert_eq!(qr.largest_acked_packet, 6);
+}
+
+#[test]
+fn ecn_congestion_experienced() {
+    let mut qr = QuicRecovery::new();
+    for seq in 1..5 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+    let window = qr.congestion.window();
+
+    let ecn = EcnCounts {
+        ect0: 1,
+        ect1: 0,
+        ce:   0,
+    };
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1]), Some(ecn), 50);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.congestion.window(), window + 115);
+    let window = qr.congestion.window();
+
+    // a CE mark reduces the window without any packet being lost
+    let ecn = EcnCounts {
+        ect0: 1,
+        ect1: 0,
+        ce:   1,
+    };
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), Some(ecn), 51);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.congestion.window(), (window + 115) / 2, "packet 2 is acked before the window is reduced");
+    let window = qr.congestion.window();
+
+    // the same count again, or a mark on a packet sent before the window was reduced, is no new signal
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), Some(ecn), 52);
+    assert_eq!(loss, LossDetection::None);
+    let ecn = EcnCounts {
+        ect0: 1,
+        ect1: 0,
+        ce:   2,
+    };
+    let loss = qr.on_ack_received(0, AckRange::from_counters(&[1, 2, 3]), Some(ecn), 53);
+    assert_eq!(loss, LossDetection::None);
+    assert_eq!(qr.congestion.window(), window);
+    assert_eq!(qr.sent_packets.len(), 1);
+}
+
+#[test]
+fn ecn_validation() {
+    let counts = |ect0, ect1, ce| Some(EcnCounts { ect0, ect1, ce });
+
+    // the first packets are marked, then nothing until the peer confirms the marks
+    let mut qr = QuicRecovery::new();
+    for seq in 1..13 {
+        let ecn = qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+        assert_eq!(ecn, if seq <= ECN_TESTING_PACKETS { Ecn::Ect0 } else { Ecn::NotEct }, "packet {}", seq);
+    }
+    assert_eq!(qr.ecn_state(), EcnState::Unknown);
+    qr.on_ack_received(0, AckRange::from_counters(&[1, 2, 3]), counts(2, 0, 1), 50);
+    assert_eq!(qr.ecn_state(), EcnState::Capable);
+    assert_eq!(qr.on_packet_sent(13, vec![stream_frame(13)], 51), Ecn::Ect0);
+    assert_eq!(qr.ecn(), Ecn::Ect0);
+
+    // a path that bleaches the marks, or acks without counts
+    let mut qr = QuicRecovery::new();
+    for seq in 1..4 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+    qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), counts(1, 0, 0), 50);
+    assert_eq!(qr.ecn_state(), EcnState::Failed);
+    assert_eq!(qr.on_packet_sent(4, vec![stream_frame(4)], 51), Ecn::NotEct);
+    assert_eq!(qr.ecn(), Ecn::NotEct);
+
+    // and once validation failed, CE counts are ignored
+    let window = qr.congestion.window();
+    qr.on_ack_received(0, AckRange::from_counters(&[1, 2, 3]), counts(1, 0, 5), 52);
+    assert_eq!(qr.congestion.window(), window + 115);
+
+    let mut qr = QuicRecovery::new();
+    qr.on_packet_sent(1, vec![stream_frame(1)], 1);
+    qr.on_ack_received(0, AckRange::from_counters(&[1]), None, 50);
+    assert_eq!(qr.ecn_state(), EcnState::Failed);
+
+    // a path that turns ECT(0) into ECT(1)
+    let mut qr = QuicRecovery::new();
+    qr.on_packet_sent(1, vec![stream_frame(1)], 1);
+    qr.on_ack_received(0, AckRange::from_counters(&[1]), counts(0, 1, 0), 50);
+    assert_eq!(qr.ecn_state(), EcnState::Failed);
+
+    // a path that drops marked packets
+    let mut qr = QuicRecovery::new();
+    for seq in 1..16 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+    qr.on_ack_received(0, AckRange::from_counters(&[14, 15]), counts(0, 0, 0), 50);
+    assert_eq!(qr.ecn_state(), EcnState::Failed);
+
+    // a new path is tested again
+    qr.on_path_change(congestion::INITIAL_MSS);
+    assert_eq!(qr.on_packet_sent(16, vec![stream_frame(16)], 60), Ecn::Ect0);
+}
+
+#[test]
+fn path_change() {
+    let mut qr = QuicRecovery::new();
+    qr.set_congestion(congestion::Algorithm::Cubic);
+    for seq in 1..4 {
+        qr.on_packet_sent(seq, vec![stream_frame(seq)], seq);
+    }
+    qr.on_ack_received(0, AckRange::from_counters(&[1, 2]), None, 100);
+    assert_ne!(qr.smoothed_rtt, 0);
+    assert_ne!(qr.congestion.window(), congestion::INITIAL_WINDOW);
+
+    qr.on_path_change(congestion::INITIAL_MSS);
+    assert_eq!(qr.smoothed_rtt, 0);
+    assert_eq!(qr.rtt().min, 0);
+    assert_eq!(qr.congestion.window(), congestion::INITIAL_WINDOW);
+    assert_eq!(qr.algorithm, congestion::Algorithm::Cubic);
+    assert_eq!(qr.bytes_in_flight(), 115, "packet 3 is still in flight");
+
+    // the first sample on the new path
+    qr.on_ack_received(0, AckRange::from_counters(&[3]), None, 150);
+    assert_eq!(qr.smoothed_rtt, 147);
+    assert_eq!(qr.rtt().min, 147);
+}
@@ -0,0 +1,60 @@
+use bytes::{Bytes, BytesMut};
+use std::cmp;
+
+/// a receive buffer that packets are sliced out of without copying.
+/// the allocation is zeroed once when it is made, so no packet pays for clearing memory.
+/// every slice handed out shares the allocation, so a single retained slice pins all of it,
+/// and a new allocation is made once the remainder is too small for the next packet
+pub struct RecvBuffer {
+    buf:  BytesMut,
+    size: usize,
+}
+
+impl RecvBuffer {
+    /// nothing is allocated until the first packet
+    pub fn new(size: usize) -> Self {
+        RecvBuffer {
+            buf:  BytesMut::new(),
+            size: size,
+        }
+    }
+
+    /// initialized space for a packet of at most len bytes
+    pub fn reserve(&mut self, len: usize) -> &mut [u8] {
+        if self.buf.capacity() < len {
+            let mut buf = BytesMut::from(vec![0; cmp::max(len, self.size)]);
+            buf.clear();
+            self.buf = buf;
+        }
+        // the whole allocation was zeroed when it was made and only ever written to since
+        unsafe { self.buf.set_len(len) };
+        &mut self.buf[..]
+    }
+
+    /// hand out the first len bytes of the reserved space. the rest is kept for the next packet
+    pub fn take(&mut self, len: usize) -> Bytes {
+        self.buf.truncate(len);
+        self.buf.take().freeze()
+    }
+}
+
+#[test]
+fn reuse() {
+    let mut buf = RecvBuffer::new(64);
+    buf.reserve(32)[..3].copy_from_slice(b"abc");
+    let a = buf.take(3);
+    assert_eq!(&a[..], b"abc");
+
+    // the next packet starts past the previous one and sees no stale bytes
+    assert_eq!(buf.reserve(32), &[0; 32][..]);
+    buf.reserve(32)[..2].copy_from_slice(b"de");
+    let b = buf.take(2);
+    assert_eq!(&b[..], b"de");
+
+    // the remaining 59 bytes are too small, a new allocation is made
+    buf.reserve(60)[0] = 1;
+    let c = buf.take(60);
+    assert_eq!(c[0], 1);
+    assert_eq!(&a[..], b"abc");
+    assert_eq!(&b[..], b"de");
+}
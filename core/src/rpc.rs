@@ -1,9 +1,12 @@
 use failure::Error;
 use std::mem;
 
+pub use headers::Headers;
+
 pub enum Progress {
     Wait,
-    Header(Vec<u8>),
+    /// headers are encoded by the channel, with its persistent hpack context
+    Header(Headers),
     Stream(Vec<u8>),
     Close,
     Done,
@@ -17,7 +20,7 @@ pub trait CallHandler<C>  {
 
 
 pub struct ImmediateCall<C> {
-    pub header: Option<Vec<u8>>,
+    pub header: Option<Headers>,
     pub items:  Vec<Vec<u8>>,
     pub next:   Box<CallHandler<C>>,
 }
@@ -65,15 +68,12 @@ impl<C> CallHandler<C> for CallError {
     fn progress(&mut self, _:&mut C) -> Result<Progress, Error> {
         match self.state {
             0 => {
-                use hpack::Encoder;
-                let mut encoder = Encoder::new();
-                let headers = vec![
-                    (":status".as_bytes(), self.status.as_slice()),
-                    (":error".as_bytes(), self.error.as_slice()),
-                ];
-                let headers = encoder.encode(headers);
+                // sent in-band as the first message of the stream the caller opened,
+                // so it is not mistaken for a stream we open
+                let headers = Headers::with(":status", self.status.as_slice())
+                    .and(b":error".to_vec(), self.error.clone());
                 self.state = 1;
-                Ok(Progress::Stream(headers))
+                Ok(Progress::Stream(headers.encode()))
             },
             1 => {
                 self.state = 2;
@@ -85,3 +85,20 @@ impl<C> CallHandler<C> for CallError {
         }
     }
 }
+
+#[test]
+fn error_reply() {
+    let mut call = CallError::new("404", "no such path");
+    match CallHandler::<()>::progress(&mut call, &mut ()).unwrap() {
+        Progress::Stream(b) => {
+            let headers = Headers::decode(&b).unwrap();
+            assert_eq!(headers.get(b":status"), Some(&b"404"[..]));
+            assert_eq!(headers.get(b":error"), Some(&b"no such path"[..]));
+        }
+        _ => panic!("error headers must be the first message of the caller's stream"),
+    }
+    assert!(match CallHandler::<()>::progress(&mut call, &mut ()).unwrap() {
+        Progress::Close => true,
+        _ => false,
+    });
+}
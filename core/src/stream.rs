@@ -1,13 +1,16 @@
+use bytes::BytesMut;
 use failure::Error;
+use flow;
 use packet::Frame;
 use std::cmp::max;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::mem;
 
 const MAX_REORDERING: u64 = 100;
 
-//FIXME: it really should be max bytes, not messages
-const MAX_QUEUE: usize = 1000;
+/// default limit for a single message after reassembling its fragments
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug, Fail)]
 enum StreamError {
@@ -18,28 +21,66 @@ enum StreamError {
     )]
     Underflow { prev: u64, this: u64 },
 
-    #[fail(display = "stream overflow: stream consumer is too slow")]
-    Overflow,
+    #[fail(display = "fragmented message exceeds maximum size of {} bytes", max)]
+    MessageTooBig { max: usize },
+
+    #[fail(display = "{} frame cannot be ordered into a stream at {:?}", typ, order)]
+    InvalidOrder { typ: &'static str, order: Option<u64> },
 }
 
 pub struct OrderedStream {
     q:        HashMap<u64, Frame>,
     producer: u64,
     consumer: u64,
+
+    /// payload of fragments that have been consumed in order, waiting for the final frame
+    partial:          BytesMut,
+    /// bytes of all fragments not yet assembled into a message
+    fragment_bytes:   usize,
+    max_message_size: usize,
+
+    /// credit given to the peer for this stream
+    window:   flow::RecvWindow,
+    /// bytes consumed since the last call to take_consumed
+    consumed: u64,
 }
 
 impl OrderedStream {
     pub fn new() -> Self {
+        Self::with_max_message_size(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_max_message_size(max_message_size: usize) -> Self {
+        Self::with_limits(max_message_size, flow::DEFAULT_STREAM_WINDOW)
+    }
+
+    /// window is the flow control credit the peer starts with
+    pub fn with_limits(max_message_size: usize, window: u64) -> Self {
         Self {
             q:        HashMap::new(),
             producer: 1,
             consumer: 1,
+
+            partial:          BytesMut::new(),
+            fragment_bytes:   0,
+            max_message_size: max_message_size,
+
+            window:   flow::RecvWindow::new(window),
+            consumed: 0,
         }
     }
 
-    pub fn push(&mut self, frame: Frame) -> Result<(), Error> {
-        let order = frame.order();
-        assert!(order > 0);
+    /// returns the number of bytes charged against flow control credit
+    pub fn push(&mut self, frame: Frame) -> Result<u64, Error> {
+        let order = match frame.order() {
+            Some(order) if order > 0 => order,
+            order => {
+                return Err(StreamError::InvalidOrder {
+                    typ: frame.name(),
+                    order,
+                }.into())
+            }
+        };
 
         if self.producer + MAX_REORDERING < order {
             return Err(StreamError::Underflow {
@@ -49,30 +90,94 @@ impl OrderedStream {
         }
         self.producer = max(self.producer, order);
 
-        if self.q.len() > MAX_QUEUE {
-            return Err(StreamError::Overflow.into());
+        if order < self.consumer {
+            trace!("stream DUP frame with order {} {:?}", order, frame);
+            return Ok(0);
         }
 
+        let charge = flow::charge(&frame);
+
         match self.q.entry(order) {
             Entry::Occupied(v) => {
                 trace!("stream DUP frame with order {} {:?}", order, frame);
-                assert_eq!(v.get().order(), order);
+                assert_eq!(v.get().order(), Some(order));
+                return Ok(0);
             }
             Entry::Vacant(v) => {
                 trace!("stream pushed frame with order {} {:?}", order, frame);
+                if let Frame::Fragment { ref payload, .. } = frame {
+                    self.fragment_bytes += payload.len();
+                    if self.fragment_bytes > self.max_message_size {
+                        return Err(StreamError::MessageTooBig {
+                            max: self.max_message_size,
+                        }.into());
+                    }
+                }
+                self.window.on_recv(charge)?;
                 v.insert(frame);
             }
         }
 
-        Ok(())
+        Ok(charge)
+    }
+
+    /// bytes consumed by the application since the last call, including fragments waiting for reassembly
+    pub fn take_consumed(&mut self) -> u64 {
+        mem::replace(&mut self.consumed, 0)
+    }
+
+    /// bytes of fragments that arrived but are not assembled into a message yet
+    pub fn fragment_bytes(&self) -> usize {
+        self.fragment_bytes
+    }
+
+    /// flow control accounting of this stream
+    pub fn window(&self) -> &flow::RecvWindow {
+        &self.window
+    }
+
+    /// returns a new limit to advertise to the peer, if the window needs to be raised
+    pub fn window_update(&mut self) -> Option<u64> {
+        self.window.update()
     }
 
+    /// returns the next complete message.
+    /// fragments are reassembled into a single Stream frame, so message boundaries are kept intact
     pub fn pop(&mut self) -> Option<Frame> {
-        if let Some(v) = self.q.remove(&self.consumer) {
+        loop {
+            let v = self.q.remove(&self.consumer)?;
             self.consumer += 1;
-            Some(v)
-        } else {
-            None
+
+            let charge = flow::charge(&v);
+            self.window.on_consumed(charge);
+            self.consumed += charge;
+
+            match v {
+                Frame::Fragment { payload, .. } => {
+                    self.partial.extend_from_slice(&payload);
+                }
+                Frame::Stream { stream, order, payload } => {
+                    if self.partial.is_empty() {
+                        return Some(Frame::Stream { stream, order, payload });
+                    }
+                    let mut message = mem::replace(&mut self.partial, BytesMut::new());
+                    self.fragment_bytes -= message.len();
+                    message.extend_from_slice(&payload);
+                    return Some(Frame::Stream {
+                        stream,
+                        order,
+                        payload: message.freeze(),
+                    });
+                }
+                v => {
+                    if !self.partial.is_empty() {
+                        warn!("stream {} interrupted a fragmented message", v.name());
+                        self.fragment_bytes -= self.partial.len();
+                        self.partial.clear();
+                    }
+                    return Some(v);
+                }
+            }
         }
     }
 }
@@ -80,41 +185,80 @@ impl OrderedStream {
 #[test]
 pub fn overflow() {
     let mut st = OrderedStream::new();
-    for i in 1..MAX_QUEUE + 2 {
+    let frame_size = Frame::Stream {
+        order:   1,
+        payload: vec![0; 1000].into(),
+        stream:  1,
+    }.len() as u64;
+    let fits = flow::DEFAULT_STREAM_WINDOW / frame_size;
+
+    for i in 1..fits + 1 {
         st.push(Frame::Stream {
-            order:   i as u64,
-            payload: Vec::new(),
+            order:   i,
+            payload: vec![0; 1000].into(),
             stream:  1,
         }).unwrap();
     }
     assert!(
         st.push(Frame::Stream {
-            order:   MAX_QUEUE as u64 + 2,
-            payload: Vec::new(),
+            order:   fits + 1,
+            payload: vec![0; 1000].into(),
             stream:  1,
-        }).is_err()
+        }).is_err(),
+        "peer must not send beyond the credit we gave it"
     );
 }
 
+#[test]
+pub fn window_update() {
+    let mut st = OrderedStream::new();
+    let mut order = 0;
+    while st.window_update().is_none() {
+        order += 1;
+        st.push(Frame::Stream {
+            order:   order,
+            payload: vec![0; 1000].into(),
+            stream:  1,
+        }).unwrap();
+        assert!(st.pop().is_some());
+        assert_eq!(st.take_consumed(), 1000 + 15);
+    }
+    assert_eq!(order, flow::DEFAULT_STREAM_WINDOW / 2 / 1015 + 1);
+}
+
 #[test]
 pub fn underflow() {
     let mut st = OrderedStream::new();
     assert!(
         st.push(Frame::Stream {
             order:   MAX_REORDERING + 2,
-            payload: Vec::new(),
+            payload: Vec::new().into(),
             stream:  1,
         }).is_err()
     );
 }
 
+#[test]
+pub fn invalid_order() {
+    let mut st = OrderedStream::new();
+    assert!(
+        st.push(Frame::Stream {
+            order:   0,
+            payload: Vec::new().into(),
+            stream:  1,
+        }).is_err(),
+        "orders start at 1"
+    );
+    assert!(st.push(Frame::Ping).is_err());
+}
+
 #[test]
 pub fn ordered() {
     let mut st = OrderedStream::new();
     for i in 1..20 {
         st.push(Frame::Stream {
             order:   i as u64,
-            payload: vec![i as u8],
+            payload: vec![i as u8].into(),
             stream:  1,
         }).unwrap();
     }
@@ -124,7 +268,7 @@ pub fn ordered() {
             st.pop().unwrap(),
             Frame::Stream {
                 order:   i as u64,
-                payload: vec![i as u8],
+                payload: vec![i as u8].into(),
                 stream:  1,
             }
         );
@@ -143,7 +287,7 @@ pub fn unordered() {
     for i in pkg {
         st.push(Frame::Stream {
             order:   i as u64,
-            payload: vec![i as u8],
+            payload: vec![i as u8].into(),
             stream:  1,
         }).unwrap();
     }
@@ -153,9 +297,73 @@ pub fn unordered() {
             st.pop().unwrap(),
             Frame::Stream {
                 order:   i as u64,
-                payload: vec![i as u8],
+                payload: vec![i as u8].into(),
                 stream:  1,
             }
         );
     }
 }
+
+#[test]
+pub fn fragmented() {
+    let mut st = OrderedStream::new();
+    st.push(Frame::Fragment {
+        order:   2,
+        payload: vec![2].into(),
+        stream:  1,
+    }).unwrap();
+    st.push(Frame::Stream {
+        order:   3,
+        payload: vec![3].into(),
+        stream:  1,
+    }).unwrap();
+    assert_eq!(st.pop(), None, "waiting for first fragment");
+
+    st.push(Frame::Fragment {
+        order:   1,
+        payload: vec![1].into(),
+        stream:  1,
+    }).unwrap();
+    st.push(Frame::Stream {
+        order:   4,
+        payload: vec![4].into(),
+        stream:  1,
+    }).unwrap();
+
+    assert_eq!(
+        st.pop().unwrap(),
+        Frame::Stream {
+            order:   3,
+            payload: vec![1, 2, 3].into(),
+            stream:  1,
+        }
+    );
+    assert_eq!(
+        st.pop().unwrap(),
+        Frame::Stream {
+            order:   4,
+            payload: vec![4].into(),
+            stream:  1,
+        }
+    );
+    assert_eq!(st.pop(), None);
+}
+
+#[test]
+pub fn fragmented_too_big() {
+    let mut st = OrderedStream::with_max_message_size(10);
+    for i in 1..3 {
+        st.push(Frame::Fragment {
+            order:   i,
+            payload: vec![0; 5].into(),
+            stream:  1,
+        }).unwrap();
+    }
+    assert!(
+        st.push(Frame::Fragment {
+            order:   3,
+            payload: vec![0; 5].into(),
+            stream:  1,
+        }).is_err()
+    );
+}
@@ -1,44 +1,158 @@
+use bytes::Bytes;
+use clock::{self, Clock};
+use congestion;
 use failure::Error;
+use flow;
+use headers::{self, HeaderDecoder, HeaderEncoder, Headers};
 use noise;
-use packet::{EncryptedPacket, Frame};
+use packet::{self, AckRange, Ecn, EcnCounts, EncryptedPacket, Frame};
+use pmtud;
 use rand;
 use recovery;
 use replay;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::mem;
 use std::time::Duration;
+use stream;
 
+/// largest packet a channel may send once path mtu discovery allows it.
+/// receive buffers must be at least this large
+pub const MAX_PACKET_SIZE: usize = pmtud::MAX_PACKET_SIZE;
+const DEFAULT_IDLE_TIMER: u64 = 120000;
 
-#[cfg(not(target_arch = "wasm32"))]
-use std::time::Instant;
+/// give up on a peer that was not heard from for this many milliseconds.
+/// never less than twice the idle timer, so the peer had a chance to answer a ping
+pub const DEFAULT_DEAD_PEER_TIMEOUT: u64 = 360000;
 
+/// negotiate new keys after this many milliseconds
+const REKEY_AFTER_TIME: u64 = 600000;
 
-#[cfg(target_arch = "wasm32")]
-use wasm_bindgen::prelude::*;
-#[cfg(target_arch = "wasm32")]
-#[wasm_bindgen]
-extern "C" {
-    type Performance;
-    static performance: Performance;
-    #[wasm_bindgen(method)]
-    fn now(this: &Performance) -> f64;
-}
+/// negotiate new keys after sending this many packets with the same keys
+const REKEY_AFTER_PACKETS: u64 = 1 << 20;
 
-use stream;
+/// how long packets from the previous key epoch are still accepted after switching keys
+const REKEY_GRACE_TIME: u64 = 10000;
 
-//TODO: use mss?
-pub const MAX_PACKET_SIZE: usize = 1400;
-const DEFAULT_IDLE_TIMER: u64 = 120000;
+/// messages larger than this are split into Fragment frames.
+/// a fragment must fit into a packet of pmtud::BASE_PACKET_SIZE, since the path mtu may shrink after it was queued
+const MAX_FRAGMENT_SIZE: usize = 960;
+
+/// largest header block. blocks of the persistent hpack context that do not fit into a packet
+/// are split into HeaderFragment frames, those of 0x08 peers must fit into a single fragment
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// maximum number of ranges in a single ack frame. older ranges are dropped
+const MAX_ACK_RANGES: usize = 64;
+
+/// datagrams must fit into a single packet
+const MAX_DATAGRAM_SIZE: usize = MAX_FRAGMENT_SIZE;
+
+/// received datagrams buffered until progress is polled. the oldest ones are dropped first
+const MAX_DATAGRAM_QUEUE: usize = 100;
+
+/// datagrams waiting to be sent. the oldest ones are dropped first, a stale sample is worth less than a fresh one
+const MAX_DATAGRAM_SEND_QUEUE: usize = 100;
+
+/// milliseconds a stream is remembered after both sides reset it, so late frames for it are ignored
+const RESET_LINGER_TIME: u64 = 10000;
 
+/// resets of streams we never heard of are ignored beyond this many reset streams
+const MAX_RESET_STREAMS: usize = 1024;
+
+/// error code of a stream we reset because its fragments exceed what the channel reassembles at once.
+/// codes from 0xffffff00 are reserved for the transport
+pub const RESET_REASSEMBLY_LIMIT: u32 = 0xffff_ff00;
+
+/// how many sequenced header blocks may arrive ahead of the next one to decode
+const MAX_HEADER_REORDERING: u32 = 1024;
+
+/// how a channel hides its traffic from an observer of the wire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingPolicy {
+    /// packets are only as large as their content. for metered links
+    None,
+    /// packets are padded to 256 byte buckets. the default
+    Buckets,
+    /// exactly one packet every interval milliseconds, carrying whatever is queued or a ping if nothing is.
+    /// every packet is padded to the same size, so an observer sees the same traffic whether the channel is
+    /// idle or busy. this caps throughput at one packet per interval, and disables path mtu discovery for good
+    ConstantRate { interval: u64 },
+}
+
+#[derive(Default)]
 pub struct Config {
-    pub timeout:  Option<u16>,
-    pub sleeping: bool,
+    pub timeout:           Option<u16>,
+    pub sleeping:          bool,
+    /// replace the congestion controller of this side of the channel. not sent to the peer
+    pub congestion:        Option<congestion::Algorithm>,
+    /// how this side of the channel detects lost packets. not sent to the peer
+    pub loss_detection:    Option<recovery::LossDetectionMode>,
+    /// largest hpack dynamic table the peer may use for headers it sends us. defaults to headers::DEFAULT_TABLE_SIZE
+    pub header_table_size: Option<u32>,
+    /// padding of packets sent by this side of the channel. not sent to the peer
+    pub padding:           Option<PaddingPolicy>,
+    /// milliseconds without hearing from the peer before the channel times out. not sent to the peer
+    pub dead_peer_timeout: Option<u64>,
 }
 
 #[derive(Debug, Fail)]
 pub enum ChannelError {
     #[fail(display = "packet dropped by anti-replay")]
     AntiReplay,
+
+    #[fail(display = "message too big: {} bytes exceeds maximum of {}", size, max)]
+    MessageTooBig { size: usize, max: usize },
+
+    #[fail(display = "header too big: {} bytes exceeds maximum of {}", size, max)]
+    HeaderTooBig { size: usize, max: usize },
+
+    #[fail(display = "datagram too big: {} bytes exceeds maximum of {}", size, max)]
+    DatagramTooBig { size: usize, max: usize },
+
+    #[fail(display = "header block {} is too far ahead of expected {}", this, expected)]
+    HeaderTooFarAhead { expected: u32, this: u32 },
+
+    #[fail(display = "final size {} of stream {} is below the {} bytes received or above the limit of {}",
+           final_size, stream, received, limit)]
+    InvalidFinalSize { stream: u32, final_size: u64, received: u64, limit: u64 },
+
+    #[fail(display = "peer violated flow control")]
+    FlowControlViolation,
+
+    #[fail(display = "frame of {} bytes does not fit into a packet for the path mtu of {}", size, mtu)]
+    FrameTooBig { size: usize, mtu: usize },
+
+    #[fail(display = "packet of {} bytes exceeds the path mtu of {}", size, mtu)]
+    PacketTooBig { size: usize, mtu: usize },
+
+    #[fail(display = "{} is not supported by wire version {} of the peer", what, version)]
+    UnsupportedByPeer { what: &'static str, version: u8 },
+}
+
+/// a snapshot of a channel's path and traffic, see Channel::stats
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStats {
+    pub recovery:         recovery::RecoveryStats,
+    /// current path mtu
+    pub mtu:              usize,
+    /// packets and their bytes on the wire, including headers. received only counts authenticated packets
+    pub packets_sent:     u64,
+    pub packets_received: u64,
+    pub bytes_sent:       u64,
+    pub bytes_received:   u64,
+    /// open streams
+    pub streams:          HashMap<u32, StreamStats>,
+    /// sum of all streams that were closed or reset and are no longer listed in streams
+    pub closed:           StreamStats,
+}
+
+/// message bytes of a stream, as queued by the application and as handed out to it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamStats {
+    pub sent:     u64,
+    pub received: u64,
 }
 
 pub struct Channel {
@@ -48,52 +162,238 @@ pub struct Channel {
     //incomming
     replay:   replay::AntiReplay,
     recovery: recovery::QuicRecovery,
+    pmtud:    pmtud::Pmtud,
     streams:  HashMap<u32, stream::OrderedStream>,
     gone:     bool,
 
+    datagrams: VecDeque<Bytes>,
+
+    /// the last path challenge in a received packet, answered by path_response
+    path_challenge: Option<u64>,
+    /// path responses received from the peer, to be handed out by progress
+    path_responses: VecDeque<u64>,
+
+    /// resets received from the peer, to be handed out by progress
+    resets:        VecDeque<(u32, u32)>,
+    /// streams that have been reset. frames for them are ignored until the entry expires
+    reset_streams: HashMap<u32, ResetStream>,
+
+    /// persistent hpack context for headers from the peer
+    header_decoder:   HeaderDecoder,
+    /// sequence number of the next header block to decode
+    recv_header_seq:  u32,
+    /// header blocks and fragments of them that arrived ahead of recv_header_seq,
+    /// with their stream and whether they end a block
+    pending_headers:  HashMap<u32, (u32, Bytes, bool)>,
+    /// the fragments of the header block being decoded, until the frame that ends it
+    header_fragments: Vec<u8>,
+    /// decoded headers, until the stream hands them out in order
+    received_headers: HashMap<u32, Headers>,
+
     //outgoing
     counters: HashMap<u32, u64>,
     outqueue: VecDeque<Frame>,
+    /// datagrams are sent before anything in the outqueue but acks, so they never wait behind retransmissions
+    send_datagrams: VecDeque<Frame>,
+
+    /// persistent hpack context for headers to the peer.
+    /// only used once the peer advertised a table size, older peers get self contained header blocks
+    header_encoder:  Option<HeaderEncoder>,
+    /// sequence number of the next header block we encode
+    send_header_seq: u32,
+
+    /// outgoing stream frames, scheduled by priority and round robin
+    send_streams: HashMap<u32, SendStream>,
+    /// stream ids in round robin order, the next to be served first
+    schedule:     VecDeque<u32>,
+    /// the stream that last received a message, for round robin on the receiving side
+    last_recv:    u32,
+    send_window: flow::SendWindow,
+    recv_window: flow::RecvWindow,
+
+    /// packets received since the last ack was sent
+    pending_acks: Vec<u64>,
+    /// receive time of the largest pending packet, for ack delay
+    ack_time:     u64,
+    /// ECN codepoints of all packets received so far, echoed in every ack
+    ecn_counts:   EcnCounts,
+    /// codepoint of the last packet sent
+    send_ecn:     Ecn,
 
     sleeping:   bool,
     idle_time:  u64,
     deadline:   u64,
     last_seen:  u64,
 
-    #[cfg(not(target_arch = "wasm32"))]
-    basetime: Instant,
+    /// when the last authenticated packet arrived
+    last_heard:        u64,
+    dead_peer_timeout: u64,
+    timed_out:         bool,
+
+    /// the peer sent more than we gave it credit for. the channel is unusable
+    violated: bool,
+
+    /// send exactly one packet every this many milliseconds
+    constant_rate: Option<u64>,
+    /// when constant rate may send the next packet
+    next_slot:     u64,
+
+    /// counters for stats. the recovery part is filled in when taking a snapshot
+    stats: ChannelStats,
+
+    rekey_time:     u64,
+    rekey_counter:  u64,
+
+    max_message_size: usize,
+
+    /// credit for each stream. unlimited for peers without flow control
+    stream_window: u64,
+
+    clock: Box<Clock + Send>,
+}
+
+/// a stream that has been reset by either side
+#[derive(Clone, Copy)]
+struct ResetStream {
+    /// if we reset first, the bytes we received and the credit we gave, until the peer tells us its final size
+    pending: Option<(u64, u64)>,
+    /// when the final sizes have been exchanged and late frames no longer need to be ignored
+    expires: u64,
+}
+
+/// lower values are more urgent, like the urgency in RFC 9218
+pub const DEFAULT_PRIORITY: u8 = 3;
+
+/// outgoing frames of a stream, waiting to be scheduled into a packet
+struct SendStream {
+    window:   flow::SendWindow,
+    queue:    VecDeque<Frame>,
+    priority: u8,
+    removed:  bool,
+}
+
+impl SendStream {
+    fn new(window: u64) -> Self {
+        Self {
+            window:   flow::SendWindow::new(window),
+            queue:    VecDeque::new(),
+            priority: DEFAULT_PRIORITY,
+            removed:  false,
+        }
+    }
 }
 
 pub enum ChannelProgress {
     Later(Duration),
     SendPacket(Vec<u8>),
-    ReceiveHeader(u32, Vec<u8>),
-    ReceiveStream(u32, Vec<u8>),
+    ReceiveHeader(u32, Headers),
+    ReceiveStream(u32, Bytes),
+    ReceiveDatagram(Bytes),
+    Reset(u32, u32),
+    /// the peer answered the path challenge with this data
+    PathResponse(u64),
     Close(u32),
     Disconnect,
+    /// the peer was not heard from for the dead peer timeout. the channel is unusable
+    Timeout,
 }
 
 impl Channel {
     pub fn new<S: Into<String>>(noise: noise::Transport, debug_id: S) -> Self {
+        Self::with_clock(noise, debug_id, Box::new(clock::SystemClock::new()))
+    }
+
+    /// a channel that reads time from clock instead of the system clock
+    pub fn with_clock<S: Into<String>>(noise: noise::Transport, debug_id: S, clock: Box<Clock + Send>) -> Self {
+        let now = clock.now();
+        let pmtud = pmtud::Pmtud::new();
+        let mut recovery = recovery::QuicRecovery::new();
+        recovery.set_mss(pmtud.current() as u64);
+
+        // tell the peer we keep an hpack context for its headers
+        let extended = noise.version() >= packet::EXTENDED_VERSION;
+        let mut outqueue = VecDeque::new();
+        if extended {
+            outqueue.push_back(Frame::Config {
+                timeout:           None,
+                sleeping:          false,
+                header_table_size: Some(headers::DEFAULT_TABLE_SIZE),
+            });
+        }
+
+        // older peers neither send nor expect flow control credit
+        let (stream_window, channel_window) = if extended {
+            (flow::DEFAULT_STREAM_WINDOW, flow::DEFAULT_CHANNEL_WINDOW)
+        } else {
+            (flow::UNLIMITED_WINDOW, flow::UNLIMITED_WINDOW)
+        };
+
         Channel {
             debug_id: debug_id.into(),
             noise:    noise,
 
             replay:   replay::AntiReplay::new(),
-            recovery: recovery::QuicRecovery::new(),
+            recovery: recovery,
+            pmtud:    pmtud,
             streams:  HashMap::new(),
             gone:     false,
 
+            datagrams: VecDeque::new(),
+
+            path_challenge: None,
+            path_responses: VecDeque::new(),
+
+            resets:        VecDeque::new(),
+            reset_streams: HashMap::new(),
+
+            header_decoder:   HeaderDecoder::new(headers::DEFAULT_TABLE_SIZE),
+            recv_header_seq:  0,
+            pending_headers:  HashMap::new(),
+            header_fragments: Vec::new(),
+            received_headers: HashMap::new(),
+
             counters: HashMap::new(),
-            outqueue: VecDeque::new(),
+            outqueue: outqueue,
+            send_datagrams: VecDeque::new(),
+
+            header_encoder:  None,
+            send_header_seq: 0,
+
+            send_streams: HashMap::new(),
+            schedule:     VecDeque::new(),
+            last_recv:    0,
+            send_window: flow::SendWindow::new(channel_window),
+            recv_window: flow::RecvWindow::new(channel_window),
+
+            pending_acks: Vec::new(),
+            ack_time:     0,
+            ecn_counts:   EcnCounts::default(),
+            send_ecn:     Ecn::NotEct,
 
             sleeping:   false,
             idle_time:  DEFAULT_IDLE_TIMER,
             deadline:   DEFAULT_IDLE_TIMER,
             last_seen:  0,
 
-            #[cfg(not(all(target_arch = "wasm32")))]
-            basetime: Instant::now(),
+            last_heard:        now,
+            dead_peer_timeout: DEFAULT_DEAD_PEER_TIMEOUT,
+            timed_out:         false,
+
+            violated: false,
+
+            constant_rate: None,
+            next_slot:     0,
+
+            stats: ChannelStats::default(),
+
+            rekey_time:     0,
+            rekey_counter:  0,
+
+            max_message_size: stream::DEFAULT_MAX_MESSAGE_SIZE,
+
+            stream_window,
+
+            clock,
         }
     }
 
@@ -105,25 +405,69 @@ impl Channel {
         self.recovery.window()
     }
 
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            recovery: self.recovery.stats(),
+            mtu: self.pmtud.current(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// streams still remembered as reset
+    #[cfg(test)]
+    pub fn reset_streams(&self) -> usize {
+        self.reset_streams.len()
+    }
+
+    /// flow control accounting of the whole channel
+    #[cfg(test)]
+    pub fn recv_window(&self) -> &flow::RecvWindow {
+        &self.recv_window
+    }
+
+    fn count_sent(&mut self, pkt: &[u8], ecn: Ecn) {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += pkt.len() as u64;
+        self.send_ecn = ecn;
+    }
+
+    /// the ECN codepoint to send the packet this channel created last with.
+    /// packets are only marked while ECN validation has not failed
+    pub fn ecn(&self) -> Ecn {
+        self.send_ecn
+    }
+
     pub fn is_initiator(&self) -> bool {
         self.noise.is_initiator()
     }
 
-    fn now(&self) -> u64 {
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            let elapsed = self.basetime.elapsed();
-            elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
-        }
-        #[cfg(target_arch = "wasm32")]
-        {
-            performance.now() as u64
-        }
+    /// the negotiated wire version
+    pub fn version(&self) -> u8 {
+        self.noise.version()
+    }
+
+    /// true if the peer speaks the frames added in packet::EXTENDED_VERSION
+    fn extended(&self) -> bool {
+        self.version() >= packet::EXTENDED_VERSION
+    }
+
+    /// limit the size of messages sent and received on this channel.
+    /// only applies to streams opened after this call
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
 
+    fn now(&self) -> u64 {
+        self.clock.now()
     }
 
     /// receive a packet from the wire
     pub fn recv(&mut self, pkt: EncryptedPacket) -> Result<(), Error> {
+        self.recv_with_ecn(pkt, Ecn::NotEct)
+    }
+
+    /// receive a packet from the wire, along with the ECN codepoint of the IP packet that carried it
+    pub fn recv_with_ecn(&mut self, pkt: EncryptedPacket, ecn: Ecn) -> Result<(), Error> {
         let now = self.now();
         trace!(
             "[{}] incomming pkt {} with {} bytes at {}",
@@ -135,23 +479,61 @@ impl Channel {
         self.last_seen = now;
 
         let counter = pkt.counter;
+        let size = packet::HEADER_SIZE + pkt.payload.len();
 
         if !self.replay.within_window(counter) {
             return Err(ChannelError::AntiReplay.into());
         }
 
+        let epoch = self.noise.epoch();
         let pkt = self.noise.recv(pkt)?;
-        let frames = Frame::decode(pkt.as_slice())?;
+        let frames = Frame::decode(self.noise.version(), pkt)?;
 
         // packet authenticated from here
 
         self.replay.update_window(counter);
+        self.ecn_counts.count(ecn);
+        self.last_heard = now;
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += size as u64;
+
+        if self.noise.epoch() != epoch {
+            self.switched_keys(now);
+        }
 
         let mut ackonly = true;
         for frame in frames {
             ackonly = ackonly && frame.is_ack();
+
+            // decoded even for reset streams, or the hpack context gets out of sync
+            if let Frame::Header { stream, seq: Some(seq), payload } = frame {
+                self.recv_sequenced_header(stream, seq, payload, true)?;
+                continue;
+            }
+            if let Frame::HeaderFragment { stream, seq, payload } = frame {
+                self.recv_sequenced_header(stream, seq, payload, false)?;
+                continue;
+            }
+
+            if let Some(stream) = frame.stream_id() {
+                if let Some(reset) = self.reset_streams.get(&stream).cloned() {
+                    if let Frame::Reset { final_size, .. } = frame {
+                        if let Some((received, limit)) = reset.pending {
+                            // the peer answered our reset with its final size
+                            self.account_final_size(stream, received, received, limit, final_size)?;
+                            self.reset_streams.insert(stream, ResetStream {
+                                pending: None,
+                                expires: now + RESET_LINGER_TIME,
+                            });
+                        }
+                    }
+                    trace!("[{}] ignoring {} for reset stream {}", self.debug_id, frame.name(), stream);
+                    continue;
+                }
+            }
+
             match frame {
-                Frame::Header { stream, payload } => {
+                Frame::Header { stream, seq, payload } => {
                     trace!("[{}] received header for stream {}", self.debug_id, stream);
 
                     if !self.streams.contains_key(&stream) && self.streams.len() > 1024 {
@@ -160,9 +542,13 @@ impl Channel {
                         return Ok(());
                     }
 
-                    let ordered = self.streams.entry(stream).or_insert(stream::OrderedStream::new());
-                    ordered.push(Frame::Header { stream, payload })?;
+                    let ordered = self
+                        .streams
+                        .entry(stream)
+                        .or_insert(stream::OrderedStream::with_limits(self.max_message_size, self.stream_window));
+                    ordered.push(Frame::Header { stream, seq, payload })?;
                 }
+                Frame::HeaderFragment { .. } => unreachable!("decoded before checking for reset streams"),
                 Frame::Stream { stream, order, payload } => {
                     trace!("[{}] received message {}", self.debug_id, order);
 
@@ -171,15 +557,49 @@ impl Channel {
                         return Ok(());
                     }
 
-                    let ordered = self.streams.entry(stream).or_insert(stream::OrderedStream::new());
-                    ordered.push(Frame::Stream { stream, order, payload })?;
+                    let ordered = self
+                        .streams
+                        .entry(stream)
+                        .or_insert(stream::OrderedStream::with_limits(self.max_message_size, self.stream_window));
+                    let charge = ordered.push(Frame::Stream { stream, order, payload })?;
+                    self.recv_window.on_recv(charge).map_err(|e| self.violation(e))?;
+                }
+                Frame::Fragment { stream, order, payload } => {
+                    trace!("[{}] received fragment {}", self.debug_id, order);
+
+                    if !self.streams.contains_key(&stream) && self.streams.len() > 1024 {
+                        error!("[{}] excessive number of streams", self.debug_id);
+                        return Ok(());
+                    }
+
+                    // fragments are credited to the peer before the message is complete,
+                    // so the windows alone do not bound how much all streams together hold for reassembly
+                    let reassembling: usize = self.streams.values().map(|s| s.fragment_bytes()).sum();
+                    if reassembling + payload.len() > self.max_message_size {
+                        warn!("[{}] resetting stream {}, reassembly exceeds {} bytes",
+                              self.debug_id, stream, self.max_message_size);
+                        self.reset(stream, RESET_REASSEMBLY_LIMIT);
+                        self.resets.push_back((stream, RESET_REASSEMBLY_LIMIT));
+                        continue;
+                    }
+
+                    let ordered = self
+                        .streams
+                        .entry(stream)
+                        .or_insert(stream::OrderedStream::with_limits(self.max_message_size, self.stream_window));
+                    let charge = ordered.push(Frame::Fragment { stream, order, payload })?;
+                    self.recv_window.on_recv(charge).map_err(|e| self.violation(e))?;
                 }
                 Frame::Disconnect => {
                     trace!("[{}] disconnected", self.debug_id);
                     self.gone = true;
                 }
-                Frame::Ack { delay, acked } => {
-                    let loss = self.recovery.on_ack_received(delay, acked.clone(), now);
+                Frame::Ack { delay, acked, ecn } => {
+                    if self.pmtud.on_ack(&acked, now) {
+                        debug!("[{}] path mtu is now {}", self.debug_id, self.pmtud.current());
+                        self.recovery.set_mss(self.pmtud.current() as u64);
+                    }
+                    let loss = self.recovery.on_ack_received(delay, acked.clone(), ecn, now);
 
                     trace!("[{}] received ack {:?} RTT is now {}. Lost packets: {:?}",
                            self.debug_id, acked, self.recovery.smoothed_rtt, loss);
@@ -196,32 +616,182 @@ impl Channel {
                         error!("[{}] excessive number of streams", self.debug_id);
                         return Ok(());
                     }
-                    let ordered = self.streams.entry(stream).or_insert(stream::OrderedStream::new());
+                    let ordered = self
+                        .streams
+                        .entry(stream)
+                        .or_insert(stream::OrderedStream::with_limits(self.max_message_size, self.stream_window));
                     ordered.push(Frame::Close { stream, order })?;
                 }
-                Frame::Config{timeout, sleeping} => {
+                Frame::Config{timeout, sleeping, header_table_size} => {
                     if let Some(seconds) = timeout {
                         debug!("peer set timeout to {} seconds", seconds);
                         self.idle_time = seconds as u64 * 1000;
                     }
+                    if let Some(size) = header_table_size {
+                        debug!("[{}] peer accepts an hpack table of {} bytes", self.debug_id, size);
+                        match self.header_encoder {
+                            Some(ref mut encoder) => encoder.set_max_size(size),
+                            None => self.header_encoder = Some(HeaderEncoder::new(size)),
+                        }
+                    }
                     self.sleeping = sleeping;
                     if sleeping {
                         warn!("peer has indicated it is sleeping or unresponsive for {}ms", self.idle_time);
                     }
                 }
+                Frame::Datagram { payload } => {
+                    trace!("[{}] received datagram with {} bytes", self.debug_id, payload.len());
+                    if self.datagrams.len() >= MAX_DATAGRAM_QUEUE {
+                        trace!("[{}] dropping stale datagram", self.debug_id);
+                        self.datagrams.pop_front();
+                    }
+                    self.datagrams.push_back(payload);
+                }
+                Frame::Reset { stream, code, final_size } => {
+                    debug!("[{}] peer reset stream {} with code {}", self.debug_id, stream, code);
+                    if !self.knows_stream(stream)
+                        && (!self.peer_may_open(stream) || self.reset_streams.len() >= MAX_RESET_STREAMS)
+                    {
+                        debug!("[{}] ignoring reset of unknown stream {}", self.debug_id, stream);
+                        continue;
+                    }
+                    let (received, consumed, limit) = match self.streams.get(&stream) {
+                        None => (0, 0, self.stream_window),
+                        Some(ordered) => {
+                            let window = ordered.window();
+                            (window.received(), window.consumed(), window.limit())
+                        }
+                    };
+                    self.account_final_size(stream, received, consumed, limit, final_size)?;
+
+                    let our_final_size = self.discard_stream(stream);
+                    self.reset_streams.insert(stream, ResetStream {
+                        pending: None,
+                        expires: now + RESET_LINGER_TIME,
+                    });
+                    self.outqueue.push_back(Frame::Reset {
+                        stream,
+                        code,
+                        final_size: our_final_size,
+                    });
+                    self.resets.push_back((stream, code));
+                }
+                Frame::PathChallenge { data } => {
+                    trace!("[{}] received path challenge {:x}", self.debug_id, data);
+                    self.path_challenge = Some(data);
+                }
+                Frame::PathResponse { data } => {
+                    trace!("[{}] received path response {:x}", self.debug_id, data);
+                    self.path_responses.push_back(data);
+                }
+                Frame::MaxData { limit } => {
+                    trace!("[{}] peer raised channel limit to {}", self.debug_id, limit);
+                    self.send_window.on_limit(limit);
+                }
+                Frame::MaxStreamData { stream, limit } => {
+                    trace!("[{}] peer raised limit of stream {} to {}", self.debug_id, stream, limit);
+                    if let Some(send) = self.send_streams.get_mut(&stream) {
+                        send.window.on_limit(limit);
+                    }
+                }
+                Frame::Rekey { epoch, response, payload } => {
+                    trace!("[{}] received rekey for epoch {}", self.debug_id, epoch);
+                    let current = self.noise.epoch();
+                    match self.noise.rekey_recv(epoch, response, &payload) {
+                        Ok(Some(payload)) => {
+                            self.outqueue.push_back(Frame::Rekey {
+                                epoch,
+                                response: true,
+                                payload: payload.into(),
+                            });
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("[{}] rejected rekey: {}", self.debug_id, e),
+                    }
+                    if self.noise.epoch() != current {
+                        self.switched_keys(now);
+                    }
+                }
             }
         }
 
         if !ackonly {
-            self.outqueue.push_back(Frame::Ack {
-                delay: now,
-                acked: vec![counter],
-            });
+            if self.pending_acks.iter().all(|pending| *pending < counter) {
+                self.ack_time = now;
+            }
+            self.pending_acks.push(counter);
+        }
+
+        Ok(())
+    }
+
+    /// header blocks of the persistent hpack context are decoded in the order they were encoded,
+    /// no matter which stream they belong to. last is false for the fragments before the end of a block
+    fn recv_sequenced_header(&mut self, stream: u32, seq: u32, payload: Bytes, last: bool) -> Result<(), Error> {
+        if seq < self.recv_header_seq {
+            trace!("[{}] DUP header block {}", self.debug_id, seq);
+            return Ok(());
         }
+        if seq - self.recv_header_seq >= MAX_HEADER_REORDERING {
+            return Err(ChannelError::HeaderTooFarAhead {
+                expected: self.recv_header_seq,
+                this:     seq,
+            }.into());
+        }
+        self.pending_headers.insert(seq, (stream, payload, last));
+
+        while let Some((stream, payload, last)) = self.pending_headers.remove(&self.recv_header_seq) {
+            let seq = self.recv_header_seq;
+            self.recv_header_seq += 1;
+
+            let size = self.header_fragments.len() + payload.len();
+            if size > MAX_HEADER_SIZE {
+                return Err(ChannelError::HeaderTooBig { size, max: MAX_HEADER_SIZE }.into());
+            }
+            if !last {
+                self.header_fragments.extend_from_slice(&payload);
+                continue;
+            }
+            let payload = if self.header_fragments.is_empty() {
+                payload
+            } else {
+                self.header_fragments.extend_from_slice(&payload);
+                Bytes::from(mem::replace(&mut self.header_fragments, Vec::new()))
+            };
+
+            // the peer's encoder already indexed this block, so failing to decode it breaks every later one
+            let headers = self.header_decoder.decode(&payload)?;
+            trace!("[{}] received header block {} for stream {}", self.debug_id, seq, stream);
+
+            if self.reset_streams.contains_key(&stream) {
+                trace!("[{}] ignoring header for reset stream {}", self.debug_id, stream);
+                continue;
+            }
+            if !self.streams.contains_key(&stream) && self.streams.len() > 1024 {
+                error!("[{}] excessive number of streams", self.debug_id);
+                continue;
+            }
 
+            let ordered = self
+                .streams
+                .entry(stream)
+                .or_insert(stream::OrderedStream::with_limits(self.max_message_size, self.stream_window));
+            ordered.push(Frame::Header {
+                stream,
+                seq: Some(seq),
+                payload: Bytes::new(),
+            })?;
+            self.received_headers.insert(stream, headers);
+        }
         Ok(())
     }
 
+    fn switched_keys(&mut self, now: u64) {
+        debug!("[{}] switched to key epoch {}", self.debug_id, self.noise.epoch());
+        self.rekey_time = now;
+        self.rekey_counter = self.noise.counter();
+    }
+
     pub fn handle_loss(&mut self, loss: recovery::LossDetection) {
         match loss {
             recovery::LossDetection::None => (),
@@ -236,7 +806,7 @@ impl Channel {
                 );
 
                 for frame in lost {
-                    if !frame.is_ack() && !frame.is_ping() {
+                    if !frame.is_ack() && !frame.is_ping() && !frame.is_datagram() {
                         self.outqueue.push_back(frame);
                     }
                 }
@@ -249,6 +819,11 @@ impl Channel {
                        .collect::<Vec<&'static str>>()
                        .join(",")
                       );
+                let re: Vec<Frame> = re.into_iter().filter(|frame| !frame.is_datagram()).collect();
+                if re.is_empty() {
+                    // datagrams are never retransmitted, but we still need to probe
+                    self.outqueue.push_back(Frame::Ping);
+                }
                 for frame in re {
                     self.outqueue.push_back(frame);
                 }
@@ -261,19 +836,28 @@ impl Channel {
                     self.recovery.bytes_in_flight(),
                     self.now(),
                 );
+                // the path may have started dropping larger packets
+                if self.pmtud.on_black_hole() {
+                    debug!("[{}] path mtu fell back to {}", self.debug_id, self.pmtud.current());
+                    self.recovery.set_mss(self.pmtud.current() as u64);
+                }
+                let re: Vec<Frame> = re.into_iter().filter(|frame| !frame.is_datagram()).collect();
+                if re.is_empty() {
+                    self.outqueue.push_front(Frame::Ping);
+                }
                 for frame in re {
                     self.outqueue.push_front(frame);
                 }
             }
             recovery::LossDetection::Unrecoverable => {
                 warn!("[{}] connection is unrecoverable", self.debug_id);
-                self.gone = true;
+                self.timed_out = true;
             }
         }
     }
 
     /// progress the channel and return something that happened
-    /// this needs to be polled until it returns Later or Disconnectd
+    /// this needs to be polled until it returns Later, Disconnect or Timeout
     pub fn progress(&mut self) -> Result<ChannelProgress, Error> {
         let now = self.now();
 
@@ -287,6 +871,12 @@ impl Channel {
         );
 
 
+        // a sleeping peer must still be heard from eventually. the deadline grows with the idle time it asked for
+        if now > self.dead_peer_deadline() && !self.timed_out {
+            warn!("[{}] peer was not heard from since {}", self.debug_id, self.last_heard);
+            self.timed_out = true;
+        }
+
         if self.sleeping  {
             if now > self.last_seen + self.idle_time {
                 self.sleeping = false;
@@ -307,16 +897,44 @@ impl Channel {
                 self.last_seen = now;
             }
 
-            if now >= self.deadline && self.recovery.bytes_in_flight() > 0 {
+            if self.extended() && (now > self.rekey_time + REKEY_AFTER_TIME
+                || self.noise.counter() > self.rekey_counter + REKEY_AFTER_PACKETS)
+            {
+                if let Some((epoch, payload)) = self.noise.rekey_request(now)? {
+                    debug!("[{}] requesting rekey for epoch {}", self.debug_id, epoch);
+                    self.outqueue.push_back(Frame::Rekey {
+                        epoch,
+                        response: false,
+                        payload: payload.into(),
+                    });
+                }
+            }
+
+            if now > self.rekey_time + REKEY_GRACE_TIME {
+                self.noise.expire_previous();
+            }
+
+            // other timers share the deadline, so check that it is the loss detection alarm that expired
+            let alarm_expired = self.recovery.loss_detection_alarm().map(|alarm| now >= alarm).unwrap_or(false);
+            if alarm_expired && self.recovery.bytes_in_flight() > 0 {
                 let loss = self.recovery.on_loss_detection_alarm(now);
                 self.handle_loss(loss);
             }
 
+            self.pmtud.on_timer(now);
+
             self.deadline = if let Some(deadline) = self.recovery.loss_detection_alarm() {
                 deadline
             } else {
                 self.last_seen + self.idle_time
             };
+            if let Some(deadline) = self.pmtud.deadline() {
+                self.deadline = min(self.deadline, deadline);
+            }
+            if self.constant_rate.is_some() {
+                self.deadline = min(self.deadline, self.next_slot);
+            }
+            self.deadline = min(self.deadline, self.dead_peer_deadline() + 1);
             if self.deadline <= now {
                 trace!(
                     "[{}] upcoming deadline {} already expired at {}",
@@ -327,39 +945,123 @@ impl Channel {
 
         }
 
-        // send out packets
-        if !self.outqueue.is_empty() {
-            let mut frames = Vec::new();
-            let mut pkt = Vec::new();
-            loop {
-                let more = match self.outqueue.front() {
-                    None => break,
-                    Some(v) => v.len(),
-                };
+        if self.timed_out {
+            return Ok(ChannelProgress::Timeout);
+        }
+
+        if self.violated {
+            return Err(ChannelError::FlowControlViolation.into());
+        }
+
+        // forget reset streams once late frames for them can no longer arrive
+        self.reset_streams.retain(|_, reset| reset.expires > now);
 
-                let overhead = 36;
-                let morelen = pkt.len() + overhead + more;
-                let morelen = morelen + (256 - (morelen % 256));
+        // forget removed streams once everything they queued has been sent
+        {
+            let send_streams = &mut self.send_streams;
+            send_streams.retain(|_, send| !send.removed || !send.queue.is_empty());
+            self.schedule.retain(|id| send_streams.contains_key(id));
+        }
+
+        // with a constant rate, nothing at all is sent before the next slot
+        let slot = self.constant_rate.is_none() || now >= self.next_slot;
+
+        // acks for everything received so far go into the next packet
+        if slot && !self.pending_acks.is_empty() {
+            let mut acked = AckRange::from_counters(&self.pending_acks);
+            acked.truncate(MAX_ACK_RANGES);
+            self.pending_acks.clear();
+            let ecn = if self.ecn_counts.is_empty() { None } else { Some(self.ecn_counts) };
+            self.outqueue.push_front(Frame::Ack {
+                delay: self.ack_time,
+                acked,
+                ecn,
+            });
+        }
 
-                if morelen >= MAX_PACKET_SIZE {
-                    break;
+        // probe for a larger path mtu, if the congestion window allows it.
+        // the probe is not tracked by recovery, so losing it does not count as congestion
+        if let Some(size) = self.pmtud.probe() {
+            if !self.sleeping && self.recovery.window() >= size {
+                let mut pkt = Vec::new();
+                Frame::Ping.encode(&mut pkt)?;
+                let padding = pmtud::frames_capacity(size, self.noise.padding()) - pkt.len();
+                pkt.extend_from_slice(&vec![0; padding]);
+
+                let pkt = self.noise.send(&pkt)?;
+                trace!("[{}] sending path mtu probe {} of {} bytes", self.debug_id, pkt.counter, size);
+                self.pmtud.on_probe_sent(pkt.counter, now, self.recovery.smoothed_rtt);
+
+                let pkt = pkt.encode();
+                if pkt.len() > size {
+                    return Err(ChannelError::PacketTooBig { size: pkt.len(), mtu: size }.into());
                 }
-                let mut frame = self.outqueue.pop_front().unwrap();
-                if let Frame::Ack { acked, delay } = frame {
-                    frame = Frame::Ack {
-                        acked,
-                        delay: now - delay,
-                    };
+                let ecn = self.recovery.ecn();
+                self.count_sent(&pkt, ecn);
+                return Ok(ChannelProgress::SendPacket(pkt));
+            }
+        }
+
+        // send out packets.
+        // acks first, then datagrams, then control frames and retransmissions, then stream data
+        let mtu = self.pmtud.current();
+        let padding = self.noise.padding();
+        let version = self.version();
+        let mut frames = Vec::new();
+        let mut pkt = Vec::new();
+        while slot {
+            let ack_first = self.outqueue.front().map(|v| v.is_ack()).unwrap_or(false);
+            let queue = if ack_first || self.send_datagrams.is_empty() {
+                &mut self.outqueue
+            } else {
+                &mut self.send_datagrams
+            };
+            let more = queue.front().map(|v| v.len_for(version));
+            let mut frame = match more {
+                Some(more) => {
+                    if !fits_packet(pkt.len(), more, mtu, padding) {
+                        if frames.is_empty() {
+                            // would block the queue forever
+                            queue.pop_front();
+                            return Err(ChannelError::FrameTooBig { size: more, mtu }.into());
+                        }
+                        break;
+                    }
+                    queue.pop_front().unwrap()
                 }
-                frame.encode(&mut pkt)?;
-                frames.push(frame);
+                None => match self.next_stream_frame(pkt.len()) {
+                    Some(frame) => frame,
+                    None => break,
+                },
+            };
+            if let Frame::Ack { acked, delay, ecn } = frame {
+                frame = Frame::Ack {
+                    acked,
+                    delay: now - delay,
+                    ecn,
+                };
+            }
+            frame.encode_for(version, &mut pkt)?;
+            frames.push(frame);
+        }
+
+        // fill the slot with a ping if nothing is queued, and pad every packet to the same size
+        if let (true, Some(interval)) = (slot, self.constant_rate) {
+            if frames.is_empty() {
+                Frame::Ping.encode_for(version, &mut pkt)?;
+                frames.push(Frame::Ping);
+            }
+            let fill = pmtud::frames_capacity(mtu, padding) - pkt.len();
+            pkt.extend_from_slice(&vec![0; fill]);
+            // stay on the grid of slots, unless we missed a whole one
+            self.next_slot = if self.next_slot + interval > now { self.next_slot + interval } else { now + interval };
+        }
+
+        if !frames.is_empty() {
+            let size = pmtud::packet_size(pkt.len(), padding);
+            if size > mtu {
+                return Err(ChannelError::PacketTooBig { size, mtu }.into());
             }
-            assert_ne!(
-                frames.len(),
-                0,
-                "bug: trying to send empty packet. outqueue is {}",
-                self.outqueue.len()
-            );
 
             let pkt = self.noise.send(&pkt)?;
 
@@ -376,23 +1078,61 @@ impl Channel {
                     .join(",")
             );
 
-            self.recovery.on_packet_sent(pkt.counter, frames, now);
+            let ecn = self.recovery.on_packet_sent(pkt.counter, frames, now);
 
             let pkt = pkt.encode();
-            assert!(pkt.len() < MAX_PACKET_SIZE);
+            self.count_sent(&pkt, ecn);
             return Ok(ChannelProgress::SendPacket(pkt));
         }
 
+        // datagrams are not ordered, so they are handed out before any stream data
+        if let Some(payload) = self.datagrams.pop_front() {
+            return Ok(ChannelProgress::ReceiveDatagram(payload));
+        }
+
+        if let Some((stream, code)) = self.resets.pop_front() {
+            return Ok(ChannelProgress::Reset(stream, code));
+        }
+
+        if let Some(data) = self.path_responses.pop_front() {
+            return Ok(ChannelProgress::PathResponse(data));
+        }
+
         // receive assembled messages
-        // TODO this unintentionally gives priority to earlier streams,
-        // since the streams first in the hash are always polled first.
-        for (_id, stream) in &mut self.streams {
-            if let Some(msg) = stream.pop() {
+        // round robin, starting after the stream that received the last message
+        let mut ids: Vec<u32> = self.streams.keys().cloned().collect();
+        ids.sort_unstable();
+        let start = ids.iter().position(|id| *id > self.last_recv).unwrap_or(0);
+        for i in 0..ids.len() {
+            let id = ids[(start + i) % ids.len()];
+            let stream = self.streams.get_mut(&id).unwrap();
+            let msg = stream.pop();
+
+            self.recv_window.on_consumed(stream.take_consumed());
+            if let Some(limit) = stream.window_update() {
+                self.outqueue.push_back(Frame::MaxStreamData { stream: id, limit });
+            }
+            if let Some(limit) = self.recv_window.update() {
+                self.outqueue.push_back(Frame::MaxData { limit });
+            }
+
+            if let Some(msg) = msg {
+                self.last_recv = id;
                 match msg {
-                    Frame::Header { stream, payload, .. } => {
-                        return Ok(ChannelProgress::ReceiveHeader(stream, payload));
+                    Frame::Header { stream, seq: Some(_), .. } => {
+                        let headers = self.received_headers.remove(&stream).unwrap_or_default();
+                        return Ok(ChannelProgress::ReceiveHeader(stream, headers));
                     }
+                    Frame::Header { stream, payload, .. } => match Headers::decode(&payload) {
+                        Ok(headers) => return Ok(ChannelProgress::ReceiveHeader(stream, headers)),
+                        Err(e) => {
+                            warn!("[{}] invalid header on stream {}: {}", self.debug_id, stream, e);
+                            self.close(stream);
+                        }
+                    },
                     Frame::Stream { stream, payload, .. } => {
+                        self.stats.streams.entry(stream).or_insert_with(StreamStats::default).received +=
+                            payload.len() as u64;
                         return Ok(ChannelProgress::ReceiveStream(stream, payload));
                     }
                     Frame::Close { stream, .. } => {
@@ -410,25 +1150,130 @@ impl Channel {
         Ok(ChannelProgress::Later(Duration::from_millis(self.deadline - now)))
     }
 
-    /// queue a message
-    pub fn stream<M: Into<Vec<u8>>>(&mut self, stream: u32, msg: M) {
-        let order = self.counters.entry(stream).or_insert(0);
-        *order += 1;
-        let order = *order;
+    /// the next stream frame that fits into a packet of pkt_len bytes and that the peer gave us credit for.
+    /// the most urgent streams go first, streams of equal priority take turns.
+    fn next_stream_frame(&mut self, pkt_len: usize) -> Option<Frame> {
+        let mtu = self.pmtud.current();
+        let padding = self.noise.padding();
+        let version = self.version();
+        let mut best: Option<(usize, u8)> = None;
+        for (i, id) in self.schedule.iter().enumerate() {
+            let send = &self.send_streams[id];
+            let frame = match send.queue.front() {
+                None => continue,
+                Some(frame) => frame,
+            };
+            let charge = flow::charge(frame);
+            if !fits_packet(pkt_len, frame.len_for(version), mtu, padding)
+                || charge > send.window.credit()
+                || charge > self.send_window.credit()
+            {
+                continue;
+            }
+            match best {
+                Some((_, priority)) if priority <= send.priority => (),
+                _ => best = Some((i, send.priority)),
+            }
+        }
+
+        let (i, _) = best?;
+        let id = self.schedule.remove(i).unwrap();
+        self.schedule.push_back(id);
+
+        let send = self.send_streams.get_mut(&id).unwrap();
+        let frame = send.queue.pop_front().unwrap();
+        let charge = flow::charge(&frame);
+        send.window.on_sent(charge);
+        self.send_window.on_sent(charge);
+        Some(frame)
+    }
+
+    /// true if all previous messages on this stream have been sent.
+    /// callers should stop queuing messages on streams that are not writable.
+    pub fn writable(&self, stream: u32) -> bool {
+        self.send_streams.get(&stream).map(|send| send.queue.is_empty()).unwrap_or(true)
+    }
+
+    /// set the priority of a stream we're sending on. lower values are more urgent.
+    /// streams of the same priority share the channel round robin.
+    pub fn set_priority(&mut self, stream: u32, priority: u8) {
+        self.send_stream(stream).priority = priority;
+    }
+
+    fn send_stream(&mut self, stream: u32) -> &mut SendStream {
+        let schedule = &mut self.schedule;
+        let window = self.stream_window;
+        self.send_streams.entry(stream).or_insert_with(|| {
+            schedule.push_back(stream);
+            SendStream::new(window)
+        })
+    }
 
+    /// queue a message
+    /// messages that do not fit into a single packet are sent as fragments and reassembled by the peer.
+    /// peers without fragments only take messages that fit into a single packet
+    pub fn stream<M: Into<Bytes>>(&mut self, stream: u32, msg: M) -> Result<(), Error> {
         let msg = msg.into();
-        assert!(msg.len() < 1200, "message too big {}", msg.len());
-        self.outqueue.push_back(Frame::Stream {
+        let max = if self.extended() { self.max_message_size } else { MAX_FRAGMENT_SIZE };
+        if msg.len() > max {
+            return Err(ChannelError::MessageTooBig { size: msg.len(), max }.into());
+        }
+
+        let mut order = *self.counters.entry(stream).or_insert(0);
+        let mut frames = Vec::new();
+
+        // fragments are slices of the message, nothing is copied
+        let mut offset = 0;
+        while msg.len() - offset > MAX_FRAGMENT_SIZE {
+            order += 1;
+            frames.push(Frame::Fragment {
+                stream:  stream,
+                order:   order,
+                payload: msg.slice(offset, offset + MAX_FRAGMENT_SIZE),
+            });
+            offset += MAX_FRAGMENT_SIZE;
+        }
+
+        order += 1;
+        frames.push(Frame::Stream {
             stream:  stream,
             order:   order,
-            payload: msg.into(),
+            payload: msg.slice_from(offset),
         });
+
+        self.counters.insert(stream, order);
+        self.stats.streams.entry(stream).or_insert_with(StreamStats::default).sent += msg.len() as u64;
+        self.send_stream(stream).queue.extend(frames);
+        Ok(())
+    }
+
+    /// queue an unreliable datagram. it is encrypted like any other frame,
+    /// but never retransmitted and may arrive out of order or not at all.
+    pub fn datagram<M: Into<Bytes>>(&mut self, msg: M) -> Result<(), Error> {
+        if !self.extended() {
+            return Err(ChannelError::UnsupportedByPeer {
+                what:    "datagram",
+                version: self.version(),
+            }.into());
+        }
+        let payload = msg.into();
+        if payload.len() > MAX_DATAGRAM_SIZE {
+            return Err(ChannelError::DatagramTooBig {
+                size: payload.len(),
+                max:  MAX_DATAGRAM_SIZE,
+            }.into());
+        }
+        if self.send_datagrams.len() >= MAX_DATAGRAM_SEND_QUEUE {
+            trace!("[{}] dropping stale outgoing datagram", self.debug_id);
+            self.send_datagrams.pop_front();
+        }
+        self.send_datagrams.push_back(Frame::Datagram { payload });
+        Ok(())
     }
 
     /// open a new stream, given a header
-    pub fn open<M: Into<Vec<u8>>>(&mut self, payload: M, are_we_initiator: bool) -> u32 {
-        let payload = payload.into();
-        assert!(payload.len() < 1200, "message too big {}", payload.len());
+    pub fn open(&mut self, headers: &Headers, are_we_initiator: bool) -> Result<u32, Error> {
+        self.check_header_size(headers)?;
 
         assert!(self.counters.len() < <u32>::max_value() as usize);
 
@@ -440,38 +1285,87 @@ impl Channel {
                 (false, s) if s % 2 == 1 => continue,
                 (_, s) if self.streams.contains_key(&s) => continue,
                 (_, s) if self.counters.contains_key(&s) => continue,
+                (_, s) if self.reset_streams.contains_key(&s) => continue,
                 (_, s) => break s,
             }
         };
 
         self.counters.insert(stream, 1);
 
-        self.outqueue.push_back(Frame::Header {
-            stream:  stream,
-            payload: payload,
-        });
+        let frames = self.header_frames(stream, headers);
+        self.outqueue.extend(frames);
 
-        stream
+        Ok(stream)
     }
 
     /// send headers (as a response)
-    pub fn header<M: Into<Vec<u8>>>(&mut self, stream: u32, payload: M) {
-        let payload = payload.into();
-        assert!(payload.len() < 1200, "message too big {}", payload.len());
+    pub fn header(&mut self, stream: u32, headers: &Headers) -> Result<(), Error> {
+        self.check_header_size(headers)?;
 
         if let Some(_) = self.counters.get(&stream) {
             warn!(
                 "[{}] attempting to send header twice on stream {}",
                 self.debug_id, stream
             );
-            return;
+            return Ok(());
         }
         self.counters.insert(stream, 1);
-        self.outqueue.push_back(Frame::Header { stream, payload });
+        let frames = self.header_frames(stream, headers);
+        self.outqueue.extend(frames);
+        Ok(())
+    }
+
+    /// checked before encoding, because an encoded block changes the hpack context and has to be sent
+    fn check_header_size(&self, headers: &Headers) -> Result<(), Error> {
+        let (size, max) = match self.header_encoder {
+            Some(_) => (HeaderEncoder::max_encoded_len(headers), MAX_HEADER_SIZE),
+            None => (headers.encode().len(), MAX_FRAGMENT_SIZE),
+        };
+        if size > max {
+            return Err(ChannelError::HeaderTooBig { size, max }.into());
+        }
+        Ok(())
+    }
+
+    /// blocks of the persistent hpack context are split into fragments that each fit into a packet,
+    /// with one sequence number per fragment
+    fn header_frames(&mut self, stream: u32, headers: &Headers) -> Vec<Frame> {
+        let block: Bytes = match self.header_encoder {
+            Some(ref mut encoder) => encoder.encode(headers).into(),
+            None => {
+                return vec![Frame::Header {
+                    stream,
+                    seq: None,
+                    payload: headers.encode().into(),
+                }]
+            }
+        };
+
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while block.len() - offset > MAX_FRAGMENT_SIZE {
+            frames.push(Frame::HeaderFragment {
+                stream,
+                seq: self.send_header_seq,
+                payload: block.slice(offset, offset + MAX_FRAGMENT_SIZE),
+            });
+            self.send_header_seq += 1;
+            offset += MAX_FRAGMENT_SIZE;
+        }
+        frames.push(Frame::Header {
+            stream,
+            seq: Some(self.send_header_seq),
+            payload: block.slice_from(offset),
+        });
+        self.send_header_seq += 1;
+        frames
     }
 
     /// queue a close, stream may still be able to receive (this is half close)
     pub fn close(&mut self, stream: u32) {
+        if self.reset_streams.contains_key(&stream) {
+            return;
+        }
         let order = match self.counters.get_mut(&stream) {
             None => {
                 warn!(
@@ -488,21 +1382,198 @@ impl Channel {
             }
         };
 
-        self.outqueue.push_back(Frame::Close { order, stream });
+        self.send_stream(stream).queue.push_back(Frame::Close { order, stream });
+    }
+
+    /// abort a stream in both directions.
+    /// anything queued or in flight for the stream is discarded and the peer is told the error code.
+    /// peers without Reset get a close instead, and the stream is removed
+    pub fn reset(&mut self, stream: u32, code: u32) {
+        if self.reset_streams.contains_key(&stream) {
+            return;
+        }
+        if !self.extended() {
+            self.close(stream);
+            self.remove(stream);
+            return;
+        }
+        let (received, limit) = match self.streams.get(&stream) {
+            None => (0, self.stream_window),
+            Some(ordered) => {
+                // return the credit for data the application will never read
+                let window = ordered.window();
+                self.recv_window.on_consumed(window.received() - window.consumed());
+                (window.received(), window.limit())
+            }
+        };
+
+        let final_size = self.discard_stream(stream);
+        self.reset_streams.insert(stream, ResetStream {
+            pending: Some((received, limit)),
+            expires: u64::max_value(),
+        });
+        self.outqueue.push_back(Frame::Reset {
+            stream,
+            code,
+            final_size,
+        });
+    }
+
+    /// true if either side opened the stream and it is not forgotten yet
+    fn knows_stream(&self, stream: u32) -> bool {
+        self.streams.contains_key(&stream)
+            || self.counters.contains_key(&stream)
+            || self.send_streams.contains_key(&stream)
+            || self.reset_streams.contains_key(&stream)
+    }
+
+    /// move the byte counts of a stream that goes away into the closed totals
+    fn close_stream_stats(&mut self, stream: u32) {
+        if let Some(stats) = self.stats.streams.remove(&stream) {
+            self.stats.closed.sent     += stats.sent;
+            self.stats.closed.received += stats.received;
+        }
+    }
+
+    /// streams opened by the initiator are odd, those opened by the responder even
+    fn peer_may_open(&self, stream: u32) -> bool {
+        stream != 0 && (stream % 2 == 1) != self.is_initiator()
+    }
+
+    /// forget everything about a stream, including frames queued or in flight.
+    /// returns how many bytes we sent on it
+    fn discard_stream(&mut self, stream: u32) -> u64 {
+        self.streams.remove(&stream);
+        self.close_stream_stats(stream);
+        self.received_headers.remove(&stream);
+        self.counters.remove(&stream);
+        self.schedule.retain(|id| *id != stream);
+        self.outqueue.retain(|frame| frame.stream_id() != Some(stream) || frame.is_sequenced_header());
+        self.recovery.discard_stream(stream);
+        match self.send_streams.remove(&stream) {
+            None => 0,
+            Some(send) => send.window.sent(),
+        }
+    }
+
+    /// the peer will not send anything more than final_size on a reset stream.
+    /// account for data that never arrived, so the channel window stays in sync with the peer.
+    /// a final size below what already arrived, or beyond the credit of the stream or channel, breaks the channel
+    fn account_final_size(
+        &mut self,
+        stream: u32,
+        received: u64,
+        consumed: u64,
+        limit: u64,
+        final_size: u64,
+    ) -> Result<(), Error> {
+        if final_size < received || final_size > limit {
+            return Err(self.violation(ChannelError::InvalidFinalSize {
+                stream,
+                final_size,
+                received,
+                limit,
+            }.into()));
+        }
+        self.recv_window.on_recv(final_size - received).map_err(|e| self.violation(e))?;
+        self.recv_window.on_consumed(final_size - consumed);
+        Ok(())
+    }
+
+    /// flow control got out of sync with the peer, so the channel cannot continue
+    fn violation(&mut self, e: Error) -> Error {
+        warn!("[{}] {}", self.debug_id, e);
+        self.violated = true;
+        e
     }
 
     /// remove a stream (full close)
     pub fn remove(&mut self, stream: u32) {
-        self.streams.remove(&stream);
+        if let Some(ordered) = self.streams.remove(&stream) {
+            // return the credit for data the application will never read
+            let window = ordered.window();
+            self.recv_window.on_consumed(window.received() - window.consumed());
+        }
+        self.close_stream_stats(stream);
+        self.received_headers.remove(&stream);
         self.counters.remove(&stream);
+
+        // frames that are still waiting for credit, like the close, are sent before the stream is forgotten
+        if let Some(send) = self.send_streams.get_mut(&stream) {
+            send.removed = true;
+        }
     }
 
     /// create a disconnect packet
     pub fn disconnect(&mut self) -> Result<Vec<u8>, Error> {
         let mut pkt = Vec::new();
         Frame::Disconnect.encode(&mut pkt)?;
-        let pkt = self.noise.send(&pkt)?;
-        Ok(pkt.encode())
+        let pkt = self.noise.send(&pkt)?.encode();
+        let ecn = self.recovery.ecn();
+        self.count_sent(&pkt, ecn);
+        Ok(pkt)
+    }
+
+    /// create a packet challenging the peer to prove it receives packets on a new path.
+    /// returns the challenge data, which comes back as ChannelProgress::PathResponse.
+    /// the packet is padded to the base packet size, so it also checks that the path carries it
+    pub fn path_challenge(&mut self) -> Result<(u64, Vec<u8>), Error> {
+        if !self.extended() {
+            return Err(ChannelError::UnsupportedByPeer {
+                what:    "path challenge",
+                version: self.version(),
+            }.into());
+        }
+        let data = rand::random::<u64>();
+        let pkt = self.padded_packet(Frame::PathChallenge { data })?;
+        Ok((data, pkt))
+    }
+
+    /// create a packet answering the path challenge in the last received packet, if there was one.
+    /// it must be sent to the address that packet came from
+    pub fn path_response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.path_challenge.take() {
+            Some(data) => Ok(Some(self.padded_packet(Frame::PathResponse { data })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// a single frame in a packet of the base packet size, not tracked by loss recovery
+    fn padded_packet(&mut self, frame: Frame) -> Result<Vec<u8>, Error> {
+        let mut pkt = Vec::new();
+        frame.encode(&mut pkt)?;
+        let padding = pmtud::frames_capacity(pmtud::BASE_PACKET_SIZE, self.noise.padding()) - pkt.len();
+        pkt.extend_from_slice(&vec![0; padding]);
+        let pkt = self.noise.send(&pkt)?.encode();
+        let ecn = self.recovery.ecn();
+        self.count_sent(&pkt, ecn);
+        Ok(pkt)
+    }
+
+    /// stop probing for packets larger than pmtud::BASE_PACKET_SIZE.
+    /// for sockets that cannot set don't fragment, where a probe might arrive fragmented
+    pub fn disable_pmtud(&mut self) {
+        self.pmtud = pmtud::Pmtud::disabled();
+        self.recovery.set_mss(self.pmtud.current() as u64);
+    }
+
+    /// the socket refused to send a packet of size bytes, because it exceeds the mtu the local stack knows
+    pub fn on_packet_too_big(&mut self, size: usize) {
+        let now = self.now();
+        if self.pmtud.on_too_big(size, now) {
+            debug!("[{}] path mtu fell back to {}", self.debug_id, self.pmtud.current());
+            self.recovery.set_mss(self.pmtud.current() as u64);
+        }
+    }
+
+    /// traffic moved to a validated new path. congestion control, rtt and path mtu start over
+    pub fn migrate(&mut self) {
+        self.pmtud = if self.pmtud.enabled() { pmtud::Pmtud::new() } else { pmtud::Pmtud::disabled() };
+        self.recovery.on_path_change(self.pmtud.current() as u64);
+    }
+
+    fn dead_peer_deadline(&self) -> u64 {
+        self.last_heard + max(self.dead_peer_timeout, 2 * self.idle_time)
     }
 
     /// send probe packets
@@ -516,11 +1587,353 @@ impl Channel {
             self.idle_time = seconds as u64 * 1000;
         }
 
+        if let Some(algorithm) = config.congestion {
+            debug!("[{}] using {:?} congestion control", self.debug_id, algorithm);
+            self.recovery.set_congestion(algorithm);
+        }
+
+        if let Some(mode) = config.loss_detection {
+            debug!("[{}] using {:?} loss detection", self.debug_id, mode);
+            self.recovery.set_loss_detection_mode(mode);
+        }
+
+        if let Some(timeout) = config.dead_peer_timeout {
+            self.dead_peer_timeout = timeout;
+        }
+
+        if let Some(size) = config.header_table_size {
+            self.header_decoder.set_max_size(size);
+        }
+
+        if let Some(policy) = config.padding {
+            debug!("[{}] using padding policy {:?}", self.debug_id, policy);
+            self.noise.set_padding(match policy {
+                PaddingPolicy::None => noise::Padding::None,
+                _ => noise::Padding::Buckets,
+            });
+            self.constant_rate = match policy {
+                PaddingPolicy::ConstantRate { interval } => Some(interval),
+                _ => None,
+            };
+            if self.constant_rate.is_some() {
+                // probes would stand out from the constant packet size
+                self.disable_pmtud();
+            }
+        }
+
         let fr = Frame::Config{
-            timeout:  config.timeout,
-            sleeping: config.sleeping,
+            timeout:           config.timeout,
+            sleeping:          config.sleeping,
+            header_table_size: config.header_table_size,
         };
         self.outqueue.push_back(fr);
 
     }
 }
+
+/// true if a frame of frame_len bytes still fits into a packet with pkt_len bytes of frames,
+/// without exceeding the path mtu
+fn fits_packet(pkt_len: usize, frame_len: usize, mtu: usize, padding: noise::Padding) -> bool {
+    pmtud::packet_size(pkt_len + frame_len, padding) <= mtu
+}
+
+#[test]
+fn legacy_peer() {
+    use netsim::{network_offering, transfer, LinkConfig};
+
+    let mut net = network_offering(
+        5,
+        LinkConfig {
+            delay: 50,
+            loss:  0.1,
+            ..LinkConfig::default()
+        },
+        &[0x08],
+    );
+    assert_eq!(net.initiator.channel.version(), 0x08);
+    assert_eq!(net.responder.channel.version(), 0x08);
+
+    // only frames of the original set go on the wire, so the peer can decode everything
+    transfer(&mut net, 100, 900);
+    assert!(net.initiator.channel.datagram(&b"dgram"[..]).is_err());
+    assert!(net.initiator.channel.path_challenge().is_err());
+    assert!(net.initiator.channel.stream(1, vec![0; 2000]).is_err(), "no fragments");
+}
+
+#[test]
+fn reset_exchange() {
+    use netsim::{network, LinkConfig};
+
+    let mut net = network(
+        6,
+        LinkConfig {
+            delay: 50,
+            loss:  0.1,
+            ..LinkConfig::default()
+        },
+    );
+    let stream = net.initiator.channel.open(&Headers::with_path("/reset"), true).unwrap();
+    net.initiator.channel.stream(stream, vec![1; 5000]).unwrap();
+    net.run_until(100, |net| net.now() >= 100);
+    net.initiator.channel.reset(stream, 7);
+
+    // the entries are forgotten once both sides exchanged their final sizes
+    let mut reset = false;
+    let done = net.run_until(300000, |net| {
+        for event in net.responder.events.drain(..) {
+            if let ChannelProgress::Reset(s, code) = event {
+                assert_eq!((s, code), (stream, 7));
+                reset = true;
+            }
+        }
+        reset && net.initiator.channel.reset_streams() == 0 && net.responder.channel.reset_streams() == 0
+    });
+    assert!(done, "reset arrived: {}", reset);
+}
+
+#[cfg(test)]
+fn padding_config(policy: PaddingPolicy) -> Config {
+    Config {
+        padding: Some(policy),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn no_padding() {
+    use netsim::{network, transfer, LinkConfig};
+
+    let mut padded = network(5, LinkConfig::default());
+    transfer(&mut padded, 20, 100);
+
+    let mut net = network(5, LinkConfig::default());
+    net.initiator.channel.config(padding_config(PaddingPolicy::None));
+    transfer(&mut net, 20, 100);
+
+    // the same packets, but those carrying the messages shrink from a 276 byte bucket to what they contain.
+    // path mtu probes are padded to their size either way
+    assert_eq!(net.forward.stats.sent, padded.forward.stats.sent);
+    assert!(
+        padded.forward.stats.bytes - net.forward.stats.bytes > 20 * 100,
+        "{} bytes without padding, {} with",
+        net.forward.stats.bytes,
+        padded.forward.stats.bytes
+    );
+}
+
+#[test]
+fn constant_rate() {
+    use netsim::{network, transfer, LinkConfig};
+
+    let mut net = network(6, LinkConfig::default());
+    net.initiator.channel.config(padding_config(PaddingPolicy::ConstantRate { interval: 100 }));
+    let size = pmtud::BASE_PACKET_SIZE as u64;
+
+    // nothing but pings and the acks for the pings of the peer, one packet per slot
+    let (start, sent, bytes) = (net.now(), net.forward.stats.sent, net.forward.stats.bytes);
+    net.run_until(start + 10000, |_| false);
+    let idle = net.forward.stats.sent - sent;
+    assert!(idle >= 100 && idle <= 102, "sent {} packets", idle);
+    assert_eq!(net.forward.stats.bytes - bytes, idle * size);
+
+    // a burst of messages looks the same on the wire, it just takes as many slots as it needs
+    let (start, sent, bytes) = (net.now(), net.forward.stats.sent, net.forward.stats.bytes);
+    let end = transfer(&mut net, 20, 2000);
+    let busy = net.forward.stats.sent - sent;
+    assert!(end - start >= 20 * 2000 / size * 100, "transfer took {} ms", end - start);
+    assert!(
+        busy * 100 + 100 >= end - start && busy * 100 <= end - start + 200,
+        "sent {} packets in {} ms",
+        busy,
+        end - start
+    );
+    assert_eq!(net.forward.stats.bytes - bytes, busy * size);
+}
+
+#[test]
+fn channel_stats() {
+    use netsim::{network, transfer, LinkConfig};
+
+    let mut net = network(
+        9,
+        LinkConfig {
+            delay: 20,
+            loss:  0.1,
+            ..LinkConfig::default()
+        },
+    );
+    transfer(&mut net, 50, 2000);
+
+    let sent = net.initiator.channel.stats();
+    assert_eq!(sent.packets_sent, net.forward.stats.sent);
+    assert_eq!(sent.bytes_sent, net.forward.stats.bytes);
+    assert!(sent.recovery.packets_lost > 0 && sent.recovery.packets_retransmitted > 0);
+    assert!(sent.recovery.min_rtt >= 40 && sent.recovery.smoothed_rtt >= sent.recovery.min_rtt);
+    assert!(sent.recovery.congestion_window > 0);
+    assert!(sent.recovery.ssthresh.is_some(), "losses end slow start");
+
+    let received = net.responder.channel.stats();
+    assert!(received.packets_received <= net.forward.stats.delivered);
+    assert!(received.packets_received + net.forward.stats.lost >= net.forward.stats.sent);
+    assert_eq!(sent.streams.len(), 1);
+    for (stream, stats) in sent.streams {
+        assert_eq!(stats.sent, 50 * 2000);
+        assert_eq!(received.streams[&stream].received, 50 * 2000);
+
+        // totals of closed streams are kept
+        net.initiator.channel.remove(stream);
+        let closed = net.initiator.channel.stats();
+        assert!(closed.streams.is_empty());
+        assert_eq!(closed.closed, stats);
+    }
+}
+
+#[cfg(test)]
+fn timed_out(peer: &::netsim::Peer) -> bool {
+    peer.events.iter().any(|event| match event {
+        ChannelProgress::Timeout => true,
+        _ => false,
+    })
+}
+
+#[test]
+fn dead_peer() {
+    use netsim::{network, Link, LinkConfig};
+
+    let mut net = network(7, LinkConfig::default());
+
+    // an idle channel is kept alive by pings
+    net.run_until(3 * DEFAULT_DEAD_PEER_TIMEOUT, |_| false);
+    assert!(!timed_out(&net.initiator) && !timed_out(&net.responder));
+
+    // the responder vanishes
+    let vanished = net.now();
+    net.backward = Link::new(
+        LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        },
+        8,
+    );
+    let mut last = vanished;
+    let done = net.run_until(vanished + 2 * DEFAULT_DEAD_PEER_TIMEOUT, |net| {
+        if timed_out(&net.initiator) {
+            return true;
+        }
+        last = net.now();
+        false
+    });
+    assert!(done, "initiator never gave up");
+    assert!(last <= vanished + DEFAULT_DEAD_PEER_TIMEOUT + 1, "timed out at {}", last);
+}
+
+#[test]
+fn remove_returns_credit() {
+    use netsim::{network, LinkConfig};
+
+    let mut net = network(
+        10,
+        LinkConfig {
+            delay:   50,
+            loss:    0.3,
+            reorder: 0.2,
+            ..LinkConfig::default()
+        },
+    );
+    let stream = net.initiator.channel.open(&Headers::with_path("/remove"), true).unwrap();
+    for i in 0..50 {
+        net.initiator.channel.stream(stream, vec![i; 900]).unwrap();
+    }
+
+    // removed while later messages wait for a lost one
+    let removed = net.run_until(60000, |net| {
+        let window = net.responder.channel.recv_window();
+        if window.received() == window.consumed() {
+            return false;
+        }
+        net.responder.channel.remove(stream);
+        true
+    });
+    assert!(removed, "no message ever waited behind a lost one");
+    let window = net.responder.channel.recv_window();
+    assert_eq!(window.received(), window.consumed());
+}
+
+#[test]
+fn reassembly_limit() {
+    use netsim::{network, LinkConfig};
+
+    let mut net = network(11, LinkConfig::default());
+    net.responder.channel.set_max_message_size(10000);
+    let first = net.initiator.channel.open(&Headers::with_path("/first"), true).unwrap();
+    let second = net.initiator.channel.open(&Headers::with_path("/second"), true).unwrap();
+    net.initiator.channel.stream(first, vec![1; 8000]).unwrap();
+    net.initiator.channel.stream(second, vec![2; 8000]).unwrap();
+
+    // both messages fit alone, but not while they are reassembled at the same time
+    let mut reset = Vec::new();
+    let mut received = Vec::new();
+    let done = net.run_until(60000, |net| {
+        for event in net.responder.events.drain(..) {
+            match event {
+                ChannelProgress::Reset(stream, code) => reset.push((stream, code)),
+                ChannelProgress::ReceiveStream(stream, payload) => received.push((stream, payload.len())),
+                _ => (),
+            }
+        }
+        reset.len() + received.len() == 2
+    });
+    assert!(done, "reset {:?}, received {:?}", reset, received);
+    assert_eq!(reset.len(), 1);
+    assert_eq!(reset[0].1, RESET_REASSEMBLY_LIMIT);
+    assert_eq!(received[0].1, 8000);
+    assert!(received[0].0 != reset[0].0);
+}
+
+#[test]
+fn sleeping_dead_peer() {
+    use netsim::{network, Link, LinkConfig};
+
+    let mut net = network(12, LinkConfig::default());
+    net.responder.channel.config(Config {
+        timeout: Some(300),
+        sleeping: true,
+        ..Default::default()
+    });
+    net.run_until(10000, |_| false);
+
+    // the responder said it sleeps, then vanishes for good
+    let vanished = net.now();
+    net.backward = Link::new(
+        LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        },
+        13,
+    );
+    let done = net.run_until(vanished + 4 * DEFAULT_DEAD_PEER_TIMEOUT, |net| timed_out(&net.initiator));
+    assert!(done, "initiator never gave up on a sleeping peer");
+    assert!(net.now() > 2 * 300000, "gave up at {}, before twice the idle time of the peer", net.now());
+}
+
+#[test]
+fn datagram_send_queue() {
+    use netsim::{network, LinkConfig};
+
+    let mut net = network(14, LinkConfig { delay: 20, ..LinkConfig::default() });
+    for i in 0..MAX_DATAGRAM_SEND_QUEUE + 50 {
+        net.initiator.channel.datagram(vec![i as u8]).unwrap();
+    }
+
+    // only the newest ones are sent
+    let mut received = Vec::new();
+    net.run_until(1000, |net| {
+        for event in net.responder.events.drain(..) {
+            if let ChannelProgress::ReceiveDatagram(payload) = event {
+                received.push(payload[0] as usize);
+            }
+        }
+        false
+    });
+    assert_eq!(received, (50..MAX_DATAGRAM_SEND_QUEUE + 50).map(|i| i % 256).collect::<Vec<_>>());
+}
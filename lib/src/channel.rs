@@ -1,43 +1,63 @@
+use df;
+use ecn;
 use endpoint;
 use failure::Error;
 use futures::sync::mpsc;
+use futures::sync::oneshot;
 use futures::{self, Async, Future, Poll, Stream};
 use futures::{AsyncSink, Sink};
 use headers::Headers;
 use identity;
-use packet::{EncryptedPacket, RoutingKey};
+use packet::{self, Ecn, EncryptedPacket, RoutingKey};
 use prost::Message;
 use proto;
 use std::collections::HashMap;
+use std::io;
 use std::marker::PhantomData;
 use std::mem;
 use std::net::SocketAddr;
 use std::net::UdpSocket as StdSocket;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio;
 use transport::{self, ChannelProgress, Config};
 use bytes::{BytesMut, Bytes};
 
+/// milliseconds until a path challenge to a new peer address is repeated
+const PATH_CHALLENGE_INTERVAL: u64 = 1000;
+
+/// give up on validating a new peer address after this many challenges
+const MAX_PATH_CHALLENGES: u8 = 3;
+
 #[derive(Debug, Fail)]
 pub enum ChannelError {
     #[fail(display = "rpc returned status: {:?}", status)]
     RpcError { headers: Headers, status:  Option<String> },
+
+    #[fail(display = "stream reset with code {}", code)]
+    StreamReset { code: u32 },
+}
+
+/// what travels between a stream handle and the ChannelWorker
+enum StreamItem {
+    Message(Bytes),
+    Reset(u32),
 }
 
 enum ChannelCmd {
-    Open(ChannelStream, Vec<u8>),
+    Open(ChannelStream, Headers),
     OnIdle(mpsc::Sender<()>),
     Config(Config),
+    Stats(oneshot::Sender<ChannelStats>),
 }
 
 pub struct ChannelStream {
-    tx: mpsc::Sender<Bytes>,
-    rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<StreamItem>,
+    rx: mpsc::Receiver<StreamItem>,
 }
 
 pub struct MessageStream<In, Out> {
-    tx:      mpsc::Sender<Bytes>,
-    rx:      mpsc::Receiver<Bytes>,
+    tx:      mpsc::Sender<StreamItem>,
+    rx:      mpsc::Receiver<StreamItem>,
     int:     PhantomData<In>,
     out:     PhantomData<Out>,
     headers: Option<Headers>,
@@ -46,6 +66,8 @@ pub struct MessageStream<In, Out> {
 pub struct Channel {
     cmd:  mpsc::Sender<ChannelCmd>,
     lst:  Option<ChannelListener>,
+    dgm:  Option<ChannelStream>,
+    mig:  Option<ChannelMigrations>,
 
     identity: identity::Identity,
     route:    RoutingKey,
@@ -58,26 +80,63 @@ pub struct ChannelControl {
     cmd:  mpsc::Sender<ChannelCmd>
 }
 
-pub struct ChannelListener(mpsc::Receiver<(ChannelStream, Vec<u8>)>);
+/// transport statistics of a channel, and the path it currently uses
+#[derive(Clone, Debug)]
+pub struct ChannelStats {
+    pub transport: transport::ChannelStats,
+    /// None while the peer address is still being discovered
+    pub addr:      Option<SocketAddr>,
+    /// None if the address was not announced by the peer, so its category is unknown
+    pub category:  Option<proto::path::Category>,
+}
+
+pub struct ChannelListener(mpsc::Receiver<(ChannelStream, Headers)>);
 
+/// the channel moved to a different peer address
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub from: SocketAddr,
+    pub to:   SocketAddr,
+}
+
+pub struct ChannelMigrations(mpsc::Receiver<Migration>);
+
+/// a new peer address is only used once the peer proved that it receives packets there
+struct PathValidation {
+    addr:     SocketAddr,
+    data:     u64,
+    sent:     Instant,
+    attempts: u8,
+}
+
+/// peer addresses with the category the peer announced them with, None for addresses it did not announce
 enum AddressMode {
-    Discovering(HashMap<SocketAddr, (proto::path::Category, usize)>),
-    Established(SocketAddr, HashMap<SocketAddr, (proto::path::Category, usize)>),
+    Discovering(HashMap<SocketAddr, (Option<proto::path::Category>, usize)>),
+    Established(SocketAddr, HashMap<SocketAddr, (Option<proto::path::Category>, usize)>),
+}
+
+/// lower is better. addresses the peer did not announce rank like internet addresses
+fn rank(category: Option<proto::path::Category>) -> i32 {
+    category.unwrap_or(proto::path::Category::Internet) as i32
 }
 
 struct ChannelWorker {
     cmd:  mpsc::Receiver<ChannelCmd>,
-    newc: mpsc::Sender<(ChannelStream, Vec<u8>)>,
+    newc: mpsc::Sender<(ChannelStream, Headers)>,
 
     streams: HashMap<u32, ChannelStream>,
+    datagrams: ChannelStream,
 
     transport: transport::Channel,
-    rx:        mpsc::Receiver<(EncryptedPacket, SocketAddr)>,
+    rx:        mpsc::Receiver<(EncryptedPacket, SocketAddr, Ecn)>,
     work:      mpsc::Sender<endpoint::EndpointWorkerCmd>,
     sock:      StdSocket,
     addrs:     AddressMode,
     deadline:  tokio::timer::Delay,
 
+    validating: Option<PathValidation>,
+    migrations: mpsc::Sender<Migration>,
+
     route: RoutingKey,
 
     stop: bool,
@@ -87,7 +146,7 @@ struct ChannelWorker {
 
 impl Channel {
     pub fn spawn(
-        rx: mpsc::Receiver<(EncryptedPacket, SocketAddr)>,
+        rx: mpsc::Receiver<(EncryptedPacket, SocketAddr, Ecn)>,
         identity: identity::Identity,
         addrs: Vec<(SocketAddr, proto::path::Category)>,
         route: RoutingKey,
@@ -97,29 +156,42 @@ impl Channel {
     ) -> Self {
         let (cmd_tx,  cmd_rx) = mpsc::channel(10);
         let (newc_tx, newc_rx) = mpsc::channel(10);
+        let (dgm_a, dgm_b) = ChannelStream::new();
+        let (mig_tx, mig_rx) = mpsc::channel(10);
 
         if addrs.len() > 1 {
             transport.probe();
         }
 
+        // probes must not arrive fragmented
+        if let Err(e) = df::enable(&sock) {
+            debug!("no path mtu discovery without don't fragment: {}", e);
+            transport.disable_pmtud();
+        }
+
         tokio::spawn(ChannelWorker {
             cmd: cmd_rx,
             newc: newc_tx,
             streams: HashMap::new(),
+            datagrams: dgm_a,
             transport,
             rx,
             work,
             sock,
-            addrs: AddressMode::Discovering(addrs.into_iter().map(|(addr, cat)| (addr, (cat, 0))).collect()),
+            addrs: AddressMode::Discovering(addrs.into_iter().map(|(addr, cat)| (addr, (Some(cat), 0))).collect()),
             route,
             stop: false,
             deadline: tokio::timer::Delay::new(Instant::now()),
+            validating: None,
+            migrations: mig_tx,
             on_idle: Vec::new(),
         });
 
         Self {
             cmd: cmd_tx,
             lst: Some(ChannelListener(newc_rx)),
+            dgm: Some(dgm_b),
+            mig: Some(ChannelMigrations(mig_rx)),
             identity,
             route,
             bag: Vec::new(),
@@ -136,7 +208,7 @@ impl Channel {
 
     pub fn open(&mut self, headers: Headers) -> Result<ChannelStream, Error> {
         let (a, b) = ChannelStream::new();
-        self.cmd.try_send(ChannelCmd::Open(a, headers.encode()))?;
+        self.cmd.try_send(ChannelCmd::Open(a, headers))?;
         Ok(b)
     }
 
@@ -145,7 +217,7 @@ impl Channel {
         p: P,
     ) -> Result<MessageStream<In, Out>, Error> {
         let (a, b) = MessageStream::new();
-        self.cmd.try_send(ChannelCmd::Open(a, Headers::with_path(p).encode()))?;
+        self.cmd.try_send(ChannelCmd::Open(a, Headers::with_path(p)))?;
         Ok(b)
     }
 
@@ -153,6 +225,18 @@ impl Channel {
         mem::replace(&mut self.lst, None)
     }
 
+    /// unreliable datagrams on this channel.
+    /// datagrams are never retransmitted and may be dropped or reordered,
+    /// incomming datagrams are also dropped when they are not consumed fast enough.
+    pub fn datagram(&mut self) -> Option<ChannelStream> {
+        mem::replace(&mut self.dgm, None)
+    }
+
+    /// peer address changes. a new address is validated with a path challenge before traffic moves to it
+    pub fn migrations(&mut self) -> Option<ChannelMigrations> {
+        mem::replace(&mut self.mig, None)
+    }
+
     pub fn ctrl(&self) -> ChannelControl{
         ChannelControl{cmd: self.cmd.clone()}
     }
@@ -173,6 +257,10 @@ impl Channel {
                 Ok(())
             })
     }
+
+    pub fn stats(&self) -> impl Future<Item=ChannelStats, Error=Error> {
+        stats(&self.cmd)
+    }
 }
 
 impl ChannelControl {
@@ -192,6 +280,19 @@ impl ChannelControl {
                 Ok(())
             })
     }
+
+    pub fn stats(&self) -> impl Future<Item=ChannelStats, Error=Error> {
+        stats(&self.cmd)
+    }
+}
+
+fn stats(cmd: &mpsc::Sender<ChannelCmd>) -> impl Future<Item=ChannelStats, Error=Error> {
+    let (tx, rx) = oneshot::channel();
+    cmd.clone().send(ChannelCmd::Stats(tx))
+        .map_err(Error::from)
+        .and_then(|_| {
+            rx.map_err(Error::from)
+        })
 }
 
 impl Stream for ChannelListener {
@@ -202,14 +303,20 @@ impl Stream for ChannelListener {
         match self.0.poll().unwrap() {
             Async::NotReady => Ok(Async::NotReady),
             Async::Ready(None) => Ok(Async::Ready(None)),
-            Async::Ready(Some((stream, headers))) => {
-                let headers = Headers::decode(&headers)?;
-                Ok(Async::Ready(Some((stream, headers))))
-            }
+            Async::Ready(Some((stream, headers))) => Ok(Async::Ready(Some((stream, headers)))),
         }
     }
 }
 
+impl Stream for ChannelMigrations {
+    type Item = Migration;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        Ok(self.0.poll().unwrap())
+    }
+}
+
 impl Future for ChannelWorker {
     type Item = ();
     type Error = ();
@@ -234,6 +341,8 @@ impl Future for ChannelWorker {
     //      - stop worker
     //   5. transport error
     //      - stop worker immediately
+    //   6. peer timed out
+    //      - stop worker immediately. dropping it frees the route and everything served on the channel
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         // --- make no progress if there's a full consumer
@@ -261,25 +370,36 @@ impl Future for ChannelWorker {
                 match &self.addrs {
                     AddressMode::Discovering(addrs) => {
                         for (addr, _) in addrs.iter() {
-                            match self.sock.send_to(&pkt, addr) {
+                            match self.send_to(&pkt, addr) {
                                 Ok(len) if len == pkt.len() => (),
+                                Err(ref e) if df::too_big(e) => self.transport.on_packet_too_big(pkt.len()),
                                 e => trace!("send to {} didnt work {:?}", addr, e),
                             }
                         }
                     }
-                    AddressMode::Established(addr, _) => match self.sock.send_to(&pkt, &addr) {
+                    AddressMode::Established(addr, _) => match self.send_to(&pkt, &addr) {
                         Ok(len) if len == pkt.len() => (),
+                        // a probe larger than the path, or the path shrank
+                        Err(ref e) if df::too_big(e) => self.transport.on_packet_too_big(pkt.len()),
                         e => error!("send didnt work {:?}", e),
                     },
                 }
                 futures::task::current().notify();
             }
-            Ok(ChannelProgress::ReceiveHeader(stream, header)) => {
-                let (a, b) = ChannelStream::new();
-                if let Err(e) = self.newc.try_send((a, header)) {
-                    error!("ChannelWorker sending newc for stream {}: {}", stream, e);
+            Ok(ChannelProgress::ReceiveHeader(stream, headers)) => {
+                if self.streams.contains_key(&stream) {
+                    // headers only open streams. never hand over a stream someone already holds
+                    warn!("ChannelWorker, headers for stream {} which is already open", stream);
+                } else {
+                    if let Some(priority) = headers.priority() {
+                        self.transport.set_priority(stream, priority);
+                    }
+                    let (a, b) = ChannelStream::new();
+                    if let Err(e) = self.newc.try_send((a, headers)) {
+                        error!("ChannelWorker sending newc for stream {}: {}", stream, e);
+                    }
+                    self.streams.insert(stream, b);
                 }
-                self.streams.insert(stream, b);
                 futures::task::current().notify();
             }
             Ok(ChannelProgress::ReceiveStream(stream, msg)) => {
@@ -289,13 +409,42 @@ impl Future for ChannelWorker {
                         warn!("ChannelWorker, stream frame for unregistered stream {}", stream);
                     }
                     Some(cs) => {
-                        if let Err(e) = cs.tx.try_send(msg.into()) {
+                        if let Err(e) = cs.tx.try_send(StreamItem::Message(msg)) {
                             warn!("ChannelWorker::stream {} try_send: {}", stream, e);
                         }
                     }
                 }
                 futures::task::current().notify();
             }
+            Ok(ChannelProgress::ReceiveDatagram(msg)) => {
+                trace!("ChannelProgress::ReceiveDatagram {:?}", msg);
+                if let Err(e) = self.datagrams.tx.try_send(StreamItem::Message(msg)) {
+                    trace!("ChannelWorker dropping datagram: {}", e);
+                }
+                futures::task::current().notify();
+            }
+            Ok(ChannelProgress::Reset(stream, code)) => {
+                if let Some(mut cs) = self.streams.remove(&stream) {
+                    if let Err(e) = cs.tx.try_send(StreamItem::Reset(code)) {
+                        warn!("ChannelWorker::stream {} try_send reset: {}", stream, e);
+                    }
+                }
+                futures::task::current().notify();
+            }
+            Ok(ChannelProgress::PathResponse(data)) => {
+                let validated = match self.validating {
+                    Some(ref validation) if validation.data == data => Some(validation.addr),
+                    _ => None,
+                };
+                match validated {
+                    Some(addr) => {
+                        self.validating = None;
+                        self.migrate(addr);
+                    }
+                    None => trace!("ignoring unexpected path response {:x}", data),
+                }
+                futures::task::current().notify();
+            }
             Ok(ChannelProgress::Close(stream)) => {
                 // close scenario 3
                 self.streams.remove(&stream);
@@ -305,6 +454,11 @@ impl Future for ChannelWorker {
                 info!("[{}] received disconnect", self.transport.debug_id);
                 return Ok(Async::Ready(()));
             }
+            Ok(ChannelProgress::Timeout) => {
+                // close scenario 6
+                warn!("[{}] peer timed out", self.transport.debug_id);
+                return Ok(Async::Ready(()));
+            }
             Err(e) => {
                 error!("transport progress error: {}", e);
                 return Ok(Async::Ready(()));
@@ -319,11 +473,11 @@ impl Future for ChannelWorker {
                     self.stop = true;
                 }
             }
-            Ok(Async::Ready(Some((pkt, addr)))) => {
+            Ok(Async::Ready(Some((pkt, addr, ecn)))) => {
                 let settle = if let AddressMode::Discovering(ref mut addrs) = self.addrs {
                     trace!("in discovery: received from {}", addr);
                     let count = {
-                        let (_, count) = addrs.entry(addr).or_insert((proto::path::Category::Internet, 0));
+                        let (_, count) = addrs.entry(addr).or_insert((None, 0));
                         *count += 1;
                         *count
                     };
@@ -333,13 +487,13 @@ impl Future for ChannelWorker {
                         for (addr, (cat, count)) in &*addrs {
                             if *count >= 1 {
                                 if let Some(ref mut bestest) = bestest {
-                                    if *bestest > *cat as i32 {
+                                    if *bestest > rank(*cat) {
                                         m = Some(addr.clone());
-                                        *bestest = *cat as i32;
+                                        *bestest = rank(*cat);
                                     }
                                 } else {
                                     m = Some(addr.clone());
-                                    bestest = Some(*cat as i32);
+                                    bestest = Some(rank(*cat));
                                 }
                             }
                         }
@@ -356,24 +510,38 @@ impl Future for ChannelWorker {
                     self.addrs = AddressMode::Established(addr, previous);
                 }
 
-                if let Err(e) = self.transport.recv(pkt) {
-                    trace!("ChannelWorker: transport.recv: {}", e);
+                let authenticated = match self.transport.recv_with_ecn(pkt, ecn) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        trace!("ChannelWorker: transport.recv: {}", e);
+                        false
+                    }
                 };
 
-                if let AddressMode::Established(ref mut addr_, ref previous) = self.addrs {
-                    if addr != *addr_ {
-                        let current_cat = previous.get(addr_).unwrap_or(&(proto::path::Category::Internet, 0)).0;
-                        let migrate_cat = previous.get(&addr).unwrap_or(&(proto::path::Category::Internet, 0)).0;
-
-                        if current_cat as i32 >= migrate_cat as i32 {
-                            warn!(
-                                "channel migration not fully implemented yet. migrating from  {} to {}",
-                                addr_, addr,
-                            );
-                            *addr_ = addr;
-                        }
+                if authenticated {
+                    // path challenges are answered on the path they arrived on
+                    match self.transport.path_response() {
+                        Ok(Some(pkt)) => match self.send_to(&pkt, &addr) {
+                            Ok(len) if len == pkt.len() => (),
+                            e => trace!("send path response to {} didnt work {:?}", addr, e),
+                        },
+                        Ok(None) => (),
+                        Err(e) => warn!("ChannelWorker::path_response: {}", e),
                     }
-                };
+
+                    // an authenticated packet may still be replayed from a spoofed address,
+                    // so traffic only moves once the peer answers a challenge on the new address
+                    let migrate = if let AddressMode::Established(ref addr_, ref previous) = self.addrs {
+                        let current_cat = rank(previous.get(addr_).and_then(|v| v.0));
+                        let migrate_cat = rank(previous.get(&addr).and_then(|v| v.0));
+                        addr != *addr_ && current_cat >= migrate_cat
+                    } else {
+                        false
+                    };
+                    if migrate {
+                        self.validate_path(addr);
+                    }
+                }
 
                 futures::task::current().notify();
             }
@@ -384,18 +552,42 @@ impl Future for ChannelWorker {
         if !self.stop && self.transport.window() > 0 {
             let mut removeme = Vec::new();
             for (id, ch) in &mut self.streams {
+                // the peer has not given us enough credit for the previous message yet
+                if !self.transport.writable(*id) {
+                    continue;
+                }
                 match ch.rx.poll() {
                     Ok(Async::Ready(None)) | Err(_) => {
                         removeme.push(*id);
                         futures::task::current().notify();
                     }
-                    Ok(Async::Ready(Some(msg))) => {
-                        self.transport.stream(*id, msg.to_vec());
+                    Ok(Async::Ready(Some(StreamItem::Message(msg)))) => {
+                        if let Err(e) = self.transport.stream(*id, msg) {
+                            warn!("ChannelWorker::stream {}: {}", id, e);
+                            removeme.push(*id);
+                        }
+                        futures::task::current().notify();
+                    }
+                    Ok(Async::Ready(Some(StreamItem::Reset(code)))) => {
+                        trace!("resetting stream {} with code {}", id, code);
+                        self.transport.reset(*id, code);
+                        removeme.push(*id);
                         futures::task::current().notify();
                     }
                     Ok(Async::NotReady) => (),
                 }
             }
+            match self.datagrams.rx.poll() {
+                Ok(Async::Ready(Some(StreamItem::Message(msg)))) => {
+                    if let Err(e) = self.transport.datagram(msg) {
+                        warn!("ChannelWorker::datagram: {}", e);
+                    }
+                    futures::task::current().notify();
+                }
+                Ok(Async::Ready(Some(StreamItem::Reset(_)))) => (),
+                Ok(Async::Ready(None)) | Err(_) | Ok(Async::NotReady) => (),
+            }
+
             for id in removeme {
                 trace!("removing stream {}", id);
                 self.transport.close(id);
@@ -417,11 +609,18 @@ impl Future for ChannelWorker {
                         futures::task::current().notify();
                     }
                 }
-                Async::Ready(Some(ChannelCmd::Open(ch, header))) => {
+                Async::Ready(Some(ChannelCmd::Open(ch, headers))) => {
                     let is_initiator = self.transport.is_initiator();
-                    let stream = self.transport.open(header, is_initiator);
-                    trace!("opened new stream {}", stream);
-                    self.streams.insert(stream, ch);
+                    match self.transport.open(&headers, is_initiator) {
+                        Ok(stream) => {
+                            trace!("opened new stream {}", stream);
+                            if let Some(priority) = headers.priority() {
+                                self.transport.set_priority(stream, priority);
+                            }
+                            self.streams.insert(stream, ch);
+                        }
+                        Err(e) => warn!("ChannelWorker::open: {}", e),
+                    }
                     futures::task::current().notify();
                 }
                 Async::Ready(Some(ChannelCmd::OnIdle(cb))) => {
@@ -432,6 +631,18 @@ impl Future for ChannelWorker {
                     self.transport.config(config);
                     futures::task::current().notify();
                 }
+                Async::Ready(Some(ChannelCmd::Stats(ret))) => {
+                    let (addr, category) = match &self.addrs {
+                        AddressMode::Discovering(_) => (None, None),
+                        AddressMode::Established(addr, previous) => (Some(*addr), previous.get(addr).and_then(|v| v.0)),
+                    };
+                    ret.send(ChannelStats {
+                        transport: self.transport.stats(),
+                        addr,
+                        category,
+                    }).ok();
+                    futures::task::current().notify();
+                }
                 Async::NotReady => (),
             };
         }
@@ -450,13 +661,13 @@ impl Future for ChannelWorker {
                 match &self.addrs {
                     AddressMode::Discovering(addrs) => {
                         for (addr, _) in addrs.iter() {
-                            match self.sock.send_to(&pkt, addr) {
+                            match self.send_to(&pkt, addr) {
                                 Ok(len) if len == pkt.len() => (),
                                 e => error!("send to {} didnt work {:?}", addr, e),
                             }
                         }
                     }
-                    AddressMode::Established(addr, _) => match self.sock.send_to(&pkt, &addr) {
+                    AddressMode::Established(addr, _) => match self.send_to(&pkt, &addr) {
                         Ok(len) if len == pkt.len() => (),
                         e => error!("send to {} didnt work {:?}", addr, e),
                     },
@@ -469,6 +680,62 @@ impl Future for ChannelWorker {
     }
 }
 
+impl ChannelWorker {
+    /// with the codepoint ECN validation of the transport allows
+    fn send_to(&self, pkt: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        ecn::send_to(&self.sock, pkt, addr, self.transport.ecn())
+    }
+
+    fn validate_path(&mut self, addr: SocketAddr) {
+        // peers without path challenges are followed to the new address right away, like before
+        if self.transport.version() < packet::EXTENDED_VERSION {
+            self.migrate(addr);
+            return;
+        }
+
+        let now = Instant::now();
+        let attempts = match self.validating {
+            Some(ref validation) if validation.addr == addr => {
+                if validation.attempts >= MAX_PATH_CHALLENGES
+                    || now < validation.sent + Duration::from_millis(PATH_CHALLENGE_INTERVAL)
+                {
+                    return;
+                }
+                validation.attempts + 1
+            }
+            _ => 1,
+        };
+
+        match self.transport.path_challenge() {
+            Ok((data, pkt)) => {
+                debug!("validating new peer address {}, attempt {}", addr, attempts);
+                match self.send_to(&pkt, &addr) {
+                    Ok(len) if len == pkt.len() => (),
+                    e => trace!("send path challenge to {} didnt work {:?}", addr, e),
+                }
+                self.validating = Some(PathValidation {
+                    addr,
+                    data,
+                    sent: now,
+                    attempts,
+                });
+            }
+            Err(e) => warn!("ChannelWorker::path_challenge: {}", e),
+        }
+    }
+
+    fn migrate(&mut self, to: SocketAddr) {
+        if let AddressMode::Established(ref mut addr, _) = self.addrs {
+            let from = mem::replace(addr, to);
+            info!("channel migrated from {} to {}", from, to);
+            self.transport.migrate();
+            if let Err(e) = self.migrations.try_send(Migration { from, to }) {
+                trace!("ChannelWorker dropping migration event: {}", e);
+            }
+        }
+    }
+}
+
 impl Drop for ChannelWorker {
     fn drop(&mut self) {
         self.work
@@ -486,12 +753,25 @@ impl ChannelStream {
     }
 }
 
+impl ChannelStream {
+    /// abort the stream in both directions. the peer receives the code as a StreamReset error.
+    pub fn reset(&mut self, code: u32) -> Result<(), Error> {
+        self.tx.try_send(StreamItem::Reset(code))?;
+        Ok(())
+    }
+}
+
 impl Stream for ChannelStream {
     type Item = Bytes;
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        self.rx.poll().map_err(|()| unreachable!())
+        match self.rx.poll().map_err(|()| unreachable!())? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some(StreamItem::Message(v))) => Ok(Async::Ready(Some(v))),
+            Async::Ready(Some(StreamItem::Reset(code))) => Err(ChannelError::StreamReset { code }.into()),
+        }
     }
 }
 
@@ -504,7 +784,11 @@ impl Sink for ChannelStream {
     }
 
     fn start_send(&mut self, item: Self::SinkItem) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
-        self.tx.start_send(item).map_err(Error::from)
+        match self.tx.start_send(StreamItem::Message(item))? {
+            AsyncSink::NotReady(StreamItem::Message(item)) => Ok(AsyncSink::NotReady(item)),
+            AsyncSink::NotReady(StreamItem::Reset(_)) => unreachable!(),
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+        }
     }
 }
 
@@ -525,6 +809,14 @@ impl<In, Out> MessageStream<In, Out> {
     }
 }
 
+impl<In, Out> MessageStream<In, Out> {
+    /// abort the stream in both directions. the peer receives the code as a StreamReset error.
+    pub fn reset(&mut self, code: u32) -> Result<(), Error> {
+        self.tx.try_send(StreamItem::Reset(code))?;
+        Ok(())
+    }
+}
+
 impl<In, Out> Stream for MessageStream<In, Out>
 where
     Out: Message + Default,
@@ -537,7 +829,10 @@ where
             match self.rx.poll().unwrap() {
                 Async::NotReady => return Ok(Async::NotReady),
                 Async::Ready(None) => return Ok(Async::Ready(None)),
-                Async::Ready(Some(v)) => {
+                Async::Ready(Some(StreamItem::Reset(code))) => {
+                    return Err(ChannelError::StreamReset { code }.into());
+                }
+                Async::Ready(Some(StreamItem::Message(v))) => {
                     if self.headers.is_none() {
                         let headers = Headers::decode(&v)?;
                         debug!("{:?}", headers);
@@ -582,7 +877,7 @@ where
     fn start_send(&mut self, item: Self::SinkItem) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
         let mut buf = BytesMut::with_capacity(item.encoded_len());
         item.encode(&mut buf)?;
-        match self.tx.start_send(buf.into())? {
+        match self.tx.start_send(StreamItem::Message(buf.into()))? {
             AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(item)),
             AsyncSink::Ready => Ok(AsyncSink::Ready),
         }
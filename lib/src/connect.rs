@@ -1,6 +1,7 @@
 ///! connect an endpoint to a broker via route0
 use channel;
 use channel::Channel;
+use cookie;
 use dns;
 use endpoint;
 use endpoint::Endpoint;
@@ -28,36 +29,41 @@ pub enum ConnectError {
     NoConnectOptions,
 }
 
+/// the psk must match the one of the broker, if it has one
 pub fn connect<S: AsRef<str>>(
     domain: S,
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
 ) -> impl Future<Item = (Endpoint, Channel, StdSocket, SocketAddr), Error = Error> {
-    dns::resolve(domain.as_ref()).and_then(|(epoch, records)| EndpointFuture::new(secret, records))
+    dns::resolve(domain.as_ref()).and_then(move |(epoch, records)| EndpointFuture::new(secret, psk, records))
 }
 
 pub fn connect_to_ip<S: AsRef<str>>(
     domain: S,
     ip: std::net::IpAddr,
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
 ) -> impl Future<Item = (Endpoint, Channel, StdSocket, SocketAddr), Error = Error> {
     dns::resolve(domain.as_ref()).and_then(move |(epoch, mut records)|{
         records.retain(move |ref record| {
             record.addr.ip() == ip
         });
-        EndpointFuture::new(secret, records)
+        EndpointFuture::new(secret, psk, records)
     })
 }
 
 struct EndpointFuture {
     secret:  identity::Secret,
+    psk:     Option<noise::Psk>,
     records: Vec<dns::DnsRecord>,
     st:      EndpointFutureState,
 }
 
 impl EndpointFuture {
-    pub fn new(secret: identity::Secret, records: Vec<dns::DnsRecord>) -> Self {
+    pub fn new(secret: identity::Secret, psk: Option<noise::Psk>, records: Vec<dns::DnsRecord>) -> Self {
         Self {
             secret,
+            psk,
             records,
             st: EndpointFutureState::Start {},
         }
@@ -73,6 +79,7 @@ enum EndpointFutureState {
         addr:     SocketAddr,
         deadline: Delay,
         attempts: u32,
+        hs:       EncryptedPacket,
         pkt:      Vec<u8>,
     },
     Invalid,
@@ -93,8 +100,9 @@ impl Future for EndpointFuture {
                 trace!("attempting connection to {} {}", record.addr, record.x);
 
                 let timestamp = clock::dns_time(&record);
-                let (noise, pkt) = noise::initiate(Some(&record.x), &self.secret, timestamp)?;
-                let pkt = pkt.encode();
+                let (noise, hs) =
+                    noise::initiate(Some(&record.x), &self.secret, timestamp, &Vec::new(), self.psk.as_ref())?;
+                let pkt = hs.clone().encode();
 
                 let stdsock = StdSocket::bind("0.0.0.0:0")?;
                 let miosock = UdpSocket::from_std(stdsock.try_clone()?, &tokio::reactor::Handle::current())?;
@@ -104,6 +112,7 @@ impl Future for EndpointFuture {
                     miosock,
                     addr: record.addr,
                     noise,
+                    hs,
                     pkt,
                     attempts: 0,
                     deadline: Delay::new(Instant::now() + Duration::from_millis(100)),
@@ -115,6 +124,7 @@ impl Future for EndpointFuture {
                 stdsock,
                 mut miosock,
                 mut noise,
+                hs,
                 pkt,
                 mut deadline,
                 mut attempts,
@@ -142,6 +152,7 @@ impl Future for EndpointFuture {
                             stdsock,
                             miosock,
                             noise,
+                            hs,
                             pkt,
                             deadline,
                             attempts,
@@ -151,8 +162,28 @@ impl Future for EndpointFuture {
                     }
                 };
 
-                match EncryptedPacket::decode(&buf[..len]).and_then(|pkt| noise.recv_response(pkt)) {
-                    Ok(identity) => {
+                let response = EncryptedPacket::decode(&buf[..len]);
+
+                // the broker is under load and wants proof that we own our address before doing any crypto
+                if let Some(cookie) = response.as_ref().ok().and_then(|reply| cookie::cookie_reply(&hs, reply)) {
+                    debug!("received cookie reply from {}, retrying", addr);
+                    let pkt = cookie::with_mac(&hs, &cookie).encode();
+                    self.st = EndpointFutureState::WaitingForResponse {
+                        stdsock,
+                        miosock,
+                        noise,
+                        hs,
+                        pkt,
+                        deadline,
+                        attempts,
+                        addr,
+                    };
+                    futures::task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+
+                match response.and_then(|pkt| noise.recv_response(pkt)) {
+                    Ok((identity, _)) => {
                         let noise = noise.into_transport()?;
                         let stdsock_ = stdsock.try_clone()?;
                         let stdsock__ = stdsock.try_clone()?;
@@ -186,6 +217,7 @@ impl Future for EndpointFuture {
                             stdsock,
                             miosock,
                             noise,
+                            hs,
                             pkt,
                             deadline,
                             attempts,
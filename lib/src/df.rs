@@ -0,0 +1,79 @@
+//! don't fragment on the udp socket, needed for path mtu discovery.
+//!
+//! without it, a probe larger than the path is fragmented by the kernel or a router and still gets acked,
+//! so the search would settle on sizes that only arrive in pieces. with it, oversized probes are dropped,
+//! and the kernel refuses packets above the mtu it already knows with EMSGSIZE.
+//! only linux is supported. elsewhere channels do not probe for larger packets.
+
+use std::io;
+use std::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    // from linux/in.h and linux/in6.h
+    const IP_MTU_DISCOVER: libc::c_int = 10;
+    const IP_PMTUDISC_DO: libc::c_int = 2;
+    const IPV6_MTU_DISCOVER: libc::c_int = 23;
+    const IPV6_PMTUDISC_DO: libc::c_int = 2;
+    const IPV6_DONTFRAG: libc::c_int = 62;
+
+    fn setsockopt(sock: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => setsockopt(sock, libc::IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_DO),
+            SocketAddr::V6(_) => {
+                setsockopt(sock, libc::IPPROTO_IPV6, IPV6_MTU_DISCOVER, IPV6_PMTUDISC_DO)?;
+                setsockopt(sock, libc::IPPROTO_IPV6, IPV6_DONTFRAG, 1)
+            }
+        }
+    }
+
+    pub fn too_big(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::UdpSocket;
+
+    pub fn enable(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "don't fragment is only supported on linux"))
+    }
+
+    pub fn too_big(_e: &io::Error) -> bool {
+        false
+    }
+}
+
+/// set don't fragment on every packet sent from sock
+pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+    sys::enable(sock)
+}
+
+/// true if a send failed because the packet exceeds the path mtu known to the kernel
+pub fn too_big(e: &io::Error) -> bool {
+    sys::too_big(e)
+}
@@ -0,0 +1,232 @@
+//! explicit congestion notification on the udp socket, RFC 3168.
+//!
+//! outgoing packets are marked ECT(0), so routers with active queue management can mark them
+//! CE instead of dropping them. the codepoint of incoming packets is read with recvmsg.
+//! only linux is supported. elsewhere packets go out unmarked and are received as Not-ECT.
+
+use packet::Ecn;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use packet::Ecn;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    // from linux/in.h and linux/in6.h
+    const IP_TOS: libc::c_int = 1;
+    const IP_RECVTOS: libc::c_int = 13;
+    const IPV6_RECVTCLASS: libc::c_int = 66;
+    const IPV6_TCLASS: libc::c_int = 67;
+
+    /// room for one cmsg carrying an int, aligned for cmsghdr
+    type ControlBuf = [u64; 4];
+
+    fn setsockopt(sock: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => {
+                setsockopt(sock, libc::IPPROTO_IP, IP_TOS, Ecn::Ect0.bits() as libc::c_int)?;
+                setsockopt(sock, libc::IPPROTO_IP, IP_RECVTOS, 1)
+            }
+            SocketAddr::V6(_) => {
+                setsockopt(sock, libc::IPPROTO_IPV6, IPV6_TCLASS, Ecn::Ect0.bits() as libc::c_int)?;
+                setsockopt(sock, libc::IPPROTO_IPV6, IPV6_RECVTCLASS, 1)
+            }
+        }
+    }
+
+    fn align(len: usize) -> usize {
+        let a = mem::size_of::<usize>();
+        (len + a - 1) & !(a - 1)
+    }
+
+    fn to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sin).sin_port = addr.port().to_be();
+                    (*sin).sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                }
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_port = addr.port().to_be();
+                    (*sin6).sin6_flowinfo = addr.flowinfo().to_be();
+                    (*sin6).sin6_addr.s6_addr = addr.ip().octets();
+                    (*sin6).sin6_scope_id = addr.scope_id();
+                }
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(sin6.sin6_port),
+                    u32::from_be(sin6.sin6_flowinfo),
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown address family")),
+        }
+    }
+
+    pub fn recv_from(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Ecn)> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control: ControlBuf = [0; 4];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len:  buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of::<ControlBuf>() as _;
+
+        let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let addr = from_sockaddr(&storage)?;
+
+        // walk the control messages by hand, the CMSG_ macros are not available in every libc version
+        let mut ecn = Ecn::NotEct;
+        let controllen = msg.msg_controllen as usize;
+        let hdrlen = align(mem::size_of::<libc::cmsghdr>());
+        let mut offset = 0;
+        while offset + hdrlen <= controllen {
+            let cmsg = unsafe { &*((control.as_ptr() as *const u8).add(offset) as *const libc::cmsghdr) };
+            let cmsglen = cmsg.cmsg_len as usize;
+            if cmsglen < hdrlen || offset + cmsglen > controllen {
+                break;
+            }
+            let data = unsafe { (control.as_ptr() as *const u8).add(offset + hdrlen) };
+            match (cmsg.cmsg_level, cmsg.cmsg_type) {
+                (libc::IPPROTO_IP, IP_TOS) if cmsglen > hdrlen => {
+                    ecn = Ecn::from_bits(unsafe { *data });
+                }
+                (libc::IPPROTO_IPV6, IPV6_TCLASS) if cmsglen >= hdrlen + mem::size_of::<libc::c_int>() => {
+                    let tclass = unsafe { ptr::read_unaligned(data as *const libc::c_int) };
+                    ecn = Ecn::from_bits(tclass as u8);
+                }
+                _ => (),
+            }
+            offset += align(cmsglen);
+        }
+
+        Ok((len as usize, addr, ecn))
+    }
+
+    pub fn send_to(sock: &UdpSocket, buf: &[u8], addr: &SocketAddr, ecn: Ecn) -> io::Result<usize> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let namelen = to_sockaddr(addr, &mut storage);
+        let (level, typ) = match addr {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, IP_TOS),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, IPV6_TCLASS),
+        };
+
+        let mut control: ControlBuf = [0; 4];
+        let hdrlen = align(mem::size_of::<libc::cmsghdr>());
+        let cmsglen = hdrlen + mem::size_of::<libc::c_int>();
+        unsafe {
+            let cmsg = control.as_mut_ptr() as *mut libc::cmsghdr;
+            (*cmsg).cmsg_len = cmsglen as _;
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = typ;
+            let data = (control.as_mut_ptr() as *mut u8).add(hdrlen) as *mut libc::c_int;
+            ptr::write_unaligned(data, ecn.bits() as libc::c_int);
+        }
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len:  buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = namelen;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = align(cmsglen) as _;
+
+        let len = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use packet::Ecn;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn enable(_sock: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn recv_from(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Ecn)> {
+        let (len, addr) = sock.recv_from(buf)?;
+        Ok((len, addr, Ecn::NotEct))
+    }
+
+    pub fn send_to(sock: &UdpSocket, buf: &[u8], addr: &SocketAddr, _ecn: Ecn) -> io::Result<usize> {
+        sock.send_to(buf, addr)
+    }
+}
+
+/// mark all outgoing packets as ECT(0) and ask the kernel for the codepoint of incoming ones
+pub fn enable(sock: &UdpSocket) -> io::Result<()> {
+    sys::enable(sock)
+}
+
+/// like UdpSocket::recv_from, but also returns the ECN codepoint of the packet
+pub fn recv_from(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Ecn)> {
+    sys::recv_from(sock, buf)
+}
+
+/// like UdpSocket::send_to, but marks this one packet with ecn instead of the socket default
+pub fn send_to(sock: &UdpSocket, buf: &[u8], addr: &SocketAddr, ecn: Ecn) -> io::Result<usize> {
+    sys::send_to(sock, buf, addr, ecn)
+}
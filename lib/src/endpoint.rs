@@ -1,11 +1,17 @@
+use bytes::Bytes;
 use failure::Error;
 use futures::sync::mpsc;
 use futures::sync::oneshot;
 use futures::Sink;
 use futures::{Async, Future, Poll, Stream};
-use packet::{EncryptedPacket, RoutingDirection, RoutingKey};
+use mio;
+use df;
+use ecn;
+use packet::{Ecn, EncryptedPacket, RoutingDirection, RoutingKey};
 use rand;
+use recvbuf::RecvBuffer;
 use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
 use std::net::UdpSocket as StdSocket;
 use tokio;
@@ -16,6 +22,10 @@ use tokio::timer::Interval;
 use std::time::{Instant,Duration};
 use proto;
 
+/// packets are received into one buffer of this size and handed on as slices of it.
+/// a packet that is kept around keeps the whole buffer alive
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Fail)]
 pub enum EndpointError {
     #[fail(display = "unknown route {}", route)]
@@ -42,7 +52,7 @@ pub struct Endpoint {
 
 pub enum ChannelBus {
     User {
-        inc:    mpsc::Sender<(EncryptedPacket, SocketAddr)>,
+        inc:    mpsc::Sender<(EncryptedPacket, SocketAddr, Ecn)>,
         tc:     stats::PacketCounter,
     },
     Proxy {
@@ -65,12 +75,19 @@ struct EndpointWorker {
 
     stdsock: StdSocket,
     sock:    UdpSocket,
+    recvbuf: RecvBuffer,
 
     channels: HashMap<RoutingKey, ChannelBus>,
 }
 
 impl Endpoint {
     pub fn spawn(stdsock: StdSocket, miosock: UdpSocket) -> Result<Self, Error> {
+        if let Err(e) = ecn::enable(&stdsock) {
+            warn!("cannot enable ecn: {}", e);
+        }
+        if let Err(e) = df::enable(&stdsock) {
+            warn!("cannot set don't fragment, path mtu discovery is off: {}", e);
+        }
         let (tx, rx) = mpsc::channel(10);
         let worker = EndpointWorker {
             work:       rx,
@@ -78,6 +95,7 @@ impl Endpoint {
             stats:      stats::Stats::default(),
             stdsock:    stdsock,
             sock:       miosock,
+            recvbuf:    RecvBuffer::new(RECV_BUFFER_SIZE),
             channels:   HashMap::new(),
         };
         tokio::spawn(worker);
@@ -203,17 +221,32 @@ impl Future for EndpointWorker {
 
         // receive from the socket
         loop {
-            let mut buf = [0; MAX_PACKET_SIZE];
-            let (len, addr) = match self.sock.poll_recv_from(&mut buf) {
-                Ok(Async::NotReady) => break,
+            let (len, addr, ecn) = match ecn::recv_from(&self.stdsock, self.recvbuf.reserve(MAX_PACKET_SIZE)) {
+                Ok(v) => v,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // the socket is drained. tokio only registers for the next readiness event
+                    // once it is told so. a packet that arrived just now is read with its codepoint on the next turn
+                    let ready = self
+                        .sock
+                        .clear_read_ready(mio::Ready::readable())
+                        .and_then(|()| self.sock.poll_read_ready(mio::Ready::readable()));
+                    match ready {
+                        Ok(Async::NotReady) => break,
+                        Ok(Async::Ready(_)) => continue,
+                        Err(e) => {
+                            error!("endpoint socket error: {}", e);
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                }
                 Err(e) => {
                     error!("endpoint socket error: {}", e);
                     return Ok(Async::Ready(()));
                 }
-                Ok(Async::Ready(v)) => v,
             };
 
-            if let Err(e) = self.recv_one_pkt(&buf[..len], addr) {
+            let pkt = self.recvbuf.take(len);
+            if let Err(e) = self.recv_one_pkt(pkt, addr, ecn) {
                 trace!("EndpointWorker::recv_one_pkt {}", e);
             }
         }
@@ -223,13 +256,13 @@ impl Future for EndpointWorker {
 }
 
 impl EndpointWorker {
-    fn recv_one_pkt(&mut self, b: &[u8], addr: SocketAddr) -> Result<(), Error> {
-        let pkt = EncryptedPacket::decode(b)?;
+    fn recv_one_pkt(&mut self, b: Bytes, addr: SocketAddr, ecn: Ecn) -> Result<(), Error> {
+        let pkt = EncryptedPacket::decode(b.clone())?;
         if let Some(channel) = self.channels.get_mut(&pkt.route) {
             match channel {
                 ChannelBus::User { inc, tc } => {
                     tc.rx += 1;
-                    inc.try_send((pkt, addr))?;
+                    inc.try_send((pkt, addr, ecn))?;
                 }
                 ChannelBus::Proxy { initiator, responder, tc } => {
                     assert_ne!(pkt.route, 0);
@@ -243,8 +276,10 @@ impl EndpointWorker {
                     if addr == *to {
                         return Err(Error::from(EndpointError::RoutingError { route: pkt.route }));
                     }
-                    let pkt = pkt.encode();
-                    assert_eq!(self.stdsock.send_to(&pkt, *to)?, pkt.len());
+                    // relayed as received, without decoding the payload or encoding the packet again.
+                    // the codepoint is passed on too, so the peers validate ECN across both hops
+                    let sent = ecn::send_to(&self.stdsock, &b, to, ecn)?;
+                    assert_eq!(sent, b.len());
                 }
             }
             Ok(())
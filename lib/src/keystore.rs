@@ -1,6 +1,8 @@
+use bs58;
 use certificate;
 use failure::Error;
 use identity::Secret;
+use noise::Psk;
 use rand::{self, RngCore};
 use std::env;
 use std::fs::{self, File};
@@ -15,15 +17,31 @@ pub enum KeystoreError {
 
     #[fail(display = "~/.devguard/secret exists, refusing to overwrite")]
     SecretsfileAlreadyExists,
+
+    #[fail(display = "psk in secrets must be 32 bytes, got {}", len)]
+    InvalidPsk { len: usize },
 }
 
 pub struct Secrets {
     pub identity: Secret,
+    /// mixed into every handshake, for closed deployments where all peers share it
+    pub psk:      Option<Psk>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct SecretsToml {
     identity: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    psk:      Option<String>,
+}
+
+fn psk_from_bytes(b: &[u8]) -> Result<Psk, Error> {
+    if b.len() != 32 {
+        return Err(KeystoreError::InvalidPsk { len: b.len() }.into());
+    }
+    let mut psk = [0; 32];
+    psk.copy_from_slice(b);
+    Ok(psk)
 }
 
 impl Secrets {
@@ -48,6 +66,7 @@ impl Secrets {
 
         let fi = toml::to_vec(&SecretsToml {
             identity: identity.to_string(),
+            psk:      None,
         }).expect("toml");
 
         f.write_all(&fi).expect(&format!("cannot write to {:?}",  &fp));
@@ -57,7 +76,10 @@ impl Secrets {
         fs::set_permissions(&fp, perms).expect("cannot set fs:metadata");
         drop(f);
 
-        Ok(Secrets { identity: identity })
+        Ok(Secrets {
+            identity: identity,
+            psk:      None,
+        })
     }
 
     pub fn load() -> Result<Secrets, Error> {
@@ -68,8 +90,16 @@ impl Secrets {
                 .read_to_end(&mut buffer)
                 .unwrap();
 
+            // an optional psk follows the secret
+            let psk = if buffer.len() > 32 {
+                Some(psk_from_bytes(&buffer[32..])?)
+            } else {
+                None
+            };
+
             return Ok(Secrets {
                 identity: Secret::from_bytes(&buffer[0..32]).expect("Secret::from_bytes"),
+                psk,
             })
         }
 
@@ -91,8 +121,14 @@ impl Secrets {
             .unwrap();
         let secrets: SecretsToml = toml::from_str(&buffer).expect("error while reading secrets toml");
 
+        let psk = match secrets.psk {
+            Some(psk) => Some(psk_from_bytes(&bs58::decode(psk).with_alphabet(bs58::alphabet::BITCOIN).into_vec()?)?),
+            None => None,
+        };
+
         Ok(Secrets {
             identity: secrets.identity.parse().unwrap(),
+            psk,
         })
     }
 }
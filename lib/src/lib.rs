@@ -29,12 +29,16 @@ extern crate trust_dns_resolver;
 extern crate interfaces2 as interfaces;
 extern crate dirs;
 extern crate fs2;
+extern crate libc;
+extern crate mio;
 
 pub mod channel;
 pub mod clock;
 pub mod config;
 pub mod connect;
+pub mod df;
 pub mod dns;
+pub mod ecn;
 pub mod endpoint;
 pub mod keystore;
 pub mod local_addrs;
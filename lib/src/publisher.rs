@@ -1,3 +1,4 @@
+use certificate;
 use channel;
 use endpoint;
 use failure::Error;
@@ -22,9 +23,10 @@ pub struct PublisherService {
     sock:       StdSocket,
     xsecret:    identity::Secret,
     secret:     identity::Secret,
+    psk:        Option<noise::Psk>,
     ep:         endpoint::Endpoint,
     tx:         mpsc::Sender<channel::Channel>,
-    acceptor:   Box<FnMut(&identity::Identity) -> bool + Send + Sync>,
+    acceptor:   Box<FnMut(&identity::Identity, &certificate::CertificateChain) -> bool + Send + Sync>,
     brokeraddr: SocketAddr,
 }
 
@@ -35,10 +37,11 @@ pub fn dispatch<F>(
     sock: StdSocket,
     brokeraddr: SocketAddr,
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
     acceptor: F,
 ) -> impl Stream<Item = channel::Channel, Error = Error>
 where
-    F: 'static + FnMut(&identity::Identity) -> bool + Send + Sync,
+    F: 'static + FnMut(&identity::Identity, &certificate::CertificateChain) -> bool + Send + Sync,
 {
     let (xsecret, xpublic) = identity::generate_x25519();
     let xaddr = identity::SignedAddress::sign(&secret, identity::Address::from_array(xpublic));
@@ -60,6 +63,7 @@ where
     let publisher = PublisherService {
         xsecret,
         secret,
+        psk,
         ep,
         sock,
         tx,
@@ -79,6 +83,29 @@ where
     rx.map_err(|()| unreachable!())
 }
 
+/// an acceptor for dispatch that lets a peer in if auth grants it resource,
+/// directly or through the certificate chain it presented in the handshake
+pub fn authenticated(
+    auth: certificate::Authenticator,
+    resource: String,
+) -> impl FnMut(&identity::Identity, &certificate::CertificateChain) -> bool + Send + Sync {
+    move |identity, chain| match auth.authorize(identity, resource.clone(), chain) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("{} is not authorized for {}: {}", identity, resource, e);
+            false
+        }
+    }
+}
+
+fn reject() -> Box<Future<Item = proto::PeerConnectResponse, Error = Error> + Sync + Send + 'static> {
+    Box::new(futures::future::ok(proto::PeerConnectResponse {
+        paths:     Vec::new(),
+        ok:        false,
+        handshake: Vec::new(),
+    }))
+}
+
 impl proto::Peer::Service for PublisherService {
     fn connect(
         &mut self,
@@ -90,25 +117,29 @@ impl proto::Peer::Service for PublisherService {
         let msgroute = msg.route;
         info!("connect request from {} :: {:?} ", msgidentity, msgpaths);
 
-        let pkt = packet::EncryptedPacket::decode(&msg.handshake).unwrap();
-        let (noise, identity, timestamp) = noise::respond(None, pkt).unwrap();
+        let pkt = match packet::EncryptedPacket::decode(&msg.handshake[..]) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("rejected connect request from {}: {}", msgidentity, e);
+                return Ok(reject());
+            }
+        };
+        let (noise, identity, timestamp, chain) = match noise::respond(None, pkt, self.psk.as_ref()) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("rejected connect request from {}: {}", msgidentity, e);
+                return Ok(reject());
+            }
+        };
 
         if identity != msgidentity || timestamp != msg.timestamp {
             warn!("rejected connect request from {} because of pkt mismatch", msgidentity);
-            return Ok(Box::new(futures::future::ok(proto::PeerConnectResponse {
-                paths:     Vec::new(),
-                ok:        false,
-                handshake: Vec::new(),
-            })));
+            return Ok(reject());
         }
 
-        if !(self.acceptor)(&identity) {
+        if !(self.acceptor)(&identity, &chain) {
             warn!("acceptor rejected connect request from {}", identity);
-            return Ok(Box::new(futures::future::ok(proto::PeerConnectResponse {
-                paths:     Vec::new(),
-                ok:        false,
-                handshake: Vec::new(),
-            })));
+            return Ok(reject());
         }
 
         let mut theirpaths = Vec::new();
@@ -117,9 +148,15 @@ impl proto::Peer::Service for PublisherService {
                 o if proto::path::Category::Local as i32 == o => proto::path::Category::Local,
                 o if proto::path::Category::Internet as i32 == o => proto::path::Category::Internet,
                 o if proto::path::Category::BrokerOrigin as i32 == o => proto::path::Category::BrokerOrigin,
-                _ => unreachable!(),
+                o => {
+                    warn!("ignoring path {} of unknown category {} from {}", path.ipaddr, o, identity);
+                    continue;
+                }
             };
-            theirpaths.push((path.ipaddr.parse().unwrap(), cat))
+            match path.ipaddr.parse() {
+                Ok(addr) => theirpaths.push((addr, cat)),
+                Err(e) => warn!("ignoring invalid path {} from {}: {}", path.ipaddr, identity, e),
+            }
         }
         theirpaths.push((self.brokeraddr.clone(), proto::path::Category::BrokerOrigin));
 
@@ -131,7 +168,13 @@ impl proto::Peer::Service for PublisherService {
             });
         }
 
-        let (transport, pkt) = noise.send_response(msg.route, &self.secret).unwrap();
+        let (transport, pkt) = match noise.send_response(msg.route, &self.secret, &Vec::new()) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("cannot respond to connect request from {}: {}", identity, e);
+                return Ok(reject());
+            }
+        };
         let transport = transport::Channel::new(transport, format!("p2p {}:{}", msgidentity, msg.route));
 
         let (tx, rx) = mpsc::channel(10);
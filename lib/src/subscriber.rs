@@ -1,3 +1,4 @@
+use certificate;
 use channel;
 use endpoint;
 use failure::Error;
@@ -35,10 +36,25 @@ pub fn connect(
     sock: StdSocket,
     brokeraddr: SocketAddr,
     secret: identity::Secret,
+    psk: Option<noise::Psk>,
+) -> impl Future<Item = channel::Channel, Error = Error> {
+    connect_with_chain(target, ep, brk, sock, brokeraddr, secret, psk, Vec::new())
+}
+
+/// like connect, but presents a certificate chain to the publisher in the handshake
+pub fn connect_with_chain(
+    target: identity::Identity,
+    ep: endpoint::Endpoint,
+    brk: &mut channel::Channel,
+    sock: StdSocket,
+    brokeraddr: SocketAddr,
+    secret: identity::Secret,
+    psk: Option<noise::Psk>,
+    chain: certificate::CertificateChain,
 ) -> impl Future<Item = channel::Channel, Error = Error> {
     let timestamp = clock::network_time(&ep);
 
-    let (mut hs, pkt) = noise::initiate(None, &secret, timestamp).unwrap();
+    let (mut hs, pkt) = noise::initiate(None, &secret, timestamp, &chain, psk.as_ref()).unwrap();
 
     let ep = ep.work.clone();
     let selfsock = sock.try_clone().unwrap();
@@ -73,8 +89,8 @@ pub fn connect(
             }
 
             let msgroute = msg.route;
-            let pkt = packet::EncryptedPacket::decode(&msg.handshake).unwrap();
-            let identity = hs.recv_response(pkt).unwrap();
+            let pkt = packet::EncryptedPacket::decode(&msg.handshake[..]).unwrap();
+            let (identity, _) = hs.recv_response(pkt).unwrap();
             let transport = hs.into_transport().unwrap();
             debug!("subscribed to {:?}", msg);
 
@@ -104,9 +104,19 @@ impl Channel {
                 ChannelProgress::ReceiveStream(stream, b) => {
                     let streams = &mut self.streams as *mut HashMap<u32, Box<CallHandler<Channel>>>;
                     if let Some(mut h) = unsafe { &mut *streams }.get_mut(&stream) {
-                        h.recv(self, b);
+                        h.recv(self, b.to_vec());
                     }
                 }
+                ChannelProgress::ReceiveDatagram(b) => {
+                    trace!("dropping datagram of {} bytes, nothing consumes them here", b.len());
+                }
+                ChannelProgress::Reset(stream, code) => {
+                    warn!("stream {} was reset with code {}", stream, code);
+                    self.streams.remove(&stream);
+                }
+                ChannelProgress::PathResponse(_) => {
+                    // websockets do not migrate, and we never send path challenges
+                }
                 ChannelProgress::Close(stream) => {
                     let streams = &mut self.streams as *mut HashMap<u32, Box<CallHandler<Channel>>>;
                     if let Some(mut h) = unsafe { &mut *streams }.get_mut(&stream) {
@@ -117,6 +127,9 @@ impl Channel {
                     panic!("disconnect!");
                     break;
                 }
+                ChannelProgress::Timeout => {
+                    return Err(format_err!("peer timed out"));
+                }
             }
 
             let mut removeme = Vec::new();
@@ -134,11 +147,11 @@ impl Channel {
                             cont = true;
                         }
                         rpc::Progress::Header(b) => {
-                            self.transport.header(*stream, b);
+                            self.transport.header(*stream, &b)?;
                             cont = true;
                         }
                         rpc::Progress::Stream(b) => {
-                            self.transport.stream(*stream, b);
+                            self.transport.stream(*stream, b)?;
                             cont = true;
                         }
                         rpc::Progress::Done => {